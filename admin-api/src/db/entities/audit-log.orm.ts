@@ -10,5 +10,7 @@ export class AuditLogOrm {
   @Column('text') decision!: 'ALLOW'|'DENY';
   @Column('int', { nullable: true }) policy_set_version!: number | null;
   @Column('int') latency_ms!: number;
+  @Column('text', { default: 'cedar' }) source!: string;
+  @Column('text', { nullable: true }) reason!: string | null;
   @Column({ type: 'timestamptz' }) ts!: Date;
 }
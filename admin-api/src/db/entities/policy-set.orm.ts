@@ -5,5 +5,6 @@ export class PolicySetOrm {
   @Column('uuid') tenant_id!: string;
   @Column('int') version!: number;
   @Column('text') status!: 'active'|'draft';
+  @Column('jsonb', { nullable: true }) schema!: Record<string, unknown> | null;
   @CreateDateColumn() created_at!: Date;
 }
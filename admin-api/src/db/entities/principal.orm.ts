@@ -6,5 +6,6 @@ export class PrincipalOrm {
   @Column('uuid') tenant_id!: string;
   @Column('text') cedar_uid!: string; // e.g. User::"123"
   @Column('jsonb', { default: () => `'{}'::jsonb` }) attrs!: Record<string, any>;
+  @Column('text', { array: true, default: () => `'{}'` }) parents!: string[]; // e.g. Group::"admins"
   @CreateDateColumn() created_at!: Date;
 }
@@ -6,5 +6,6 @@ export class ResourceOrm {
   @Column('uuid') tenant_id!: string;
   @Column('text') cedar_uid!: string; // e.g. Document::"abc"
   @Column('jsonb', { default: () => `'{}'::jsonb` }) attrs!: Record<string, any>;
+  @Column('text', { array: true, default: () => `'{}'` }) parents!: string[]; // e.g. Group::"admins"
   @CreateDateColumn() created_at!: Date;
 }
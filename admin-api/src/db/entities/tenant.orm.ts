@@ -5,5 +5,6 @@ export class TenantOrm {
   @PrimaryGeneratedColumn('uuid') id!: string;
   @Column('text') name!: string;
   @Column('text') status!: 'active'|'disabled';
+  @Column('jsonb', { default: {} }) settings!: Record<string, unknown>;
   @CreateDateColumn() created_at!: Date;
 }
@@ -5,7 +5,7 @@ export class AuditRepo {
 
   async list(qr: QueryRunner, tenantId: string, limit=50, offset=0) {
     return qr.query(
-      `SELECT tenant_id, principal, resource, action, decision, policy_set_version, latency_ms, ts
+      `SELECT tenant_id, principal, resource, action, decision, policy_set_version, latency_ms, source, reason, ts
        FROM audit_logs
        WHERE tenant_id = $1
        ORDER BY ts DESC
@@ -6,12 +6,13 @@ import { ValidateReq, ValidateRes, TestActiveReq, TestOverrideReq, TestRes } fro
 @Injectable()
 export class PdpHttp implements PdpPort {
   private base = process.env.PDP_BASE_URL || 'http://pdp:8081';
+  private headers = process.env.PDP_ADMIN_API_KEY ? { 'x-api-key': process.env.PDP_ADMIN_API_KEY } : {};
 
   async validate(req: ValidateReq): Promise<ValidateRes> {
     try {
       console.log("VALIDATE");
       console.log(req);
-      const { data } = await axios.post(`${this.base}/admin/validate`, req);
+      const { data } = await axios.post(`${this.base}/admin/validate`, req, { headers: this.headers });
       console.log("Response VALIDATE");
       console.log(data)
       return data;
@@ -36,7 +37,7 @@ export class PdpHttp implements PdpPort {
         action: typeof (req as any).action === 'string'
           ? (req as any).action
           : (req as any).action.id,
-      });
+      }, { headers: this.headers });
       return data;
     } catch (e) {
       const err = e as AxiosError;
@@ -60,7 +61,7 @@ export class PdpHttp implements PdpPort {
           ? (req as any).action
           : (req as any).action.id,
         context: (req as any).context,
-      });
+      }, { headers: this.headers });
       return data;
     } catch (e) {
       const err = e as AxiosError;
@@ -1,8 +1,27 @@
-import Redis from 'ioredis';
+import Redis, { Cluster } from 'ioredis';
 import { PubSubPort } from '../../core/events/pubsub.port';
 
+// Same settings as the PDP: REDIS_MODE=standalone|cluster|sentinel, REDIS_URL comma separated
+function connect(): Redis | Cluster {
+  const mode = process.env.REDIS_MODE ?? 'standalone';
+  const urls = (process.env.REDIS_URL || 'redis://localhost:6379').split(',').map(s => s.trim());
+  const nodes = urls.map(u => new URL(u)).map(u => ({ host: u.hostname, port: Number(u.port || 6379) }));
+  const password = new URL(urls[0]).password || undefined;
+  switch (mode) {
+    case 'standalone':
+      return new Redis(urls[0]);
+    // A cluster forwards PUBLISH to every node
+    case 'cluster':
+      return new Cluster(nodes, { redisOptions: { password } });
+    case 'sentinel':
+      return new Redis({ sentinels: nodes, name: process.env.REDIS_SENTINEL_MASTER ?? 'mymaster', password });
+    default:
+      throw new Error(`unknown REDIS_MODE ${mode} (standalone|cluster|sentinel)`);
+  }
+}
+
 export class RedisPubSub implements PubSubPort {
-  private client = new Redis(process.env.REDIS_URL || 'redis://localhost:6379');
+  private client = connect();
   async publishInvalidate(tenantId: string) {
     await this.client.publish('pdp:invalidate', tenantId);
   }
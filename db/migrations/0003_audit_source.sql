@@ -0,0 +1,7 @@
+-- Decision source for audit rows: 'cedar' (policy evaluation), 'default' (no active
+-- policy set) or 'break_glass'.
+-- `reason` carries the break-glass justification.
+ALTER TABLE audit_logs ADD COLUMN IF NOT EXISTS source TEXT NOT NULL DEFAULT 'cedar';
+ALTER TABLE audit_logs ADD COLUMN IF NOT EXISTS reason TEXT;
+
+CREATE INDEX IF NOT EXISTS idx_audit_tenant_source ON audit_logs(tenant_id, source, ts);
@@ -0,0 +1,3 @@
+-- Per-tenant PDP settings, e.g. {"default_decision":"allow","fail_mode":"open"}.
+-- `tenants.status` is enforced by the PDP: only 'active' tenants get decisions.
+ALTER TABLE tenants ADD COLUMN IF NOT EXISTS settings JSONB NOT NULL DEFAULT '{}'::jsonb;
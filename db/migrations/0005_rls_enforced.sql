@@ -0,0 +1,45 @@
+-- Make the tenant RLS policies from 0002 actually apply to the PDP.
+--
+-- 1. A dedicated `pdp` role: no superuser, no BYPASSRLS, not the table owner,
+--    read-only on tenant data plus INSERT on audit_logs. Created without a
+--    password; the deployment sets one (infra/db-init/init.sh in dev).
+-- 2. FORCE RLS so the tables' owner is filtered too.
+-- 3. Treat an unset/empty `app.tenant_id` as "no tenant" (no rows) instead of
+--    failing the ''::uuid cast on pooled connections that had it set before.
+
+DO $$
+BEGIN
+  IF NOT EXISTS (SELECT 1 FROM pg_roles WHERE rolname = 'pdp') THEN
+    CREATE ROLE pdp LOGIN NOSUPERUSER NOBYPASSRLS;
+  END IF;
+END $$;
+
+GRANT SELECT ON tenants, policy_sets, policies, principals, resources, attributes TO pdp;
+GRANT SELECT, INSERT ON audit_logs TO pdp;
+GRANT USAGE ON SEQUENCE audit_logs_id_seq TO pdp;
+
+ALTER TABLE policy_sets FORCE ROW LEVEL SECURITY;
+ALTER TABLE policies    FORCE ROW LEVEL SECURITY;
+ALTER TABLE principals  FORCE ROW LEVEL SECURITY;
+ALTER TABLE resources   FORCE ROW LEVEL SECURITY;
+ALTER TABLE attributes  FORCE ROW LEVEL SECURITY;
+ALTER TABLE audit_logs  FORCE ROW LEVEL SECURITY;
+
+DROP POLICY IF EXISTS ps_rls ON policy_sets;
+CREATE POLICY ps_rls ON policy_sets
+USING (tenant_id = NULLIF(current_setting('app.tenant_id', true), '')::uuid);
+DROP POLICY IF EXISTS p_rls ON policies;
+CREATE POLICY p_rls  ON policies
+USING (policy_set_id IN (SELECT id FROM policy_sets WHERE tenant_id = NULLIF(current_setting('app.tenant_id', true), '')::uuid));
+DROP POLICY IF EXISTS princ_rls ON principals;
+CREATE POLICY princ_rls ON principals
+USING (tenant_id = NULLIF(current_setting('app.tenant_id', true), '')::uuid);
+DROP POLICY IF EXISTS res_rls ON resources;
+CREATE POLICY res_rls ON resources
+USING (tenant_id = NULLIF(current_setting('app.tenant_id', true), '')::uuid);
+DROP POLICY IF EXISTS attr_rls ON attributes;
+CREATE POLICY attr_rls ON attributes
+USING (tenant_id = NULLIF(current_setting('app.tenant_id', true), '')::uuid);
+DROP POLICY IF EXISTS audit_rls ON audit_logs;
+CREATE POLICY audit_rls ON audit_logs
+USING (tenant_id = NULLIF(current_setting('app.tenant_id', true), '')::uuid);
@@ -0,0 +1,4 @@
+-- Direct Cedar parents of principals/resources (e.g. ARRAY['Group::"admins"']),
+-- so policies can use `principal in Group::"admins"`.
+ALTER TABLE principals ADD COLUMN IF NOT EXISTS parents TEXT[] NOT NULL DEFAULT '{}';
+ALTER TABLE resources  ADD COLUMN IF NOT EXISTS parents TEXT[] NOT NULL DEFAULT '{}';
@@ -0,0 +1,3 @@
+-- Optional Cedar schema (JSON format) per policy set version. Not used for
+-- evaluation; shipped in signed bundles so edge PDPs validate against it.
+ALTER TABLE policy_sets ADD COLUMN IF NOT EXISTS schema JSONB;
@@ -0,0 +1,73 @@
+-- Change feed for sidecar PDPs: one row per write to tenant data, read with
+-- `seq > cursor`. Rows only carry ids (no attributes or policy text), so the
+-- table is not under RLS; sidecars re-read the data itself through RLS.
+-- Old rows are pruned by the central PDP (0010_change_feed_retention.sql);
+-- sidecars fully resync periodically, so a pruned gap heals on its own.
+CREATE TABLE IF NOT EXISTS changes (
+  seq BIGSERIAL PRIMARY KEY,
+  tenant_id UUID NOT NULL,
+  kind TEXT NOT NULL,  -- tenant|policy_set|entity
+  entity_uid TEXT,     -- Cedar UID for kind = 'entity'
+  ts TIMESTAMPTZ NOT NULL DEFAULT clock_timestamp()
+);
+CREATE INDEX IF NOT EXISTS idx_changes_tenant_seq ON changes(tenant_id, seq);
+
+GRANT SELECT ON changes TO pdp;
+
+-- Writes by the admin API (or psql) land in the feed through these triggers.
+-- SECURITY DEFINER so writers need no grant on `changes`.
+CREATE OR REPLACE FUNCTION record_tenant_change() RETURNS trigger
+LANGUAGE plpgsql SECURITY DEFINER SET search_path = public AS $$
+BEGIN
+  INSERT INTO changes (tenant_id, kind)
+  VALUES (CASE WHEN TG_OP = 'DELETE' THEN OLD.id ELSE NEW.id END, 'tenant');
+  RETURN NULL;
+END $$;
+
+CREATE OR REPLACE FUNCTION record_policy_set_change() RETURNS trigger
+LANGUAGE plpgsql SECURITY DEFINER SET search_path = public AS $$
+BEGIN
+  INSERT INTO changes (tenant_id, kind)
+  VALUES (CASE WHEN TG_OP = 'DELETE' THEN OLD.tenant_id ELSE NEW.tenant_id END, 'policy_set');
+  RETURN NULL;
+END $$;
+
+CREATE OR REPLACE FUNCTION record_policy_change() RETURNS trigger
+LANGUAGE plpgsql SECURITY DEFINER SET search_path = public AS $$
+BEGIN
+  -- No row when the whole policy set is being deleted; its own trigger records that.
+  INSERT INTO changes (tenant_id, kind)
+  SELECT tenant_id, 'policy_set' FROM policy_sets
+  WHERE id = CASE WHEN TG_OP = 'DELETE' THEN OLD.policy_set_id ELSE NEW.policy_set_id END;
+  RETURN NULL;
+END $$;
+
+-- principals and resources: the old UID too when it was renamed or deleted.
+CREATE OR REPLACE FUNCTION record_entity_change() RETURNS trigger
+LANGUAGE plpgsql SECURITY DEFINER SET search_path = public AS $$
+BEGIN
+  IF TG_OP <> 'INSERT' THEN
+    INSERT INTO changes (tenant_id, kind, entity_uid) VALUES (OLD.tenant_id, 'entity', OLD.cedar_uid);
+  END IF;
+  IF TG_OP = 'INSERT' OR (TG_OP = 'UPDATE'
+      AND (NEW.tenant_id, NEW.cedar_uid) IS DISTINCT FROM (OLD.tenant_id, OLD.cedar_uid)) THEN
+    INSERT INTO changes (tenant_id, kind, entity_uid) VALUES (NEW.tenant_id, 'entity', NEW.cedar_uid);
+  END IF;
+  RETURN NULL;
+END $$;
+
+DROP TRIGGER IF EXISTS tenants_change ON tenants;
+CREATE TRIGGER tenants_change AFTER INSERT OR UPDATE OR DELETE ON tenants
+FOR EACH ROW EXECUTE FUNCTION record_tenant_change();
+DROP TRIGGER IF EXISTS policy_sets_change ON policy_sets;
+CREATE TRIGGER policy_sets_change AFTER INSERT OR UPDATE OR DELETE ON policy_sets
+FOR EACH ROW EXECUTE FUNCTION record_policy_set_change();
+DROP TRIGGER IF EXISTS policies_change ON policies;
+CREATE TRIGGER policies_change AFTER INSERT OR UPDATE OR DELETE ON policies
+FOR EACH ROW EXECUTE FUNCTION record_policy_change();
+DROP TRIGGER IF EXISTS principals_change ON principals;
+CREATE TRIGGER principals_change AFTER INSERT OR UPDATE OR DELETE ON principals
+FOR EACH ROW EXECUTE FUNCTION record_entity_change();
+DROP TRIGGER IF EXISTS resources_change ON resources;
+CREATE TRIGGER resources_change AFTER INSERT OR UPDATE OR DELETE ON resources
+FOR EACH ROW EXECUTE FUNCTION record_entity_change();
@@ -0,0 +1,15 @@
+-- Cache invalidation without an external publish step: every change-feed row
+-- (0008) is also sent on NOTIFY channel `pdp_invalidate`, e.g.
+-- {"tenant_id":"…","kind":"entity","uid":"User::\"123\""}. Postgres folds
+-- identical payloads within one transaction, so a bulk write notifies once.
+CREATE OR REPLACE FUNCTION notify_change() RETURNS trigger
+LANGUAGE plpgsql AS $$
+BEGIN
+  PERFORM pg_notify('pdp_invalidate', json_build_object(
+    'tenant_id', NEW.tenant_id, 'kind', NEW.kind, 'uid', NEW.entity_uid)::text);
+  RETURN NULL;
+END $$;
+
+DROP TRIGGER IF EXISTS changes_notify ON changes;
+CREATE TRIGGER changes_notify AFTER INSERT ON changes
+FOR EACH ROW EXECUTE FUNCTION notify_change();
@@ -0,0 +1,20 @@
+-- Retention for the change feed (0008). The central PDP calls prune_changes()
+-- periodically (CHANGE_FEED_RETENTION_SECS); the `pdp` role gets no DELETE on
+-- `changes`, only this age-bounded function.
+CREATE INDEX IF NOT EXISTS idx_changes_ts ON changes(ts);
+
+CREATE OR REPLACE FUNCTION prune_changes(retention INTERVAL) RETURNS BIGINT
+LANGUAGE sql SECURITY DEFINER SET search_path = public AS $$
+  WITH deleted AS (
+    DELETE FROM changes c
+    WHERE c.ts < now() - retention
+      -- Each tenant keeps its newest row: the feed cursor is MAX(seq) per tenant
+      -- and must not go back.
+      AND c.seq < (SELECT MAX(l.seq) FROM changes l WHERE l.tenant_id = c.tenant_id)
+    RETURNING 1
+  )
+  SELECT count(*) FROM deleted;
+$$;
+
+REVOKE ALL ON FUNCTION prune_changes(INTERVAL) FROM PUBLIC;
+GRANT EXECUTE ON FUNCTION prune_changes(INTERVAL) TO pdp;
@@ -0,0 +1,15 @@
+#!/bin/sh
+# Dev database init: applies db/migrations in order, then gives the RLS-bound
+# `pdp` role (0005) its password. Migrations never set credentials.
+set -e
+
+for f in /migrations/*.sql; do
+  echo "applying $f"
+  psql -v ON_ERROR_STOP=1 --username "$POSTGRES_USER" --dbname "$POSTGRES_DB" -f "$f"
+done
+
+# psql quotes :'pw' itself; variables are not expanded in -c commands, hence stdin
+psql -v ON_ERROR_STOP=1 --username "$POSTGRES_USER" --dbname "$POSTGRES_DB" \
+  -v pw="$PDP_DB_PASSWORD" <<'SQL'
+ALTER ROLE pdp PASSWORD :'pw';
+SQL
@@ -0,0 +1,157 @@
+//! Policy cache read path: snapshots (`src/policy_cache.rs`) and their slice for
+//! the request vs. the previous `RwLock<HashMap<Uuid, (i32, PolicySet)>>` that
+//! cloned the set on every read.
+//!
+//!     cargo bench --bench policy_cache
+//!
+//! `lookup/*` reads from one thread; `contended/*` reads from `READERS` threads
+//! while another thread replaces a tenant's set every millisecond, like a busy
+//! PDP taking invalidations.
+
+use cedar_policy::{EntityUid, Policy, PolicySet};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use std::{
+    collections::HashMap,
+    hint::black_box,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+use tokio::sync::RwLock;
+use uuid::Uuid;
+
+#[allow(dead_code)]
+#[path = "../src/policy_cache.rs"]
+mod policy_cache;
+// Its tests are compiled but not run here
+#[allow(dead_code, unused_imports)]
+#[path = "../src/policy_index.rs"]
+mod policy_index;
+use policy_cache::{PolicyCache, PolicySnapshot};
+
+const TENANTS: u128 = 100;
+const POLICIES: usize = 50;
+const READERS: usize = 4;
+
+fn policies() -> PolicySet {
+    let mut pset = PolicySet::new();
+    for i in 0..POLICIES {
+        let src = format!(
+            r#"permit(principal in Group::"g{i}", action == Action::"read", resource)
+            when {{ principal.department == resource.department && context.hour < {i} }};"#
+        );
+        pset.add(Policy::parse(Some(format!("p{i}")), src).unwrap())
+            .unwrap();
+    }
+    pset
+}
+
+/// The cache as it was: a version and a `PolicySet` per tenant behind a lock.
+struct Locked(RwLock<HashMap<Uuid, (i32, PolicySet)>>);
+
+/// Principal, action and resource of the request being authorized.
+type Uids = [EntityUid; 3];
+
+trait Cache: Send + Sync {
+    /// Number of policies to evaluate.
+    fn read(&self, tenant: Uuid, uids: &Uids) -> usize;
+    fn replace(&self, tenant: Uuid, pset: &PolicySet);
+}
+
+impl Cache for Locked {
+    fn read(&self, tenant: Uuid, _: &Uids) -> usize {
+        let (_, pset) = self.0.blocking_read().get(&tenant).cloned().unwrap();
+        pset.policies().count()
+    }
+
+    fn replace(&self, tenant: Uuid, pset: &PolicySet) {
+        self.0.blocking_write().insert(tenant, (1, pset.clone()));
+    }
+}
+
+impl Cache for PolicyCache {
+    fn read(&self, tenant: Uuid, [principal, action, resource]: &Uids) -> usize {
+        let snapshot = self.get(tenant).unwrap();
+        let pset = snapshot.policies.slice(principal, action, resource);
+        pset.policies().count()
+    }
+
+    fn replace(&self, tenant: Uuid, pset: &PolicySet) {
+        self.insert(tenant, Arc::new(PolicySnapshot::new(1, pset.clone())));
+    }
+}
+
+fn uids() -> Uids {
+    [
+        r#"User::"alice""#,
+        r#"Action::"read""#,
+        r#"Document::"spec""#,
+    ]
+    .map(|uid| EntityUid::from_str(uid).unwrap())
+}
+
+fn caches() -> Vec<(&'static str, Arc<dyn Cache>)> {
+    let locked = Arc::new(Locked(RwLock::new(HashMap::new())));
+    let snapshots = Arc::new(PolicyCache::new());
+    let pset = policies();
+    for t in 0..TENANTS {
+        locked.replace(Uuid::from_u128(t), &pset);
+        snapshots.replace(Uuid::from_u128(t), &pset);
+    }
+    vec![("rwlock_clone", locked), ("snapshot", snapshots)]
+}
+
+fn lookup(c: &mut Criterion) {
+    let mut group = c.benchmark_group("lookup");
+    let uids = uids();
+    for (name, cache) in caches() {
+        let tenant = Uuid::from_u128(TENANTS / 2);
+        group.bench_function(name, |b| b.iter(|| black_box(cache.read(tenant, &uids))));
+    }
+    group.finish();
+}
+
+fn contended(c: &mut Criterion) {
+    let mut group = c.benchmark_group("contended");
+    let (pset, uids) = (policies(), uids());
+    for (name, cache) in caches() {
+        group.bench_function(BenchmarkId::new(name, READERS), |b| {
+            b.iter_custom(|iters| {
+                let stop = Arc::new(AtomicBool::new(false));
+                let writer = {
+                    let (cache, stop, pset) = (cache.clone(), stop.clone(), pset.clone());
+                    thread::spawn(move || {
+                        while !stop.load(Ordering::Relaxed) {
+                            cache.replace(Uuid::from_u128(0), &pset);
+                            thread::sleep(Duration::from_millis(1));
+                        }
+                    })
+                };
+                let started = Instant::now();
+                thread::scope(|s| {
+                    for r in 0..READERS {
+                        let (cache, uids) = (&cache, &uids);
+                        s.spawn(move || {
+                            for i in 0..iters / READERS as u64 {
+                                let tenant = Uuid::from_u128((i as u128 + r as u128) % TENANTS);
+                                black_box(cache.read(tenant, uids));
+                            }
+                        });
+                    }
+                });
+                let elapsed = started.elapsed();
+                stop.store(true, Ordering::Relaxed);
+                writer.join().unwrap();
+                elapsed
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, lookup, contended);
+criterion_main!(benches);
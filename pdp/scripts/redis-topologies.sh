@@ -0,0 +1,59 @@
+#!/usr/bin/env bash
+# Local Redis Cluster (3 masters) and Sentinel (master, replica, sentinel) for
+# trying REDIS_MODE=cluster|sentinel and running the gated redis_conn tests.
+#   scripts/redis-topologies.sh up|down
+set -euo pipefail
+
+DIR="${REDIS_TOPOLOGIES_DIR:-/tmp/pdp-redis-topologies}"
+CLUSTER_PORTS=(7000 7001 7002)
+MASTER_PORT=6380
+REPLICA_PORT=6381
+SENTINEL_PORT=26379
+
+up() {
+  mkdir -p "$DIR"
+  for port in "${CLUSTER_PORTS[@]}"; do
+    mkdir -p "$DIR/$port"
+    redis-server --port "$port" --dir "$DIR/$port" --daemonize yes --save '' \
+      --cluster-enabled yes --cluster-config-file nodes.conf --logfile "$DIR/$port.log"
+  done
+  for port in "${CLUSTER_PORTS[@]}"; do
+    until redis-cli -p "$port" ping >/dev/null 2>&1; do sleep 0.1; done
+  done
+  redis-cli --cluster create "${CLUSTER_PORTS[@]/#/127.0.0.1:}" \
+    --cluster-replicas 0 --cluster-yes >/dev/null
+  until redis-cli -p "${CLUSTER_PORTS[0]}" cluster info | grep -q 'cluster_state:ok'; do sleep 0.1; done
+
+  redis-server --port "$MASTER_PORT" --daemonize yes --save '' --logfile "$DIR/$MASTER_PORT.log"
+  redis-server --port "$REPLICA_PORT" --daemonize yes --save '' --logfile "$DIR/$REPLICA_PORT.log" \
+    --replicaof 127.0.0.1 "$MASTER_PORT"
+  cat > "$DIR/sentinel.conf" <<CONF
+port $SENTINEL_PORT
+sentinel monitor mymaster 127.0.0.1 $MASTER_PORT 1
+sentinel down-after-milliseconds mymaster 1000
+sentinel failover-timeout mymaster 5000
+CONF
+  redis-server "$DIR/sentinel.conf" --sentinel --daemonize yes --logfile "$DIR/sentinel.log"
+  until redis-cli -p "$SENTINEL_PORT" sentinel get-master-addr-by-name mymaster >/dev/null 2>&1; do sleep 0.1; done
+
+  cat <<ENV
+export TEST_REDIS_URL=redis://127.0.0.1:$MASTER_PORT
+export TEST_REDIS_CLUSTER_URL=$(printf 'redis://127.0.0.1:%s,' "${CLUSTER_PORTS[@]}" | sed 's/,$//')
+export TEST_REDIS_SENTINEL_URL=redis://127.0.0.1:$SENTINEL_PORT
+# PDP: REDIS_MODE=cluster REDIS_URL=\$TEST_REDIS_CLUSTER_URL
+#      REDIS_MODE=sentinel REDIS_URL=\$TEST_REDIS_SENTINEL_URL REDIS_SENTINEL_MASTER=mymaster
+ENV
+}
+
+down() {
+  for port in "${CLUSTER_PORTS[@]}" "$MASTER_PORT" "$REPLICA_PORT" "$SENTINEL_PORT"; do
+    redis-cli -p "$port" shutdown nosave >/dev/null 2>&1 || true
+  done
+  rm -rf "$DIR"
+}
+
+case "${1:-}" in
+  up) up ;;
+  down) down ;;
+  *) echo "usage: $0 up|down" >&2; exit 2 ;;
+esac
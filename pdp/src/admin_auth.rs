@@ -0,0 +1,588 @@
+//! Authentication and role checks for the `/admin/*` routes.
+//!
+//! Callers authenticate with a static API key (`x-api-key`), an OIDC bearer
+//! token verified against a local JWKS, or (over mTLS) a client certificate
+//! whose subject is listed in `ADMIN_CERT_SCOPES`. All resolve to an
+//! [`AdminPrincipal`] holding scopes such as `validate`, `test`, `test:<tenant-id>`
+//! or `bundle:<tenant-id>`; handlers check the scope they need for the tenant they touch.
+
+use crate::jwt::{
+    check_registered_claims, split_audiences, KeySet, TenantJwtSettings, DEFAULT_LEEWAY_SECS,
+};
+use crate::tls::ClientCert;
+use crate::{AppState, AuthzDecision};
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::{request::Parts, HeaderMap, StatusCode},
+    Json,
+};
+use serde::Deserialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::{collections::HashMap, env, fmt, str::FromStr};
+use thiserror::Error;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+pub const API_KEY_HEADER: &str = "x-api-key";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    /// Everything, on every tenant.
+    Admin,
+    /// `/admin/validate`
+    Validate,
+    /// `/admin/test`
+    Test,
+    /// `/admin/bundles/{tenant}` and `/admin/changes/{tenant}`
+    Bundle,
+}
+
+/// A permission, optionally restricted to one tenant (`test:<tenant-id>`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Scope {
+    perm: Permission,
+    tenant: Option<Uuid>,
+}
+
+impl FromStr for Scope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (perm, tenant) = match s.split_once(':') {
+            Some((p, t)) => (
+                p,
+                Some(Uuid::parse_str(t).map_err(|_| format!("invalid tenant in scope {s:?}"))?),
+            ),
+            None => (s, None),
+        };
+        let perm = match perm {
+            "admin" => Permission::Admin,
+            "validate" => Permission::Validate,
+            "test" => Permission::Test,
+            "bundle" => Permission::Bundle,
+            _ => return Err(format!("unknown scope {s:?}")),
+        };
+        Ok(Scope { perm, tenant })
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let perm = match self.perm {
+            Permission::Admin => "admin",
+            Permission::Validate => "validate",
+            Permission::Test => "test",
+            Permission::Bundle => "bundle",
+        };
+        match self.tenant {
+            Some(t) => write!(f, "{perm}:{t}"),
+            None => f.write_str(perm),
+        }
+    }
+}
+
+/// Authenticated caller of an admin route.
+#[derive(Debug, Clone)]
+pub struct AdminPrincipal {
+    pub name: String,
+    pub scopes: Vec<Scope>,
+}
+
+impl AdminPrincipal {
+    /// `tenant = None` means the operation touches no tenant data; any scope
+    /// granting `perm` (tenant-restricted or not) is then enough.
+    pub fn allows(&self, perm: Permission, tenant: Option<Uuid>) -> bool {
+        self.scopes.iter().any(|s| {
+            (s.perm == perm || s.perm == Permission::Admin)
+                && match (s.tenant, tenant) {
+                    (None, _) | (Some(_), None) => true,
+                    (Some(a), Some(b)) => a == b,
+                }
+        })
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum AdminAuthError {
+    #[error("admin credentials required")]
+    Missing,
+    #[error("invalid api key")]
+    BadApiKey,
+    #[error("invalid bearer token: {0}")]
+    BadToken(String),
+    #[error("bearer token given but OIDC is not configured")]
+    OidcDisabled,
+    #[error("client certificate {0:?} not authorized")]
+    UnknownCert(String),
+    #[error("missing scope {0}")]
+    Forbidden(String),
+}
+
+impl AdminAuthError {
+    fn label(&self) -> &'static str {
+        match self {
+            AdminAuthError::Missing => "missing",
+            AdminAuthError::BadApiKey => "api_key",
+            AdminAuthError::BadToken(_) => "token",
+            AdminAuthError::OidcDisabled => "oidc_disabled",
+            AdminAuthError::UnknownCert(_) => "cert",
+            AdminAuthError::Forbidden(_) => "forbidden",
+        }
+    }
+
+    pub fn into_response(self) -> (StatusCode, Json<AuthzDecision>) {
+        metrics::counter!("pdp_admin_auth_failures_total", "reason" => self.label()).increment(1);
+        let status = match self {
+            AdminAuthError::Forbidden(_) => StatusCode::FORBIDDEN,
+            _ => StatusCode::UNAUTHORIZED,
+        };
+        (
+            status,
+            Json(AuthzDecision {
+                decision: "DENY".into(),
+                reason: self.to_string(),
+            }),
+        )
+    }
+}
+
+#[derive(Deserialize)]
+struct ApiKeyEntry {
+    name: String,
+    /// Hex SHA-256 of the key; keys themselves are never stored.
+    key_sha256: String,
+    scopes: Vec<String>,
+}
+
+struct Oidc {
+    keys: KeySet,
+    settings: TenantJwtSettings,
+    role_map: HashMap<String, Vec<String>>,
+    // Unmapped roles naming a scope (`test:<tenant-id>`) are granted as that scope
+    scope_roles: bool,
+}
+
+/// The default accepts no credentials.
+#[derive(Default)]
+pub struct AdminAuth {
+    // sha256(key) -> principal
+    api_keys: HashMap<[u8; 32], AdminPrincipal>,
+    oidc: Option<Oidc>,
+    // client certificate subject -> scopes
+    cert_scopes: HashMap<String, Vec<Scope>>,
+    disabled: bool,
+}
+
+impl AdminAuth {
+    /// * `ADMIN_API_KEYS` / `ADMIN_API_KEYS_FILE` — JSON list of `{name, key_sha256, scopes}`
+    /// * `ADMIN_OIDC_JWKS_PATH`, `ADMIN_OIDC_ISSUER`, `ADMIN_OIDC_AUDIENCE` (comma
+    ///   separated, required with OIDC)
+    /// * `ADMIN_OIDC_ROLE_MAP` — JSON map of role → scopes
+    ///   (default `{"admin":["admin"],"ops":["validate","test"]}`)
+    /// * `ADMIN_OIDC_SCOPE_ROLES=1` — also accept unmapped roles that name a scope
+    /// * `ADMIN_CERT_SCOPES` — JSON map of client certificate subject → scopes
+    /// * `ADMIN_AUTH_DISABLED=1` — dev only, every caller gets `admin`
+    pub async fn from_env() -> anyhow::Result<Self> {
+        let disabled = env::var("ADMIN_AUTH_DISABLED")
+            .map(|v| v == "1" || v.to_lowercase() == "true")
+            .unwrap_or(false);
+
+        let entries: Vec<ApiKeyEntry> =
+            match (env::var("ADMIN_API_KEYS"), env::var("ADMIN_API_KEYS_FILE")) {
+                (Ok(inline), _) => serde_json::from_str(&inline)?,
+                (_, Ok(path)) => serde_json::from_slice(&tokio::fs::read(path).await?)?,
+                _ => Vec::new(),
+            };
+        let mut api_keys = HashMap::new();
+        for e in entries {
+            let mut digest = [0u8; 32];
+            let raw = decode_hex(&e.key_sha256)
+                .filter(|b| b.len() == 32)
+                .ok_or_else(|| {
+                    anyhow::anyhow!("api key {}: key_sha256 must be 64 hex chars", e.name)
+                })?;
+            digest.copy_from_slice(&raw);
+            let scopes = parse_scopes(&e.scopes)
+                .map_err(|err| anyhow::anyhow!("api key {}: {err}", e.name))?;
+            api_keys.insert(
+                digest,
+                AdminPrincipal {
+                    name: e.name,
+                    scopes,
+                },
+            );
+        }
+
+        let oidc = match env::var("ADMIN_OIDC_JWKS_PATH") {
+            Ok(path) => {
+                // Without an audience any token from the IdP would do, whatever client it was for
+                let audiences = env::var("ADMIN_OIDC_AUDIENCE")
+                    .map(|s| split_audiences(&s))
+                    .unwrap_or_default();
+                if audiences.is_empty() {
+                    anyhow::bail!("ADMIN_OIDC_AUDIENCE is required with ADMIN_OIDC_JWKS_PATH");
+                }
+                let jwks = serde_json::from_slice(&tokio::fs::read(&path).await?)?;
+                let role_map = match env::var("ADMIN_OIDC_ROLE_MAP") {
+                    Ok(s) => serde_json::from_str(&s)?,
+                    Err(_) => HashMap::from([
+                        ("admin".to_string(), vec!["admin".to_string()]),
+                        (
+                            "ops".to_string(),
+                            vec!["validate".to_string(), "test".to_string()],
+                        ),
+                    ]),
+                };
+                Some(Oidc {
+                    keys: KeySet::new(None, Some(jwks)),
+                    settings: TenantJwtSettings {
+                        issuer: env::var("ADMIN_OIDC_ISSUER").ok(),
+                        audiences,
+                        leeway_secs: None,
+                    },
+                    role_map,
+                    scope_roles: env::var("ADMIN_OIDC_SCOPE_ROLES")
+                        .map(|v| v == "1" || v.to_lowercase() == "true")
+                        .unwrap_or(false),
+                })
+            }
+            Err(_) => None,
+        };
+
+        let cert_scopes = match env::var("ADMIN_CERT_SCOPES") {
+            Ok(s) => serde_json::from_str::<HashMap<String, Vec<String>>>(&s)?
+                .into_iter()
+                .map(|(subject, scopes)| {
+                    parse_scopes(&scopes)
+                        .map(|scopes| (subject.clone(), scopes))
+                        .map_err(|err| anyhow::anyhow!("cert {subject}: {err}"))
+                })
+                .collect::<anyhow::Result<_>>()?,
+            Err(_) => HashMap::new(),
+        };
+
+        if disabled {
+            warn!("ADMIN_AUTH_DISABLED is set: /admin routes are unauthenticated");
+        } else {
+            info!(
+                "admin auth: {} api key(s), oidc {}, {} client cert subject(s)",
+                api_keys.len(),
+                if oidc.is_some() { "on" } else { "off" },
+                cert_scopes.len()
+            );
+        }
+        Ok(Self {
+            api_keys,
+            oidc,
+            cert_scopes,
+            disabled,
+        })
+    }
+
+    /// API key first, then bearer token, then the mTLS client certificate. Without
+    /// OIDC a bearer token is ignored in favour of the certificate.
+    pub fn authenticate(
+        &self,
+        headers: &HeaderMap,
+        client_cert: Option<&ClientCert>,
+    ) -> Result<AdminPrincipal, AdminAuthError> {
+        if self.disabled {
+            return Ok(AdminPrincipal {
+                name: "anonymous".into(),
+                scopes: vec![Scope {
+                    perm: Permission::Admin,
+                    tenant: None,
+                }],
+            });
+        }
+
+        if let Some(key) = headers.get(API_KEY_HEADER).and_then(|v| v.to_str().ok()) {
+            let digest: [u8; 32] = Sha256::digest(key.as_bytes()).into();
+            return self
+                .api_keys
+                .get(&digest)
+                .cloned()
+                .ok_or(AdminAuthError::BadApiKey);
+        }
+
+        let bearer = headers
+            .get("authorization")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| {
+                v.strip_prefix("Bearer ")
+                    .or_else(|| v.strip_prefix("bearer "))
+            });
+        match (bearer, &self.oidc, client_cert) {
+            (Some(token), Some(oidc), _) => oidc.authenticate(token.trim()),
+            (_, _, Some(cert)) => match self.cert_scopes.get(&cert.subject) {
+                Some(scopes) => Ok(AdminPrincipal {
+                    name: format!("cert:{}", cert.subject),
+                    scopes: scopes.clone(),
+                }),
+                None => Err(AdminAuthError::UnknownCert(cert.subject.clone())),
+            },
+            (Some(_), None, None) => Err(AdminAuthError::OidcDisabled),
+            (None, _, None) => Err(AdminAuthError::Missing),
+        }
+    }
+}
+
+impl Oidc {
+    fn authenticate(&self, token: &str) -> Result<AdminPrincipal, AdminAuthError> {
+        let bad = |e: &dyn fmt::Display| AdminAuthError::BadToken(e.to_string());
+        let claims = self.keys.decode(token).map_err(|e| bad(&e))?;
+        check_registered_claims(&claims, &self.settings, DEFAULT_LEEWAY_SECS)
+            .map_err(|e| bad(&e))?;
+
+        // `roles` or Keycloak's `realm_access.roles`
+        let roles = claims
+            .get("roles")
+            .or_else(|| claims.get("realm_access").and_then(|r| r.get("roles")))
+            .and_then(Value::as_array)
+            .map(|a| a.iter().filter_map(Value::as_str).collect::<Vec<_>>())
+            .unwrap_or_default();
+
+        let mut scopes = Vec::new();
+        for role in roles {
+            match self.role_map.get(role) {
+                Some(mapped) => scopes.extend(parse_scopes(mapped).map_err(|e| bad(&e))?),
+                // Anyone who can edit IdP roles could otherwise grant themselves `admin`
+                None if self.scope_roles => scopes.extend(role.parse::<Scope>().ok()),
+                None => {}
+            }
+        }
+
+        let name = claims
+            .get("preferred_username")
+            .or_else(|| claims.get("sub"))
+            .and_then(Value::as_str)
+            .unwrap_or("oidc")
+            .to_string();
+        Ok(AdminPrincipal { name, scopes })
+    }
+}
+
+fn parse_scopes(raw: &[String]) -> Result<Vec<Scope>, String> {
+    raw.iter().map(|s| s.parse()).collect()
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    s.as_bytes()
+        .chunks(2)
+        .map(|pair| match pair {
+            [_, _] => u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok(),
+            _ => None,
+        })
+        .collect()
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for AdminPrincipal {
+    type Rejection = (StatusCode, Json<AuthzDecision>);
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        state
+            .admin_auth
+            .authenticate(&parts.headers, parts.extensions.get::<ClientCert>())
+            .map_err(AdminAuthError::into_response)
+    }
+}
+
+/// Fails with 403 unless `principal` holds `perm` for `tenant`.
+pub fn require(
+    principal: &AdminPrincipal,
+    perm: Permission,
+    tenant: Option<Uuid>,
+) -> Result<(), (StatusCode, Json<AuthzDecision>)> {
+    if principal.allows(perm, tenant) {
+        return Ok(());
+    }
+    let needed = Scope { perm, tenant };
+    warn!("admin {} denied: missing scope {}", principal.name, needed);
+    Err(AdminAuthError::Forbidden(needed.to_string()).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{EncodingKey, Header};
+    use serde_json::json;
+
+    const TENANT_A: Uuid = Uuid::from_u128(1);
+    const TENANT_B: Uuid = Uuid::from_u128(2);
+    const OIDC_SECRET: &[u8] = b"oidc-secret";
+
+    fn scopes(raw: &[&str]) -> Vec<Scope> {
+        raw.iter().map(|s| s.parse().unwrap()).collect()
+    }
+
+    fn auth() -> AdminAuth {
+        let key_a: [u8; 32] = Sha256::digest(b"key-a").into();
+        AdminAuth {
+            api_keys: HashMap::from([(
+                key_a,
+                AdminPrincipal {
+                    name: "ci-a".into(),
+                    scopes: scopes(&[&format!("test:{TENANT_A}")]),
+                },
+            )]),
+            oidc: Some(Oidc {
+                keys: KeySet::new(Some(OIDC_SECRET.to_vec()), None),
+                settings: TenantJwtSettings {
+                    issuer: Some("https://idp".into()),
+                    audiences: vec!["pdp-admin".into()],
+                    leeway_secs: None,
+                },
+                role_map: HashMap::from([("ops".into(), vec!["validate".into(), "test".into()])]),
+                scope_roles: false,
+            }),
+            cert_scopes: HashMap::from([("CN=deployer".into(), scopes(&["bundle"]))]),
+            disabled: false,
+        }
+    }
+
+    fn headers(name: &'static str, value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, value.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn tenant_scoped_api_key_is_limited_to_its_tenant() {
+        let caller = auth()
+            .authenticate(&headers(API_KEY_HEADER, "key-a"), None)
+            .unwrap();
+        assert_eq!(caller.name, "ci-a");
+        assert!(require(&caller, Permission::Test, Some(TENANT_A)).is_ok());
+        let (status, _) = require(&caller, Permission::Test, Some(TENANT_B)).unwrap_err();
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert!(!caller.allows(Permission::Bundle, Some(TENANT_A)));
+    }
+
+    #[test]
+    fn bad_api_key_is_refused() {
+        // A bad key is not retried as a bearer token or certificate
+        let mut h = headers(API_KEY_HEADER, "key-b");
+        h.insert("authorization", "Bearer x".parse().unwrap());
+        let cert = ClientCert {
+            subject: "CN=deployer".into(),
+        };
+        assert!(matches!(
+            auth().authenticate(&h, Some(&cert)),
+            Err(AdminAuthError::BadApiKey)
+        ));
+    }
+
+    /// Bearer headers for a token from the test IdP, valid for five minutes.
+    fn oidc_bearer(aud: &str, roles: Value) -> HeaderMap {
+        let now = time::OffsetDateTime::now_utc().unix_timestamp();
+        let token = jsonwebtoken::encode(
+            &Header::default(),
+            &json!({
+                "iss": "https://idp",
+                "aud": aud,
+                "exp": now + 300,
+                "preferred_username": "dana",
+                "realm_access": { "roles": roles },
+            }),
+            &EncodingKey::from_secret(OIDC_SECRET),
+        )
+        .unwrap();
+        headers("authorization", &format!("Bearer {token}"))
+    }
+
+    #[test]
+    fn oidc_roles_from_realm_access() {
+        let roles = json!(["ops", format!("bundle:{TENANT_B}"), "unrelated"]);
+        let caller = auth()
+            .authenticate(&oidc_bearer("pdp-admin", roles), None)
+            .unwrap();
+        assert_eq!(caller.name, "dana");
+        assert_eq!(caller.scopes, scopes(&["validate", "test"]));
+        assert!(caller.allows(Permission::Test, Some(TENANT_A)));
+        assert!(!caller.allows(Permission::Bundle, Some(TENANT_B)));
+    }
+
+    #[test]
+    fn unmapped_oidc_roles_grant_nothing() {
+        let caller = auth()
+            .authenticate(&oidc_bearer("pdp-admin", json!(["admin"])), None)
+            .unwrap();
+        assert!(caller.scopes.is_empty());
+        let (status, _) = require(&caller, Permission::Admin, None).unwrap_err();
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+
+    #[test]
+    fn scope_roles_are_opt_in() {
+        let mut auth = auth();
+        auth.oidc.as_mut().unwrap().scope_roles = true;
+        let roles = json!(["ops", format!("bundle:{TENANT_B}"), "unrelated"]);
+        let caller = auth
+            .authenticate(&oidc_bearer("pdp-admin", roles), None)
+            .unwrap();
+        assert_eq!(
+            caller.scopes,
+            scopes(&["validate", "test", &format!("bundle:{TENANT_B}")])
+        );
+        assert!(caller.allows(Permission::Bundle, Some(TENANT_B)));
+    }
+
+    #[test]
+    fn oidc_tokens_for_another_audience_are_refused() {
+        let err = auth()
+            .authenticate(&oidc_bearer("grafana", json!(["ops"])), None)
+            .unwrap_err();
+        assert!(matches!(err, AdminAuthError::BadToken(_)), "{err}");
+    }
+
+    #[test]
+    fn client_certificates_need_a_known_subject() {
+        let known = ClientCert {
+            subject: "CN=deployer".into(),
+        };
+        let caller = auth()
+            .authenticate(&HeaderMap::new(), Some(&known))
+            .unwrap();
+        assert_eq!(caller.name, "cert:CN=deployer");
+        assert!(caller.allows(Permission::Bundle, Some(TENANT_B)));
+
+        let unknown = ClientCert {
+            subject: "CN=intruder".into(),
+        };
+        assert!(matches!(
+            auth().authenticate(&HeaderMap::new(), Some(&unknown)),
+            Err(AdminAuthError::UnknownCert(s)) if s == "CN=intruder"
+        ));
+        assert!(matches!(
+            auth().authenticate(&HeaderMap::new(), None),
+            Err(AdminAuthError::Missing)
+        ));
+    }
+
+    #[test]
+    fn bearer_without_oidc_falls_back_to_the_certificate() {
+        let no_oidc = AdminAuth {
+            oidc: None,
+            ..auth()
+        };
+        let bearer = headers("authorization", "Bearer x");
+        let cert = ClientCert {
+            subject: "CN=deployer".into(),
+        };
+        let caller = no_oidc.authenticate(&bearer, Some(&cert)).unwrap();
+        assert_eq!(caller.name, "cert:CN=deployer");
+
+        let err = no_oidc.authenticate(&bearer, None).unwrap_err();
+        assert!(matches!(err, AdminAuthError::OidcDisabled));
+        assert_eq!(
+            err.to_string(),
+            "bearer token given but OIDC is not configured"
+        );
+    }
+}
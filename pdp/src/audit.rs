@@ -0,0 +1,355 @@
+//! Decision audit trail, written off the request path.
+//!
+//! `/check` hands each [`AuditRecord`] to a bounded queue (`AUDIT_QUEUE_CAPACITY`,
+//! default 10000) and answers without waiting for the database. A background task
+//! batches the queue into multi-row INSERTs, flushing when `AUDIT_BATCH_SIZE`
+//! (default 500) records are waiting or `AUDIT_FLUSH_MS` (default 100) after the
+//! first one arrived. When the queue is full `AUDIT_BACKPRESSURE` decides:
+//! `block` (default) waits for room, `drop` discards the record and counts it.
+//! [`AuditWriter::shutdown`] writes what is still queued.
+//!
+//! A batch that fails to insert is logged under the `audit` tracing target, like
+//! every record when there is no database, so it is not lost silently.
+//!
+//! Metrics: `pdp_audit_queue_depth`, `pdp_audit_batch_size`,
+//! `pdp_audit_dropped_total` and `pdp_audit_write_failures_total` (records).
+
+use crate::db::TenantTx;
+use sqlx::{PgPool, Postgres, QueryBuilder};
+use std::{
+    env,
+    sync::{Arc, Mutex, PoisonError},
+    time::Duration,
+};
+use tokio::{
+    sync::{
+        mpsc::{self, error::TrySendError},
+        Notify,
+    },
+    task::JoinHandle,
+    time::Instant,
+};
+use tracing::{error, info};
+use uuid::Uuid;
+
+const DEFAULT_QUEUE_CAPACITY: usize = 10_000;
+const DEFAULT_BATCH_SIZE: usize = 500;
+const DEFAULT_FLUSH_MS: u64 = 100;
+// 9 bind parameters per row; Postgres takes at most 65535 per statement
+const ROWS_PER_INSERT: usize = 1000;
+
+#[derive(Debug)]
+pub struct AuditRecord {
+    pub tenant_id: Uuid,
+    pub principal: String,
+    pub resource: String,
+    pub action: String,
+    pub decision: &'static str,
+    pub policy_set_version: Option<i32>,
+    pub latency_ms: i32,
+    // Decision source: "cedar" | "default" | "break_glass"
+    pub source: &'static str,
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backpressure {
+    Block,
+    Drop,
+}
+
+struct Queue {
+    tx: mpsc::Sender<AuditRecord>,
+    backpressure: Backpressure,
+    closing: Arc<Notify>,
+    worker: Mutex<Option<JoinHandle<()>>>,
+}
+
+/// Hands audit records to the background writer; `None` database logs them.
+pub struct AuditWriter {
+    queue: Option<Queue>,
+    // Records kept in memory instead, see `capturing`
+    #[cfg(test)]
+    captured: Option<Mutex<Vec<AuditRecord>>>,
+}
+
+impl AuditWriter {
+    pub fn from_env(db: Option<PgPool>) -> anyhow::Result<Self> {
+        let num = |name: &str, default: u64| {
+            env::var(name)
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(default)
+        };
+        let backpressure = match env::var("AUDIT_BACKPRESSURE").as_deref() {
+            Err(_) | Ok("block") => Backpressure::Block,
+            Ok("drop") => Backpressure::Drop,
+            Ok(other) => anyhow::bail!("unknown AUDIT_BACKPRESSURE {other:?} (block|drop)"),
+        };
+        Ok(Self::new(
+            db,
+            num("AUDIT_QUEUE_CAPACITY", DEFAULT_QUEUE_CAPACITY as u64) as usize,
+            num("AUDIT_BATCH_SIZE", DEFAULT_BATCH_SIZE as u64) as usize,
+            Duration::from_millis(num("AUDIT_FLUSH_MS", DEFAULT_FLUSH_MS)),
+            backpressure,
+        ))
+    }
+
+    /// Starts the writer task when there is a database.
+    pub fn new(
+        db: Option<PgPool>,
+        capacity: usize,
+        batch_size: usize,
+        flush_every: Duration,
+        backpressure: Backpressure,
+    ) -> Self {
+        let queue = db.map(|db| {
+            let (tx, rx) = mpsc::channel(capacity.max(1));
+            let closing = Arc::new(Notify::new());
+            let worker = tokio::spawn(run(db, rx, closing.clone(), batch_size.max(1), flush_every));
+            Queue {
+                tx,
+                backpressure,
+                closing,
+                worker: Mutex::new(Some(worker)),
+            }
+        });
+        Self {
+            queue,
+            #[cfg(test)]
+            captured: None,
+        }
+    }
+
+    /// A writer that keeps records for [`AuditWriter::captured`].
+    #[cfg(test)]
+    pub fn capturing() -> Self {
+        Self {
+            queue: None,
+            captured: Some(Mutex::default()),
+        }
+    }
+
+    /// Takes the records captured so far.
+    #[cfg(test)]
+    pub fn captured(&self) -> Vec<AuditRecord> {
+        self.captured
+            .as_ref()
+            .map(|c| std::mem::take(&mut *c.lock().unwrap_or_else(PoisonError::into_inner)))
+            .unwrap_or_default()
+    }
+
+    pub async fn record(&self, rec: AuditRecord) {
+        #[cfg(test)]
+        if let Some(captured) = &self.captured {
+            captured
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .push(rec);
+            return;
+        }
+        let Some(queue) = &self.queue else {
+            log(&rec);
+            return;
+        };
+        let sent = match queue.backpressure {
+            Backpressure::Block => queue.tx.send(rec).await.map_err(|e| e.0),
+            Backpressure::Drop => match queue.tx.try_send(rec) {
+                Ok(()) => Ok(()),
+                Err(TrySendError::Full(_)) => {
+                    metrics::counter!("pdp_audit_dropped_total").increment(1);
+                    return;
+                }
+                Err(TrySendError::Closed(rec)) => Err(rec),
+            },
+        };
+        match sent {
+            Ok(()) => depth(&queue.tx),
+            // Shutting down: the writer no longer takes records
+            Err(rec) => log(&rec),
+        }
+    }
+
+    /// Stops taking records and writes the queued ones.
+    pub async fn shutdown(&self) {
+        let Some(queue) = &self.queue else {
+            return;
+        };
+        let worker = queue
+            .worker
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take();
+        if let Some(worker) = worker {
+            info!("flushing the audit queue");
+            queue.closing.notify_one();
+            let _ = worker.await;
+        }
+    }
+}
+
+fn depth(tx: &mpsc::Sender<AuditRecord>) {
+    metrics::gauge!("pdp_audit_queue_depth").set((tx.max_capacity() - tx.capacity()) as f64);
+}
+
+async fn run(
+    db: PgPool,
+    mut rx: mpsc::Receiver<AuditRecord>,
+    closing: Arc<Notify>,
+    batch_size: usize,
+    flush_every: Duration,
+) {
+    let mut batch = Vec::with_capacity(batch_size);
+    let mut closed = false;
+    while !closed {
+        // Wait for the first record, then up to `flush_every` for more
+        tokio::select! {
+            rec = rx.recv() => match rec {
+                Some(rec) => batch.push(rec),
+                None => break,
+            },
+            _ = closing.notified() => closed = true,
+        }
+        let deadline = Instant::now() + flush_every;
+        while !closed && batch.len() < batch_size {
+            tokio::select! {
+                rec = rx.recv() => match rec {
+                    Some(rec) => batch.push(rec),
+                    None => break,
+                },
+                _ = tokio::time::sleep_until(deadline) => break,
+                _ = closing.notified() => closed = true,
+            }
+        }
+        if closed {
+            rx.close();
+            while let Some(rec) = rx.recv().await {
+                batch.push(rec);
+            }
+        }
+        metrics::gauge!("pdp_audit_queue_depth").set(rx.len() as f64);
+        for chunk in batch.chunks(batch_size) {
+            write(&db, chunk).await;
+        }
+        batch.clear();
+    }
+}
+
+async fn write(db: &PgPool, batch: &[AuditRecord]) {
+    if batch.is_empty() {
+        return;
+    }
+    metrics::histogram!("pdp_audit_batch_size").record(batch.len() as f64);
+    let mut by_tenant: Vec<&AuditRecord> = batch.iter().collect();
+    by_tenant.sort_by_key(|rec| rec.tenant_id);
+    for rows in by_tenant.chunk_by(|a, b| a.tenant_id == b.tenant_id) {
+        if let Err(e) = insert(db, rows).await {
+            metrics::counter!("pdp_audit_write_failures_total").increment(rows.len() as u64);
+            error!("audit write of {} records failed: {e}", rows.len());
+            rows.iter().copied().for_each(log);
+        }
+    }
+}
+
+/// Inserts one tenant's records: audit_logs is under RLS too.
+async fn insert(db: &PgPool, rows: &[&AuditRecord]) -> Result<(), sqlx::Error> {
+    let mut tx = TenantTx::begin(db, rows[0].tenant_id).await?;
+    for chunk in rows.chunks(ROWS_PER_INSERT) {
+        QueryBuilder::<Postgres>::new(
+            "INSERT INTO audit_logs (tenant_id, principal, resource, action, decision, policy_set_version, latency_ms, source, reason) ",
+        )
+        .push_values(chunk, |mut row, rec| {
+            row.push_bind(rec.tenant_id)
+                .push_bind(&rec.principal)
+                .push_bind(&rec.resource)
+                .push_bind(&rec.action)
+                .push_bind(rec.decision)
+                .push_bind(rec.policy_set_version)
+                .push_bind(rec.latency_ms)
+                .push_bind(rec.source)
+                .push_bind(&rec.reason);
+        })
+        .build()
+        .execute(&mut *tx)
+        .await?;
+    }
+    tx.commit().await
+}
+
+fn log(rec: &AuditRecord) {
+    info!(
+        target: "audit",
+        tenant_id = %rec.tenant_id,
+        principal = rec.principal,
+        resource = rec.resource,
+        action = rec.action,
+        decision = rec.decision,
+        policy_set_version = rec.policy_set_version,
+        latency_ms = rec.latency_ms,
+        source = rec.source,
+        reason = rec.reason,
+        "decision"
+    );
+}
+
+/// Needs `TEST_DATABASE_URL` (reads and cleanup) and `TEST_PDP_DATABASE_URL`
+/// (writes, under RLS); skipped when either is unset.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(tenant_id: Uuid, i: usize) -> AuditRecord {
+        AuditRecord {
+            tenant_id,
+            principal: format!("User::\"{i}\""),
+            resource: "Document::\"d\"".into(),
+            action: "Action::\"read\"".into(),
+            decision: "ALLOW",
+            policy_set_version: Some(1),
+            latency_ms: 0,
+            source: "cedar",
+            reason: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn batches_tenants_and_flushes_on_shutdown() {
+        let Some((owner, pdp)) = crate::db::test_pools().await else {
+            return;
+        };
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+
+        // Never flushes on time: only full batches and the shutdown write
+        let writer = AuditWriter::new(
+            Some(pdp),
+            100,
+            10,
+            Duration::from_secs(3600),
+            Backpressure::Block,
+        );
+        for i in 0..25 {
+            writer.record(record(a, i)).await;
+            writer.record(record(b, i)).await;
+        }
+        writer.shutdown().await;
+        // Closed: later records are logged instead
+        writer.record(record(a, 99)).await;
+
+        let counts: Vec<(Uuid, i64)> = sqlx::query_as(
+            "SELECT tenant_id, count(*) FROM audit_logs WHERE tenant_id = ANY($1) GROUP BY tenant_id ORDER BY tenant_id",
+        )
+        .bind(vec![a, b])
+        .fetch_all(&owner)
+        .await
+        .unwrap();
+        let mut expected = vec![(a, 25), (b, 25)];
+        expected.sort();
+        assert_eq!(counts, expected);
+
+        sqlx::query("DELETE FROM audit_logs WHERE tenant_id = ANY($1)")
+            .bind(vec![a, b])
+            .execute(&owner)
+            .await
+            .unwrap();
+    }
+}
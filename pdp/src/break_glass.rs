@@ -0,0 +1,274 @@
+//! Break-glass access.
+//!
+//! Replaces the old unconditional `x-allow: 1` bypass. A caller presents a
+//! short-lived HS256 token in `x-break-glass`, signed with `BREAK_GLASS_SECRET`
+//! and bound to the tenant (`tid`), the principal (`sub`) and a `justification`.
+//! It is only honoured for tenants listed in `BREAK_GLASS_TENANTS`, and every
+//! grant is audited with source `break_glass`.
+
+use crate::jwt::{check_registered_claims, JwtError, KeySet, TenantJwtSettings};
+use serde_json::Value;
+use std::{collections::HashSet, env};
+use thiserror::Error;
+use tracing::info;
+use uuid::Uuid;
+
+pub const BREAK_GLASS_HEADER: &str = "x-break-glass";
+const DEFAULT_MAX_TTL_SECS: i64 = 900;
+// Clock skew allowed between the token minter and the PDP for `iat`
+const IAT_LEEWAY_SECS: i64 = 30;
+
+#[derive(Error, Debug)]
+pub enum BreakGlassError {
+    #[error("break-glass not enabled for tenant")]
+    NotEnabled,
+    #[error("{0}")]
+    Token(#[from] JwtError),
+    #[error("break-glass token is for another tenant")]
+    TenantMismatch,
+    #[error("break-glass token is for another principal")]
+    PrincipalMismatch,
+    #[error("break-glass token has no justification")]
+    MissingJustification,
+    #[error("break-glass token lifetime exceeds {0}s")]
+    TooLong(i64),
+    #[error("break-glass token issued in the future")]
+    IssuedInFuture,
+}
+
+/// A validated break-glass grant.
+pub struct Grant {
+    pub justification: String,
+}
+
+pub struct BreakGlass {
+    keys: KeySet,
+    // `None` = every tenant
+    tenants: Option<HashSet<Uuid>>,
+    max_ttl_secs: i64,
+}
+
+impl BreakGlass {
+    /// Returns `None` unless `BREAK_GLASS_SECRET` is set.
+    ///
+    /// * `BREAK_GLASS_TENANTS` — comma separated tenant ids, or `*` (default: none)
+    /// * `BREAK_GLASS_MAX_TTL_SECS` — longest accepted `exp - iat` and `exp - now` (default 900)
+    pub fn from_env() -> anyhow::Result<Option<Self>> {
+        let secret = match env::var("BREAK_GLASS_SECRET") {
+            Ok(s) if !s.is_empty() => s,
+            _ => return Ok(None),
+        };
+        let tenants = match env::var("BREAK_GLASS_TENANTS") {
+            Ok(s) if s.trim() == "*" => None,
+            Ok(s) => Some(
+                s.split(',')
+                    .map(str::trim)
+                    .filter(|t| !t.is_empty())
+                    .map(Uuid::parse_str)
+                    .collect::<Result<HashSet<_>, _>>()?,
+            ),
+            Err(_) => Some(HashSet::new()),
+        };
+        let max_ttl_secs = env::var("BREAK_GLASS_MAX_TTL_SECS")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(DEFAULT_MAX_TTL_SECS);
+        info!(
+            "break-glass enabled for {}",
+            match &tenants {
+                None => "all tenants".to_string(),
+                Some(t) => format!("{} tenant(s)", t.len()),
+            }
+        );
+        Ok(Some(Self::new(secret.as_bytes(), tenants, max_ttl_secs)))
+    }
+
+    /// `tenants = None` enables every tenant.
+    pub fn new(secret: &[u8], tenants: Option<HashSet<Uuid>>, max_ttl_secs: i64) -> Self {
+        Self {
+            keys: KeySet::new(Some(secret.to_vec()), None),
+            tenants,
+            max_ttl_secs,
+        }
+    }
+
+    fn enabled_for(&self, tenant: Uuid) -> bool {
+        self.tenants.as_ref().is_none_or(|t| t.contains(&tenant))
+    }
+
+    /// Validates `token` for this tenant and principal.
+    pub fn verify(
+        &self,
+        token: &str,
+        tenant: Uuid,
+        principal: &str,
+    ) -> Result<Grant, BreakGlassError> {
+        if !self.enabled_for(tenant) {
+            return Err(BreakGlassError::NotEnabled);
+        }
+        let claims = self.keys.decode(token)?;
+        check_registered_claims(&claims, &TenantJwtSettings::default(), 0)?;
+
+        let iat = claims
+            .get("iat")
+            .and_then(Value::as_i64)
+            .ok_or_else(|| JwtError::MissingClaim("iat".into()))?;
+        let now = time::OffsetDateTime::now_utc().unix_timestamp();
+        if iat > now + IAT_LEEWAY_SECS {
+            return Err(BreakGlassError::IssuedInFuture);
+        }
+        // `exp` is required by check_registered_claims. Both bounds: a token
+        // dated ahead must not stay valid longer than the max from now on.
+        let exp = claims.get("exp").and_then(Value::as_i64).unwrap_or(iat);
+        if exp - iat > self.max_ttl_secs || exp - now > self.max_ttl_secs {
+            return Err(BreakGlassError::TooLong(self.max_ttl_secs));
+        }
+
+        let tid = claims.get("tid").and_then(Value::as_str);
+        if tid.and_then(|s| Uuid::parse_str(s).ok()) != Some(tenant) {
+            return Err(BreakGlassError::TenantMismatch);
+        }
+        if claims.get("sub").and_then(Value::as_str) != Some(principal) {
+            return Err(BreakGlassError::PrincipalMismatch);
+        }
+        let justification = claims
+            .get("justification")
+            .and_then(Value::as_str)
+            .map(str::trim)
+            .filter(|j| !j.is_empty())
+            .ok_or(BreakGlassError::MissingJustification)?;
+
+        Ok(Grant {
+            justification: justification.to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{EncodingKey, Header};
+    use serde_json::json;
+
+    const SECRET: &[u8] = b"bg-secret";
+    const TENANT: Uuid = Uuid::from_u128(1);
+    const OTHER: Uuid = Uuid::from_u128(2);
+    const PRINCIPAL: &str = "User::\"oncall\"";
+
+    fn break_glass() -> BreakGlass {
+        BreakGlass::new(SECRET, Some(HashSet::from([TENANT])), 900)
+    }
+
+    /// A valid token for TENANT, with `patch` applied to its claims.
+    fn token(patch: impl FnOnce(&mut Value)) -> String {
+        let now = time::OffsetDateTime::now_utc().unix_timestamp();
+        let mut claims = json!({
+            "tid": TENANT.to_string(),
+            "sub": PRINCIPAL,
+            "justification": "INC-42",
+            "iat": now,
+            "exp": now + 300,
+        });
+        patch(&mut claims);
+        jsonwebtoken::encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(SECRET),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn grants_a_valid_token() {
+        let grant = break_glass()
+            .verify(&token(|_| {}), TENANT, PRINCIPAL)
+            .unwrap();
+        assert_eq!(grant.justification, "INC-42");
+    }
+
+    #[test]
+    fn refuses_tenants_not_enabled() {
+        let tok = token(|c| c["tid"] = json!(OTHER.to_string()));
+        assert!(matches!(
+            break_glass().verify(&tok, OTHER, PRINCIPAL),
+            Err(BreakGlassError::NotEnabled)
+        ));
+        // Enabled for every tenant with `*`
+        let all = BreakGlass::new(SECRET, None, 900);
+        assert!(all.verify(&tok, OTHER, PRINCIPAL).is_ok());
+    }
+
+    #[test]
+    fn refuses_a_token_for_another_tenant_or_principal() {
+        let tok = token(|c| c["tid"] = json!(OTHER.to_string()));
+        assert!(matches!(
+            break_glass().verify(&tok, TENANT, PRINCIPAL),
+            Err(BreakGlassError::TenantMismatch)
+        ));
+        assert!(matches!(
+            break_glass().verify(&token(|_| {}), TENANT, "User::\"mallory\""),
+            Err(BreakGlassError::PrincipalMismatch)
+        ));
+    }
+
+    #[test]
+    fn refuses_a_lifetime_above_the_max() {
+        let tok = token(|c| c["exp"] = json!(c["iat"].as_i64().unwrap() + 901));
+        assert!(matches!(
+            break_glass().verify(&tok, TENANT, PRINCIPAL),
+            Err(BreakGlassError::TooLong(900))
+        ));
+    }
+
+    #[test]
+    fn refuses_a_future_iat() {
+        // Short-lived on paper, but valid for ten years
+        let tok = token(|c| {
+            let iat = c["iat"].as_i64().unwrap() + 10 * 365 * 86400;
+            c["iat"] = json!(iat);
+            c["exp"] = json!(iat + 900);
+        });
+        assert!(matches!(
+            break_glass().verify(&tok, TENANT, PRINCIPAL),
+            Err(BreakGlassError::IssuedInFuture)
+        ));
+
+        // Within the clock skew, but still bounded from now
+        let tok = token(|c| {
+            let iat = c["iat"].as_i64().unwrap() + 20;
+            c["iat"] = json!(iat);
+            c["exp"] = json!(iat + 900);
+        });
+        assert!(matches!(
+            break_glass().verify(&tok, TENANT, PRINCIPAL),
+            Err(BreakGlassError::TooLong(900))
+        ));
+        let tok = token(|c| c["iat"] = json!(c["iat"].as_i64().unwrap() + 20));
+        assert!(break_glass().verify(&tok, TENANT, PRINCIPAL).is_ok());
+    }
+
+    #[test]
+    fn refuses_a_missing_justification() {
+        for justification in [Value::Null, json!(""), json!("  ")] {
+            let tok = token(|c| c["justification"] = justification);
+            assert!(matches!(
+                break_glass().verify(&tok, TENANT, PRINCIPAL),
+                Err(BreakGlassError::MissingJustification)
+            ));
+        }
+    }
+
+    #[test]
+    fn refuses_a_token_signed_with_another_secret() {
+        let tok = jsonwebtoken::encode(
+            &Header::default(),
+            &json!({ "tid": TENANT.to_string(), "sub": PRINCIPAL }),
+            &EncodingKey::from_secret(b"other"),
+        )
+        .unwrap();
+        assert!(matches!(
+            break_glass().verify(&tok, TENANT, PRINCIPAL),
+            Err(BreakGlassError::Token(JwtError::Invalid(_)))
+        ));
+    }
+}
@@ -0,0 +1,304 @@
+//! Decision cache: an in-process [`L1`] in front of Redis.
+//!
+//! Redis keys carry the tenant and the policy set version the decision was computed
+//! with, `pdp:decision:{<tenant>}:v<version>:<sha256(principal|resource|action|context)>`,
+//! so a PDP on a newer version never reads older entries. Each tenant also has an
+//! index of its keys (`pdp:decision-index:{<tenant>}`, a sorted set scored by
+//! expiry) so an invalidation can drop all of them at once, and one per principal
+//! and resource the decision was computed for (`pdp:decision-entity:{<tenant>}:<uid>`)
+//! for entity-scoped invalidations. The `{<tenant>}` hash tag keeps all of them
+//! in one Redis Cluster slot.
+//!
+//! The L1 uses the same keys, so it is versioned the same way; invalidations drop
+//! its entries by tenant or entity too.
+//!
+//! Versions do not cover entity changes, so each invalidation also bumps the
+//! tenant's [`Generations`]. A decision whose evaluation overlapped one is not
+//! written back: it may have read the attributes the invalidation was about.
+
+use crate::redis_conn::RedisConn;
+use moka::{sync::Cache, Expiry};
+use redis::AsyncCommands;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    env,
+    sync::{Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+use uuid::Uuid;
+
+pub const TTL_SECS: u64 = 30;
+const DEFAULT_L1_MAX_ENTRIES: u64 = 100_000;
+const DEFAULT_L1_ALLOW_TTL_SECS: u64 = 5;
+const DEFAULT_L1_DENY_TTL_SECS: u64 = 15;
+// Keys deleted per DEL when flushing a tenant
+const FLUSH_CHUNK: usize = 500;
+
+fn index_key(tenant: Uuid) -> String {
+    format!("pdp:decision-index:{{{tenant}}}")
+}
+
+fn entity_index_key(tenant: Uuid, uid: &str) -> String {
+    format!("pdp:decision-entity:{{{tenant}}}:{uid}")
+}
+
+pub fn key(
+    tenant: Uuid,
+    version: i32,
+    principal: &str,
+    resource: &str,
+    action: &str,
+    context_json: &str,
+) -> String {
+    let mut h = Sha256::new();
+    // Length-prefixed, so no two different requests hash the same fields
+    for field in [principal, resource, action, context_json] {
+        h.update((field.len() as u64).to_le_bytes());
+        h.update(field.as_bytes());
+    }
+    format!("pdp:decision:{{{tenant}}}:v{version}:{:x}", h.finalize())
+}
+
+pub async fn get(conn: &mut RedisConn, key: &str) -> redis::RedisResult<Option<String>> {
+    conn.get(key).await
+}
+
+/// Caches `decision` and records the key in the tenant's index and in the index of
+/// each entity in `entities`, trimming entries that have expired since.
+pub async fn put(
+    conn: &mut RedisConn,
+    tenant: Uuid,
+    entities: &[&str],
+    key: &str,
+    decision: &str,
+) -> redis::RedisResult<()> {
+    let now = time::OffsetDateTime::now_utc().unix_timestamp();
+    let mut pipe = redis::pipe();
+    pipe.set_ex(key, decision, TTL_SECS).ignore();
+    let indexes = std::iter::once(index_key(tenant))
+        .chain(entities.iter().map(|uid| entity_index_key(tenant, uid)));
+    for index in indexes {
+        pipe.zadd(&index, key, now + TTL_SECS as i64)
+            .ignore()
+            .zrembyscore(&index, "-inf", now)
+            .ignore()
+            .expire(&index, TTL_SECS as i64)
+            .ignore();
+    }
+    pipe.query_async(conn).await
+}
+
+/// Drops one cached decision; its index entries expire on their own.
+pub async fn delete(conn: &mut RedisConn, key: &str) -> redis::RedisResult<()> {
+    conn.del(key).await
+}
+
+/// Drops every cached decision of the tenant. Returns how many keys were indexed.
+pub async fn flush_tenant(conn: &mut RedisConn, tenant: Uuid) -> redis::RedisResult<usize> {
+    flush_index(conn, &index_key(tenant)).await
+}
+
+/// Drops every cached decision computed for the entity as principal or resource.
+pub async fn flush_entity(
+    conn: &mut RedisConn,
+    tenant: Uuid,
+    uid: &str,
+) -> redis::RedisResult<usize> {
+    flush_index(conn, &entity_index_key(tenant, uid)).await
+}
+
+// Keys already gone from an index (expired, or flushed through another index)
+// are harmless: DEL skips them.
+async fn flush_index(conn: &mut RedisConn, index: &str) -> redis::RedisResult<usize> {
+    let keys: Vec<String> = conn.zrange(index, 0, -1).await?;
+    for chunk in keys.chunks(FLUSH_CHUNK) {
+        conn.del::<_, ()>(chunk).await?;
+    }
+    conn.del::<_, ()>(index).await?;
+    Ok(keys.len())
+}
+
+/// Invalidation generation per tenant: read before a decision is evaluated and
+/// checked again before it is cached.
+#[derive(Default)]
+pub struct Generations {
+    // Per tenant, plus the epoch for all of them
+    tenants: Mutex<(u64, HashMap<Uuid, u64>)>,
+}
+
+impl Generations {
+    pub fn current(&self, tenant: Uuid) -> u64 {
+        let tenants = self.tenants.lock().unwrap_or_else(PoisonError::into_inner);
+        tenants.0 + tenants.1.get(&tenant).copied().unwrap_or(0)
+    }
+
+    /// Before the tenant's decisions (or some of them) are flushed.
+    pub fn bump(&self, tenant: Uuid) {
+        let mut tenants = self.tenants.lock().unwrap_or_else(PoisonError::into_inner);
+        *tenants.1.entry(tenant).or_default() += 1;
+    }
+
+    pub fn bump_all(&self) {
+        self.tenants
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .0 += 1;
+    }
+}
+
+#[derive(Clone)]
+struct L1Entry {
+    allow: bool,
+    tenant: Uuid,
+    principal: String,
+    resource: String,
+}
+
+struct L1Ttl {
+    allow: Duration,
+    deny: Duration,
+}
+
+impl Expiry<String, L1Entry> for L1Ttl {
+    fn expire_after_create(&self, _: &String, entry: &L1Entry, _: Instant) -> Option<Duration> {
+        Some(if entry.allow { self.allow } else { self.deny })
+    }
+}
+
+/// Bounded in-process decision cache (TinyLFU admission, LRU eviction) with
+/// separate allow and deny TTLs.
+pub struct L1 {
+    cache: Cache<String, L1Entry>,
+}
+
+impl L1 {
+    /// `DECISION_L1_MAX_ENTRIES` (default 100000, `0` disables the L1),
+    /// `DECISION_L1_ALLOW_TTL_SECS` (default 5) and `DECISION_L1_DENY_TTL_SECS`
+    /// (default 15).
+    pub fn from_env() -> Option<Self> {
+        let num = |name: &str, default: u64| {
+            env::var(name)
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(default)
+        };
+        let max_entries = num("DECISION_L1_MAX_ENTRIES", DEFAULT_L1_MAX_ENTRIES);
+        (max_entries > 0).then(|| {
+            Self::new(
+                max_entries,
+                Duration::from_secs(num("DECISION_L1_ALLOW_TTL_SECS", DEFAULT_L1_ALLOW_TTL_SECS)),
+                Duration::from_secs(num("DECISION_L1_DENY_TTL_SECS", DEFAULT_L1_DENY_TTL_SECS)),
+            )
+        })
+    }
+
+    pub fn new(max_entries: u64, allow_ttl: Duration, deny_ttl: Duration) -> Self {
+        let cache = Cache::builder()
+            .max_capacity(max_entries)
+            .expire_after(L1Ttl {
+                allow: allow_ttl,
+                deny: deny_ttl,
+            })
+            .support_invalidation_closures()
+            .build();
+        Self { cache }
+    }
+
+    /// `"ALLOW"` / `"DENY"`, as stored in Redis.
+    pub fn get(&self, key: &str) -> Option<&'static str> {
+        self.cache
+            .get(key)
+            .map(|e| if e.allow { "ALLOW" } else { "DENY" })
+    }
+
+    pub fn put(&self, tenant: Uuid, principal: &str, resource: &str, key: &str, decision: &str) {
+        let entry = L1Entry {
+            allow: decision == "ALLOW",
+            tenant,
+            principal: principal.to_string(),
+            resource: resource.to_string(),
+        };
+        self.cache.insert(key.to_string(), entry);
+    }
+
+    /// Drops the tenant's decisions, or with `entity` only those computed for
+    /// that principal or resource.
+    pub fn invalidate(&self, tenant: Uuid, entity: Option<&str>) {
+        let entity = entity.map(str::to_string);
+        // Only fails without support_invalidation_closures(), which new() sets
+        let _ = self.cache.invalidate_entries_if(move |_, e| {
+            e.tenant == tenant
+                && entity
+                    .as_deref()
+                    .is_none_or(|uid| e.principal == uid || e.resource == uid)
+        });
+    }
+
+    pub fn remove(&self, key: &str) {
+        self.cache.invalidate(key);
+    }
+
+    pub fn clear(&self) {
+        self.cache.invalidate_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_carry_tenant_slot_and_version() {
+        let t = Uuid::from_u128(1);
+        let k1 = key(t, 1, "User::\"a\"", "Doc::\"d\"", "read", "{}");
+        let k2 = key(t, 2, "User::\"a\"", "Doc::\"d\"", "read", "{}");
+        assert_ne!(k1, k2);
+        // Fields shifted from one into the next
+        assert_ne!(k1, key(t, 1, "User::\"a\"", "Doc::\"d\"r", "ead", "{}"));
+        let tag = format!("{{{t}}}");
+        assert!(k1.contains(&tag) && index_key(t).contains(&tag));
+        assert!(entity_index_key(t, "User::\"a\"").contains(&tag));
+        assert_ne!(
+            k1,
+            key(
+                Uuid::from_u128(2),
+                1,
+                "User::\"a\"",
+                "Doc::\"d\"",
+                "read",
+                "{}"
+            )
+        );
+    }
+
+    #[test]
+    fn l1_invalidates_by_tenant_and_entity() {
+        let ttl = Duration::from_secs(60);
+        let l1 = L1::new(100, ttl, ttl);
+        let (t1, t2) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        let (alice, bob, doc) = ("User::\"alice\"", "User::\"bob\"", "Doc::\"d\"");
+        l1.put(t1, alice, doc, "a", "ALLOW");
+        l1.put(t1, bob, doc, "b", "DENY");
+        l1.put(t1, bob, "Doc::\"e\"", "c", "ALLOW");
+        l1.put(t2, alice, doc, "d", "ALLOW");
+        assert_eq!(l1.get("b"), Some("DENY"));
+
+        l1.invalidate(t1, Some(doc));
+        assert_eq!((l1.get("a"), l1.get("b")), (None, None));
+        assert_eq!((l1.get("c"), l1.get("d")), (Some("ALLOW"), Some("ALLOW")));
+
+        l1.invalidate(t1, None);
+        assert_eq!((l1.get("c"), l1.get("d")), (None, Some("ALLOW")));
+    }
+
+    #[test]
+    fn l1_expires_allow_and_deny_separately() {
+        let l1 = L1::new(100, Duration::from_millis(20), Duration::from_secs(60));
+        let t = Uuid::from_u128(1);
+        l1.put(t, "User::\"a\"", "Doc::\"d\"", "allow", "ALLOW");
+        l1.put(t, "User::\"a\"", "Doc::\"e\"", "deny", "DENY");
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!((l1.get("allow"), l1.get("deny")), (None, Some("DENY")));
+    }
+}
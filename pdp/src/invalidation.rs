@@ -0,0 +1,539 @@
+//! Policy cache invalidation transports.
+//!
+//! `INVALIDATION_TRANSPORTS` is a comma separated list; by default every transport
+//! that is available:
+//! * `redis`: pub/sub on `pdp:invalidate`, published by the admin API (needs Redis;
+//!   with cluster any node hears every publish, with sentinel the current master);
+//! * `postgres`: `LISTEN pdp_invalidate`, fed by triggers on tenant data
+//!   (`0009_invalidation_notify.sql`), so no publish step is needed (Postgres store).
+//!
+//! Both feed one channel. [`dispatch`] applies what arrives within `COALESCE_WINDOW`
+//! once, so the same change over both transports, or one notification per written
+//! row, costs a single reload.
+//!
+//! Listeners reconnect with backoff (`RECONNECT_MIN`..`RECONNECT_MAX`) and send
+//! [`Event::Resync`] after every (re)connect, since messages sent while they were
+//! down are lost. [`ListenerHealth`] tracks them for `/ready` and
+//! `pdp_invalidation_listener_up{transport}`.
+
+use crate::{redis_conn::Topology, store::ChangeKind};
+use futures::StreamExt;
+use serde::Deserialize;
+use sqlx::{postgres::PgListener, PgPool};
+use std::{
+    collections::HashMap,
+    env,
+    future::Future,
+    sync::{Arc, Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+pub const REDIS_CHANNEL: &str = "pdp:invalidate";
+pub const POSTGRES_CHANNEL: &str = "pdp_invalidate";
+const COALESCE_WINDOW: Duration = Duration::from_millis(50);
+const RECONNECT_MIN: Duration = Duration::from_millis(500);
+const RECONNECT_MAX: Duration = Duration::from_secs(30);
+const DEFAULT_MAX_DOWN_SECS: u64 = 60;
+
+/// `{"tenant_id": "...", "status": "suspended"}`; `status` is optional and a bare
+/// tenant id is accepted too. `{"tenant_id": "...", "entity_uid": "User::\"alice\""}`
+/// invalidates one principal or resource. Postgres notifications carry `kind` and `uid`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct InvalidationMsg {
+    pub tenant_id: Uuid,
+    #[serde(default)]
+    pub status: Option<String>,
+    /// `None`: anything of the tenant may have changed
+    #[serde(default)]
+    pub kind: Option<ChangeKind>,
+    #[serde(default, alias = "entity_uid")]
+    pub uid: Option<String>,
+}
+
+impl InvalidationMsg {
+    pub fn tenant(tenant_id: Uuid) -> Self {
+        Self {
+            tenant_id,
+            status: None,
+            kind: None,
+            uid: None,
+        }
+    }
+
+    /// The entity the message is scoped to: `kind` entity, or a uid without a kind.
+    pub fn entity(&self) -> Option<&str> {
+        match self.kind {
+            Some(ChangeKind::Entity) | None => self.uid.as_deref(),
+            Some(_) => None,
+        }
+    }
+
+    /// What the message invalidates: one entity, or the tenant's policies and meta.
+    fn scope(&self) -> (Uuid, Option<&str>) {
+        (self.tenant_id, self.entity())
+    }
+
+    /// Folds a later message with the same scope into this one: different kinds
+    /// widen to `None` (anything changed), the later status wins.
+    fn merge(&mut self, later: &InvalidationMsg) {
+        if self.kind != later.kind {
+            if self.entity().is_none() {
+                // A uid without a kind would narrow the message to that entity
+                self.uid = None;
+            }
+            self.kind = None;
+        }
+        if later.status.is_some() {
+            self.status = later.status.clone();
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    Invalidate(InvalidationMsg),
+    /// A listener (re)connected: anything published meanwhile was missed.
+    Resync,
+}
+
+impl Event {
+    /// Events with the same key within one window are applied once.
+    fn key(&self) -> Option<(Uuid, Option<&str>)> {
+        match self {
+            Event::Invalidate(msg) => Some(msg.scope()),
+            Event::Resync => None,
+        }
+    }
+}
+
+pub fn parse(payload: &str) -> Option<InvalidationMsg> {
+    serde_json::from_str(payload).ok().or_else(|| {
+        Uuid::parse_str(payload.trim())
+            .ok()
+            .map(InvalidationMsg::tenant)
+    })
+}
+
+/// `INVALIDATION_TRANSPORTS`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Transports {
+    pub redis: bool,
+    pub postgres: bool,
+}
+
+impl Transports {
+    /// `redis` / `postgres`: whether each transport is available.
+    pub fn from_env(redis: bool, postgres: bool) -> anyhow::Result<Self> {
+        let Ok(list) = env::var("INVALIDATION_TRANSPORTS") else {
+            return Ok(Self { redis, postgres });
+        };
+        let mut wanted = Self {
+            redis: false,
+            postgres: false,
+        };
+        for t in list.split(',').map(str::trim).filter(|t| !t.is_empty()) {
+            match t {
+                "redis" if redis => wanted.redis = true,
+                "postgres" if postgres => wanted.postgres = true,
+                "redis" => anyhow::bail!("INVALIDATION_TRANSPORTS=redis needs REDIS_URL"),
+                "postgres" => {
+                    anyhow::bail!("INVALIDATION_TRANSPORTS=postgres needs the Postgres store")
+                }
+                other => anyhow::bail!("unknown invalidation transport {other:?} (redis|postgres)"),
+            }
+        }
+        Ok(wanted)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Redis,
+    Postgres,
+}
+
+impl Transport {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Transport::Redis => "redis",
+            Transport::Postgres => "postgres",
+        }
+    }
+}
+
+/// Connection state of the enabled listeners. Down since startup until the first
+/// connect.
+pub struct ListenerHealth {
+    // (transport, down since; `None` while connected)
+    links: Mutex<Vec<(Transport, Option<Instant>)>>,
+    // `INVALIDATION_MAX_DOWN_SECS`: unready once every listener is down this long
+    max_down: Option<Duration>,
+}
+
+impl ListenerHealth {
+    /// `None` when no transport is enabled.
+    pub fn from_env(transports: Transports) -> Option<Self> {
+        let mut links = Vec::new();
+        let now = Instant::now();
+        if transports.redis {
+            links.push((Transport::Redis, Some(now)));
+        }
+        if transports.postgres {
+            links.push((Transport::Postgres, Some(now)));
+        }
+        for (t, _) in &links {
+            metrics::gauge!("pdp_invalidation_listener_up", "transport" => t.as_str()).set(0.0);
+        }
+        let max_down = env::var("INVALIDATION_MAX_DOWN_SECS")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(DEFAULT_MAX_DOWN_SECS);
+        (!links.is_empty()).then(|| Self {
+            links: Mutex::new(links),
+            max_down: (max_down > 0).then(|| Duration::from_secs(max_down)),
+        })
+    }
+
+    fn set(&self, transport: Transport, up: bool) {
+        let mut links = self.links.lock().unwrap_or_else(PoisonError::into_inner);
+        for (t, down_since) in links.iter_mut().filter(|(t, _)| *t == transport) {
+            match (up, down_since.is_some()) {
+                (true, _) => *down_since = None,
+                (false, false) => *down_since = Some(Instant::now()),
+                (false, true) => {}
+            }
+            metrics::gauge!("pdp_invalidation_listener_up", "transport" => t.as_str()).set(if up {
+                1.0
+            } else {
+                0.0
+            });
+        }
+    }
+
+    /// For `/ready`: false once every listener has been down for `max_down`.
+    pub fn readiness(&self) -> (bool, String) {
+        let links = self.links.lock().unwrap_or_else(PoisonError::into_inner);
+        let states: Vec<_> = links
+            .iter()
+            .map(|(t, down_since)| match down_since {
+                None => format!("{} up", t.as_str()),
+                Some(at) => format!("{} down {}s", t.as_str(), at.elapsed().as_secs()),
+            })
+            .collect();
+        let all_down_for = links
+            .iter()
+            .map(|(_, down_since)| down_since.map(|at| at.elapsed()))
+            .min()
+            .flatten();
+        let ready = match (all_down_for, self.max_down) {
+            (Some(down), Some(max)) => down < max,
+            _ => true,
+        };
+        (ready, format!("invalidation: {}", states.join(", ")))
+    }
+}
+
+fn backoff(delay: &mut Duration) -> Duration {
+    let current = *delay;
+    *delay = (*delay * 2).min(RECONNECT_MAX);
+    current
+}
+
+pub fn spawn_redis_listener(
+    topology: Topology,
+    tx: UnboundedSender<Event>,
+    health: Arc<ListenerHealth>,
+) {
+    tokio::spawn(async move {
+        let mut delay = RECONNECT_MIN;
+        loop {
+            // For pub/sub, "non-multiplexed" connection
+            let mut pubsub = match topology.pubsub().await {
+                Ok(p) => p,
+                Err(e) => {
+                    warn!("redis pubsub connect error, retrying in {delay:?}: {e}");
+                    tokio::time::sleep(backoff(&mut delay)).await;
+                    continue;
+                }
+            };
+            if let Err(e) = pubsub.subscribe(REDIS_CHANNEL).await {
+                warn!("redis subscribe error, retrying in {delay:?}: {e}");
+                tokio::time::sleep(backoff(&mut delay)).await;
+                continue;
+            }
+            info!("Subscribed to Redis invalidation channel {REDIS_CHANNEL}");
+            delay = RECONNECT_MIN;
+            health.set(Transport::Redis, true);
+            if tx.send(Event::Resync).is_err() {
+                return;
+            }
+            let mut messages = pubsub.on_message();
+            while let Some(msg) = messages.next().await {
+                if let Some(inv) = msg.get_payload::<String>().ok().and_then(|p| parse(&p)) {
+                    metrics::counter!("pdp_invalidations_total", "transport" => "redis")
+                        .increment(1);
+                    if tx.send(Event::Invalidate(inv)).is_err() {
+                        return;
+                    }
+                }
+            }
+            health.set(Transport::Redis, false);
+            metrics::counter!("pdp_invalidation_reconnects_total", "transport" => "redis")
+                .increment(1);
+            warn!("redis invalidation subscription lost, reconnecting");
+        }
+    });
+}
+
+pub fn spawn_postgres_listener(
+    db: PgPool,
+    tx: UnboundedSender<Event>,
+    health: Arc<ListenerHealth>,
+) {
+    tokio::spawn(async move {
+        let mut delay = RECONNECT_MIN;
+        loop {
+            let connected = async {
+                let mut listener = PgListener::connect_with(&db).await?;
+                listener.listen(POSTGRES_CHANNEL).await?;
+                Ok::<_, sqlx::Error>(listener)
+            };
+            let mut listener = match connected.await {
+                Ok(l) => l,
+                Err(e) => {
+                    warn!("postgres listen error, retrying in {delay:?}: {e}");
+                    tokio::time::sleep(backoff(&mut delay)).await;
+                    continue;
+                }
+            };
+            info!("Listening on Postgres invalidation channel {POSTGRES_CHANNEL}");
+            delay = RECONNECT_MIN;
+            health.set(Transport::Postgres, true);
+            if tx.send(Event::Resync).is_err() {
+                return;
+            }
+            // try_recv, not recv: recv reconnects silently and hides the lost messages.
+            loop {
+                match listener.try_recv().await {
+                    Ok(Some(n)) => match parse(n.payload()) {
+                        Some(inv) => {
+                            metrics::counter!("pdp_invalidations_total", "transport" => "postgres")
+                                .increment(1);
+                            if tx.send(Event::Invalidate(inv)).is_err() {
+                                return;
+                            }
+                        }
+                        None => warn!("ignoring invalidation payload {:?}", n.payload()),
+                    },
+                    Ok(None) => {
+                        warn!("postgres invalidation connection lost, reconnecting");
+                        break;
+                    }
+                    Err(e) => {
+                        warn!("postgres listen error, reconnecting: {e}");
+                        break;
+                    }
+                }
+            }
+            health.set(Transport::Postgres, false);
+            metrics::counter!("pdp_invalidation_reconnects_total", "transport" => "postgres")
+                .increment(1);
+        }
+    });
+}
+
+/// Applies every event from the transports, each distinct scope once per
+/// `COALESCE_WINDOW`; messages for one scope are merged, so none of their kinds is
+/// lost. Coalescing only delays an event, never drops one that arrives after the
+/// previous batch was applied.
+pub async fn dispatch<F, Fut>(mut rx: UnboundedReceiver<Event>, apply: F)
+where
+    F: Fn(Event) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    while let Some(first) = rx.recv().await {
+        let mut batch = vec![first];
+        let window = tokio::time::sleep(COALESCE_WINDOW);
+        tokio::pin!(window);
+        loop {
+            tokio::select! {
+                _ = &mut window => break,
+                event = rx.recv() => match event {
+                    Some(event) => batch.push(event),
+                    None => break,
+                },
+            }
+        }
+        let mut seen = HashMap::new();
+        let mut unique: Vec<Event> = Vec::with_capacity(batch.len());
+        for event in &batch {
+            match seen.get(&event.key()) {
+                Some(&i) => {
+                    if let (Event::Invalidate(first), Event::Invalidate(later)) =
+                        (&mut unique[i], event)
+                    {
+                        first.merge(later);
+                    }
+                }
+                None => {
+                    seen.insert(event.key(), unique.len());
+                    unique.push(event.clone());
+                }
+            }
+        }
+        metrics::counter!("pdp_invalidations_coalesced_total")
+            .increment((batch.len() - unique.len()) as u64);
+        for event in unique {
+            apply(event).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use tokio::sync::mpsc;
+
+    #[test]
+    fn parses_redis_and_postgres_payloads() {
+        let t = Uuid::from_u128(1);
+        assert_eq!(parse(&t.to_string()), Some(InvalidationMsg::tenant(t)));
+        let msg = parse(&format!(r#"{{"tenant_id":"{t}","status":"suspended"}}"#)).unwrap();
+        assert_eq!(msg.status.as_deref(), Some("suspended"));
+        let msg = parse(&format!(
+            r#"{{"tenant_id":"{t}","kind":"entity","uid":"User::\"1\""}}"#
+        ))
+        .unwrap();
+        assert_eq!(msg.kind, Some(ChangeKind::Entity));
+        assert_eq!(msg.uid.as_deref(), Some("User::\"1\""));
+        assert_eq!(msg.entity(), Some("User::\"1\""));
+        let msg = parse(&format!(
+            r#"{{"tenant_id":"{t}","entity_uid":"User::\"1\""}}"#
+        ))
+        .unwrap();
+        assert_eq!(msg.entity(), Some("User::\"1\""));
+        let msg = parse(&format!(
+            r#"{{"tenant_id":"{t}","kind":"tenant","uid":"x"}}"#
+        ))
+        .unwrap();
+        assert_eq!(msg.entity(), None);
+        assert_eq!(parse("nope"), None);
+    }
+
+    #[tokio::test]
+    async fn coalesces_duplicates_within_the_window() {
+        let (a, b) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        let policy_set = |t| InvalidationMsg {
+            kind: Some(ChangeKind::PolicySet),
+            ..InvalidationMsg::tenant(t)
+        };
+        let entity = |t, uid: &str| InvalidationMsg {
+            kind: Some(ChangeKind::Entity),
+            uid: Some(uid.into()),
+            ..InvalidationMsg::tenant(t)
+        };
+        let (tx, rx) = mpsc::unbounded_channel();
+        // Redis publish + Postgres notify for the same promotion, one notify per row.
+        for msg in [
+            InvalidationMsg::tenant(a),
+            policy_set(a),
+            policy_set(a),
+            entity(a, "User::\"1\""),
+            entity(a, "User::\"1\""),
+            entity(a, "User::\"2\""),
+            policy_set(b),
+        ] {
+            tx.send(Event::Invalidate(msg)).unwrap();
+        }
+        // Both listeners reconnecting at once: one resync.
+        tx.send(Event::Resync).unwrap();
+        tx.send(Event::Resync).unwrap();
+        let applied = Arc::new(Mutex::new(Vec::new()));
+        let task = {
+            let applied = applied.clone();
+            tokio::spawn(dispatch(rx, move |msg| {
+                applied.lock().unwrap().push(msg);
+                async {}
+            }))
+        };
+        tokio::time::sleep(COALESCE_WINDOW * 4).await;
+        assert_eq!(
+            *applied.lock().unwrap(),
+            vec![
+                Event::Invalidate(InvalidationMsg::tenant(a)),
+                Event::Invalidate(entity(a, "User::\"1\"")),
+                Event::Invalidate(entity(a, "User::\"2\"")),
+                Event::Invalidate(policy_set(b)),
+                Event::Resync,
+            ]
+        );
+
+        // A later change is applied again.
+        tx.send(Event::Invalidate(policy_set(a))).unwrap();
+        drop(tx);
+        task.await.unwrap();
+        assert_eq!(
+            applied.lock().unwrap().last(),
+            Some(&Event::Invalidate(policy_set(a)))
+        );
+    }
+
+    #[tokio::test]
+    async fn merges_kinds_of_one_scope() {
+        let t = Uuid::from_u128(1);
+        let kind = |kind, uid: Option<&str>| InvalidationMsg {
+            kind: Some(kind),
+            uid: uid.map(Into::into),
+            ..InvalidationMsg::tenant(t)
+        };
+        let (tx, rx) = mpsc::unbounded_channel();
+        // A promotion and an attribute change in the same window
+        tx.send(Event::Invalidate(kind(ChangeKind::PolicySet, None)))
+            .unwrap();
+        tx.send(Event::Invalidate(kind(ChangeKind::Tenant, Some("x"))))
+            .unwrap();
+        tx.send(Event::Invalidate(InvalidationMsg {
+            status: Some("suspended".into()),
+            ..kind(ChangeKind::PolicySet, None)
+        }))
+        .unwrap();
+        drop(tx);
+        let applied = Arc::new(Mutex::new(Vec::new()));
+        let recorded = applied.clone();
+        dispatch(rx, move |event| {
+            recorded.lock().unwrap().push(event);
+            async {}
+        })
+        .await;
+        let applied = applied.lock().unwrap();
+        let [Event::Invalidate(msg)] = applied.as_slice() else {
+            panic!("{applied:?}");
+        };
+        // Not PolicySet, so the entity cache is dropped too
+        assert_eq!(msg.kind, None);
+        assert_eq!(msg.entity(), None);
+        assert_eq!(msg.status.as_deref(), Some("suspended"));
+    }
+
+    #[test]
+    fn unready_only_when_every_listener_is_down_too_long() {
+        let health = ListenerHealth {
+            links: Mutex::new(vec![
+                (Transport::Redis, Some(Instant::now())),
+                (Transport::Postgres, Some(Instant::now())),
+            ]),
+            max_down: Some(Duration::ZERO),
+        };
+        assert!(!health.readiness().0);
+        health.set(Transport::Postgres, true);
+        let (ready, msg) = health.readiness();
+        assert!(ready);
+        assert_eq!(msg, "invalidation: redis down 0s, postgres up");
+        health.set(Transport::Postgres, false);
+        assert!(!health.readiness().0);
+    }
+}
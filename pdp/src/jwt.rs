@@ -0,0 +1,527 @@
+//! In-PDP JWT verification.
+//!
+//! By default the PDP trusts `x-tenant-id` / `x-principal` as forwarded by Envoy's
+//! `jwt_authn`. With `JWT_VERIFY=1` the PDP verifies the token itself (bearer token
+//! or a full compact JWS in `x-jwt-payload`) and derives tenant/principal from claims.
+
+use axum::http::HeaderMap;
+use jsonwebtoken::jwk::{AlgorithmParameters, JwkSet};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::{collections::HashMap, env, sync::Arc, time::Duration};
+use thiserror::Error;
+use tokio::sync::RwLock;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+pub(crate) const DEFAULT_LEEWAY_SECS: u64 = 30;
+const DEFAULT_JWKS_REFRESH_SECS: u64 = 300;
+
+#[derive(Error, Debug)]
+pub enum JwtError {
+    #[error("missing bearer token")]
+    MissingToken,
+    #[error("x-jwt-payload is not a signed token")]
+    UnsignedPayload,
+    #[error("unsupported alg {0:?}")]
+    UnsupportedAlg(Algorithm),
+    #[error("no key for kid {0:?}")]
+    UnknownKey(Option<String>),
+    #[error("invalid token: {0}")]
+    Invalid(#[from] jsonwebtoken::errors::Error),
+    #[error("missing claim {0}")]
+    MissingClaim(String),
+    #[error("invalid tenant claim")]
+    InvalidTenant,
+    #[error("issuer not accepted")]
+    Issuer,
+    #[error("audience not accepted")]
+    Audience,
+    #[error("token expired")]
+    Expired,
+    #[error("token not yet valid")]
+    NotYetValid,
+}
+
+impl JwtError {
+    /// Short, low-cardinality label for metrics.
+    pub fn label(&self) -> &'static str {
+        match self {
+            JwtError::MissingToken => "missing",
+            JwtError::UnsignedPayload => "unsigned",
+            JwtError::UnsupportedAlg(_) => "alg",
+            JwtError::UnknownKey(_) => "kid",
+            JwtError::Invalid(_) => "invalid",
+            JwtError::MissingClaim(_) => "claim",
+            JwtError::InvalidTenant => "tenant",
+            JwtError::Issuer => "iss",
+            JwtError::Audience => "aud",
+            JwtError::Expired => "exp",
+            JwtError::NotYetValid => "nbf",
+        }
+    }
+}
+
+/// Issuer/audience/leeway accepted for a tenant's tokens.
+#[derive(Deserialize, Clone, Default, Debug)]
+pub struct TenantJwtSettings {
+    #[serde(default)]
+    pub issuer: Option<String>,
+    #[serde(default)]
+    pub audiences: Vec<String>,
+    #[serde(default)]
+    pub leeway_secs: Option<u64>,
+}
+
+/// Where JWKS keys come from; `Url` sources are refreshed periodically.
+#[derive(Clone, Debug)]
+pub enum JwksSource {
+    File(String),
+    Url(String),
+}
+
+impl JwksSource {
+    pub async fn load(&self) -> anyhow::Result<JwkSet> {
+        let set = match self {
+            JwksSource::File(path) => serde_json::from_slice(&tokio::fs::read(path).await?)?,
+            JwksSource::Url(url) => {
+                reqwest::get(url)
+                    .await?
+                    .error_for_status()?
+                    .json::<JwkSet>()
+                    .await?
+            }
+        };
+        Ok(set)
+    }
+}
+
+/// Verification keys: an optional HS256 shared secret plus a JWKS (RS256/ES256/oct).
+pub struct KeySet {
+    hs256_secret: Option<Vec<u8>>,
+    jwks: JwkSet,
+}
+
+impl KeySet {
+    pub fn new(hs256_secret: Option<Vec<u8>>, jwks: Option<JwkSet>) -> Self {
+        Self {
+            hs256_secret,
+            jwks: jwks.unwrap_or(JwkSet { keys: Vec::new() }),
+        }
+    }
+
+    fn key_for(&self, alg: Algorithm, kid: Option<&str>) -> Result<DecodingKey, JwtError> {
+        let family_matches = |p: &AlgorithmParameters| {
+            matches!(
+                (alg, p),
+                (Algorithm::HS256, AlgorithmParameters::OctetKey(_))
+                    | (Algorithm::RS256, AlgorithmParameters::RSA(_))
+                    | (Algorithm::ES256, AlgorithmParameters::EllipticCurve(_))
+            )
+        };
+        let jwk = match kid {
+            Some(k) => self.jwks.find(k).filter(|j| family_matches(&j.algorithm)),
+            None => {
+                // Without a kid we only accept an unambiguous key.
+                let mut it = self
+                    .jwks
+                    .keys
+                    .iter()
+                    .filter(|j| family_matches(&j.algorithm));
+                match (it.next(), it.next()) {
+                    (Some(j), None) => Some(j),
+                    _ => None,
+                }
+            }
+        };
+        if let Some(jwk) = jwk {
+            return Ok(DecodingKey::from_jwk(jwk)?);
+        }
+        match (alg, &self.hs256_secret) {
+            (Algorithm::HS256, Some(secret)) => Ok(DecodingKey::from_secret(secret)),
+            _ => Err(JwtError::UnknownKey(kid.map(str::to_string))),
+        }
+    }
+
+    /// Checks the signature only; time and issuer/audience checks are left to the caller.
+    pub fn decode(&self, token: &str) -> Result<Map<String, Value>, JwtError> {
+        let header = jsonwebtoken::decode_header(token)?;
+        if !matches!(
+            header.alg,
+            Algorithm::HS256 | Algorithm::RS256 | Algorithm::ES256
+        ) {
+            return Err(JwtError::UnsupportedAlg(header.alg));
+        }
+        let key = self.key_for(header.alg, header.kid.as_deref())?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.required_spec_claims.clear();
+        validation.validate_exp = false;
+        validation.validate_nbf = false;
+        validation.validate_aud = false;
+        let data = jsonwebtoken::decode::<Map<String, Value>>(token, &key, &validation)?;
+        Ok(data.claims)
+    }
+}
+
+/// A comma separated audience list, as in `JWT_AUDIENCE`.
+pub(crate) fn split_audiences(raw: &str) -> Vec<String> {
+    raw.split(',')
+        .map(|a| a.trim().to_string())
+        .filter(|a| !a.is_empty())
+        .collect()
+}
+
+/// Checks `iss`, `aud`, `exp` and `nbf` against `settings`.
+pub fn check_registered_claims(
+    claims: &Map<String, Value>,
+    settings: &TenantJwtSettings,
+    default_leeway: u64,
+) -> Result<(), JwtError> {
+    if let Some(expected) = &settings.issuer {
+        if claims.get("iss").and_then(Value::as_str) != Some(expected.as_str()) {
+            return Err(JwtError::Issuer);
+        }
+    }
+    if !settings.audiences.is_empty() {
+        let ok = match claims.get("aud") {
+            Some(Value::String(a)) => settings.audiences.contains(a),
+            Some(Value::Array(items)) => items
+                .iter()
+                .filter_map(Value::as_str)
+                .any(|a| settings.audiences.iter().any(|x| x == a)),
+            _ => false,
+        };
+        if !ok {
+            return Err(JwtError::Audience);
+        }
+    }
+
+    let leeway = settings.leeway_secs.unwrap_or(default_leeway) as i64;
+    let now = time::OffsetDateTime::now_utc().unix_timestamp();
+    let exp = claims
+        .get("exp")
+        .and_then(Value::as_i64)
+        .ok_or_else(|| JwtError::MissingClaim("exp".into()))?;
+    if exp + leeway < now {
+        return Err(JwtError::Expired);
+    }
+    if let Some(nbf) = claims.get("nbf").and_then(Value::as_i64) {
+        if nbf - leeway > now {
+            return Err(JwtError::NotYetValid);
+        }
+    }
+    Ok(())
+}
+
+/// Identity derived from a verified token.
+#[derive(Debug, Clone)]
+pub struct VerifiedIdentity {
+    pub tenant_id: Uuid,
+    pub principal: String,
+}
+
+pub struct JwtVerifier {
+    keys: RwLock<KeySet>,
+    hs256_secret: Option<Vec<u8>>,
+    jwks_source: Option<JwksSource>,
+    default_settings: TenantJwtSettings,
+    tenant_settings: HashMap<Uuid, TenantJwtSettings>,
+    tenant_claim: String,
+    principal_claim: String,
+    leeway_secs: u64,
+}
+
+impl JwtVerifier {
+    /// Builds the verifier from env. Returns `None` unless `JWT_VERIFY` is enabled.
+    ///
+    /// * `JWT_HS256_SECRET` — shared secret for HS256 tokens
+    /// * `JWT_JWKS_PATH` / `JWT_JWKS_URL` — JWKS for RS256/ES256 (and `oct` keys)
+    /// * `JWT_ISSUER`, `JWT_AUDIENCE` (comma separated), `JWT_LEEWAY_SECS` — defaults
+    /// * `JWT_TENANTS_FILE` — JSON map of tenant id → `TenantJwtSettings`
+    /// * `JWT_TENANT_CLAIM` (`tid`), `JWT_PRINCIPAL_CLAIM` (`sub`)
+    pub async fn from_env() -> anyhow::Result<Option<Arc<Self>>> {
+        let enabled = env::var("JWT_VERIFY")
+            .map(|v| v == "1" || v.to_lowercase() == "true")
+            .unwrap_or(false);
+        if !enabled {
+            return Ok(None);
+        }
+
+        let hs256_secret = env::var("JWT_HS256_SECRET").ok().map(String::into_bytes);
+        let jwks_source = match (env::var("JWT_JWKS_PATH"), env::var("JWT_JWKS_URL")) {
+            (Ok(path), _) => Some(JwksSource::File(path)),
+            (_, Ok(url)) => Some(JwksSource::Url(url)),
+            _ => None,
+        };
+        if hs256_secret.is_none() && jwks_source.is_none() {
+            anyhow::bail!("JWT_VERIFY=1 requires JWT_HS256_SECRET, JWT_JWKS_PATH or JWT_JWKS_URL");
+        }
+        let jwks = match &jwks_source {
+            Some(src) => Some(src.load().await?),
+            None => None,
+        };
+
+        let default_settings = TenantJwtSettings {
+            issuer: env::var("JWT_ISSUER").ok(),
+            audiences: env::var("JWT_AUDIENCE")
+                .map(|s| split_audiences(&s))
+                .unwrap_or_default(),
+            leeway_secs: None,
+        };
+        let tenant_settings = match env::var("JWT_TENANTS_FILE") {
+            Ok(path) => serde_json::from_slice(&tokio::fs::read(&path).await?)?,
+            Err(_) => HashMap::new(),
+        };
+
+        let verifier = Arc::new(Self {
+            keys: RwLock::new(KeySet::new(hs256_secret.clone(), jwks)),
+            hs256_secret,
+            jwks_source,
+            default_settings,
+            tenant_settings,
+            tenant_claim: env::var("JWT_TENANT_CLAIM").unwrap_or_else(|_| "tid".into()),
+            principal_claim: env::var("JWT_PRINCIPAL_CLAIM").unwrap_or_else(|_| "sub".into()),
+            leeway_secs: env::var("JWT_LEEWAY_SECS")
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(DEFAULT_LEEWAY_SECS),
+        });
+
+        if let Some(JwksSource::Url(_)) = &verifier.jwks_source {
+            let refresh = env::var("JWT_JWKS_REFRESH_SECS")
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(DEFAULT_JWKS_REFRESH_SECS);
+            spawn_jwks_refresh(verifier.clone(), Duration::from_secs(refresh));
+        }
+        info!(
+            "JWT verification enabled (tenant claim {}, principal claim {})",
+            verifier.tenant_claim, verifier.principal_claim
+        );
+        Ok(Some(verifier))
+    }
+
+    /// Verifies the request's token and returns the identity it carries.
+    pub async fn verify(&self, headers: &HeaderMap) -> Result<VerifiedIdentity, JwtError> {
+        let token = token_from_headers(headers)?;
+        let claims = self.keys.read().await.decode(token)?;
+
+        let tenant_id = match claims.get(&self.tenant_claim) {
+            Some(Value::String(s)) => Uuid::parse_str(s).map_err(|_| JwtError::InvalidTenant)?,
+            Some(_) => return Err(JwtError::InvalidTenant),
+            None => return Err(JwtError::MissingClaim(self.tenant_claim.clone())),
+        };
+        let settings = self
+            .tenant_settings
+            .get(&tenant_id)
+            .unwrap_or(&self.default_settings);
+        check_registered_claims(&claims, settings, self.leeway_secs)?;
+
+        let principal = claims
+            .get(&self.principal_claim)
+            .and_then(Value::as_str)
+            .ok_or_else(|| JwtError::MissingClaim(self.principal_claim.clone()))?
+            .to_string();
+
+        Ok(VerifiedIdentity {
+            tenant_id,
+            principal,
+        })
+    }
+}
+
+/// Bearer token from `Authorization`, falling back to `x-jwt-payload` when it
+/// carries a full compact JWS (Envoy's payload-only header cannot be verified).
+fn token_from_headers(headers: &HeaderMap) -> Result<&str, JwtError> {
+    if let Some(token) = headers
+        .get("authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| {
+            v.strip_prefix("Bearer ")
+                .or_else(|| v.strip_prefix("bearer "))
+        })
+    {
+        return Ok(token.trim());
+    }
+    match headers.get("x-jwt-payload").and_then(|v| v.to_str().ok()) {
+        Some(v) if v.split('.').count() == 3 => Ok(v.trim()),
+        Some(_) => Err(JwtError::UnsignedPayload),
+        None => Err(JwtError::MissingToken),
+    }
+}
+
+fn spawn_jwks_refresh(verifier: Arc<JwtVerifier>, every: Duration) {
+    tokio::spawn(async move {
+        let Some(source) = verifier.jwks_source.clone() else {
+            return;
+        };
+        let mut tick = tokio::time::interval(every);
+        tick.tick().await;
+        loop {
+            tick.tick().await;
+            match source.load().await {
+                Ok(jwks) => {
+                    *verifier.keys.write().await =
+                        KeySet::new(verifier.hs256_secret.clone(), Some(jwks));
+                }
+                Err(e) => warn!("jwks refresh failed, keeping previous keys: {e}"),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{EncodingKey, Header};
+    use serde_json::json;
+
+    const SECRET: &[u8] = b"test-secret";
+    const TENANT: &str = "11111111-1111-1111-1111-111111111111";
+
+    fn verifier(jwks: Option<JwkSet>) -> JwtVerifier {
+        JwtVerifier {
+            keys: RwLock::new(KeySet::new(Some(SECRET.to_vec()), jwks)),
+            hs256_secret: Some(SECRET.to_vec()),
+            jwks_source: None,
+            default_settings: TenantJwtSettings {
+                issuer: Some("https://idp".into()),
+                audiences: vec!["pdp".into()],
+                leeway_secs: None,
+            },
+            tenant_settings: HashMap::new(),
+            tenant_claim: "tid".into(),
+            principal_claim: "sub".into(),
+            leeway_secs: 0,
+        }
+    }
+
+    fn now() -> i64 {
+        time::OffsetDateTime::now_utc().unix_timestamp()
+    }
+
+    fn claims() -> Value {
+        json!({
+            "iss": "https://idp",
+            "aud": "pdp",
+            "tid": TENANT,
+            "sub": "User::\"alice\"",
+            "exp": now() + 300,
+        })
+    }
+
+    fn sign(header: &Header, claims: &Value, secret: &[u8]) -> String {
+        jsonwebtoken::encode(header, claims, &EncodingKey::from_secret(secret)).unwrap()
+    }
+
+    fn bearer(token: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("authorization", format!("Bearer {token}").parse().unwrap());
+        headers
+    }
+
+    async fn verify(claims: &Value) -> Result<VerifiedIdentity, JwtError> {
+        let token = sign(&Header::default(), claims, SECRET);
+        verifier(None).verify(&bearer(&token)).await
+    }
+
+    #[tokio::test]
+    async fn accepts_a_valid_token() {
+        let id = verify(&claims()).await.unwrap();
+        assert_eq!(id.tenant_id, Uuid::parse_str(TENANT).unwrap());
+        assert_eq!(id.principal, "User::\"alice\"");
+    }
+
+    #[tokio::test]
+    async fn rejects_a_bad_signature() {
+        let token = sign(&Header::default(), &claims(), b"other-secret");
+        let err = verifier(None).verify(&bearer(&token)).await.unwrap_err();
+        assert!(matches!(err, JwtError::Invalid(_)), "{err}");
+    }
+
+    #[tokio::test]
+    async fn rejects_expired_and_not_yet_valid_tokens() {
+        let mut expired = claims();
+        expired["exp"] = json!(now() - 60);
+        assert!(matches!(verify(&expired).await, Err(JwtError::Expired)));
+
+        let mut early = claims();
+        early["nbf"] = json!(now() + 60);
+        assert!(matches!(verify(&early).await, Err(JwtError::NotYetValid)));
+
+        let mut no_exp = claims();
+        no_exp.as_object_mut().unwrap().remove("exp");
+        assert!(matches!(
+            verify(&no_exp).await,
+            Err(JwtError::MissingClaim(c)) if c == "exp"
+        ));
+    }
+
+    #[tokio::test]
+    async fn rejects_wrong_issuer_and_audience() {
+        let mut iss = claims();
+        iss["iss"] = json!("https://evil");
+        assert!(matches!(verify(&iss).await, Err(JwtError::Issuer)));
+
+        let mut aud = claims();
+        aud["aud"] = json!(["other", "api"]);
+        assert!(matches!(verify(&aud).await, Err(JwtError::Audience)));
+
+        // Any listed audience is enough
+        aud["aud"] = json!(["other", "pdp"]);
+        assert!(verify(&aud).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn rejects_an_unknown_kid() {
+        let jwks: JwkSet = serde_json::from_value(json!({
+            "keys": [{ "kty": "oct", "kid": "k1", "k": "c2VjcmV0" }]
+        }))
+        .unwrap();
+        let header = Header {
+            kid: Some("k2".into()),
+            ..Header::default()
+        };
+        let token = sign(&header, &claims(), SECRET);
+
+        let jwks_only = verifier(None);
+        *jwks_only.keys.write().await = KeySet::new(None, Some(jwks.clone()));
+        let err = jwks_only.verify(&bearer(&token)).await.unwrap_err();
+        assert!(
+            matches!(err, JwtError::UnknownKey(Some(ref k)) if k == "k2"),
+            "{err}"
+        );
+
+        // An HS256 kid missing from the JWKS falls back to the shared secret
+        assert!(verifier(Some(jwks)).verify(&bearer(&token)).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn identity_comes_from_claims_not_headers() {
+        let token = sign(&Header::default(), &claims(), SECRET);
+        let mut headers = bearer(&token);
+        headers.insert(
+            "x-tenant-id",
+            "22222222-2222-2222-2222-222222222222".parse().unwrap(),
+        );
+        headers.insert("x-principal", "User::\"mallory\"".parse().unwrap());
+        let id = verifier(None).verify(&headers).await.unwrap();
+        assert_eq!(id.tenant_id, Uuid::parse_str(TENANT).unwrap());
+        assert_eq!(id.principal, "User::\"alice\"");
+    }
+
+    #[tokio::test]
+    async fn rejects_missing_or_invalid_tenant_claims() {
+        let mut bad = claims();
+        bad["tid"] = json!("not-a-uuid");
+        assert!(matches!(verify(&bad).await, Err(JwtError::InvalidTenant)));
+
+        bad.as_object_mut().unwrap().remove("tid");
+        assert!(matches!(
+            verify(&bad).await,
+            Err(JwtError::MissingClaim(c)) if c == "tid"
+        ));
+    }
+}
@@ -1,37 +1,138 @@
-use axum::http::{HeaderMap, Method, StatusCode};
+use axum::http::{header, HeaderMap, Method, StatusCode};
+use axum::response::{IntoResponse, Response};
 use axum::{
-    extract::{Path, State},
+    extract::{Path, Query, State},
     routing::{get, post},
     Json, Router,
 };
 use cedar_policy::Decision;
 use cedar_policy::{Authorizer, Entities, EntityUid, Policy, PolicySet, Request};
-use futures::StreamExt;
+use futures::{FutureExt, StreamExt};
 use metrics_exporter_prometheus::PrometheusBuilder;
-use redis::{aio::MultiplexedConnection, AsyncCommands};
+use policy_cache::{PolicyCache, PolicySnapshot};
+use policy_index::PolicyIndex;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
-use sha2::{Digest, Sha256};
-use sqlx::{postgres::PgPoolOptions, PgPool, Row};
-use std::{collections::HashMap, env, net::SocketAddr, str::FromStr, sync::Arc, time::Duration};
+use single_flight::SingleFlight;
+use sqlx::postgres::PgPoolOptions;
+use std::{
+    collections::HashMap,
+    env,
+    net::SocketAddr,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 use thiserror::Error;
-use tokio::{net::TcpListener, sync::RwLock, time::Instant};
+use tokio::{
+    net::TcpListener,
+    signal,
+    sync::{mpsc, RwLock},
+    time::Instant,
+};
 use tracing::{error, info, warn};
 use tracing_subscriber::EnvFilter;
 use uuid::Uuid;
 
-const REDIS_DECISIONS_TTL_SECS: usize = 30;
-const REDIS_INVALIDATION_CHANNEL: &str = "pdp:invalidate";
+mod admin_auth;
+mod audit;
+mod break_glass;
+mod bundle;
+mod db;
+mod decision_cache;
+mod invalidation;
+mod jwt;
+mod policy_cache;
+mod policy_index;
+mod redis_conn;
+mod rls;
+mod single_flight;
+mod store;
+mod tenant;
+mod tls;
+
+use admin_auth::{AdminAuth, AdminPrincipal, Permission};
+use audit::{AuditRecord, AuditWriter};
+use break_glass::{BreakGlass, BREAK_GLASS_HEADER};
+use bundle::BundleBuilder;
+use invalidation::{Event, InvalidationMsg, ListenerHealth, Transports};
+use jwt::JwtVerifier;
+use redis_conn::{SharedRedis, Topology};
+use store::{
+    bundle::BundleStore,
+    cached::CachedEntityStore,
+    file::FileStore,
+    memory::{MemoryStore, MemoryTenant},
+    postgres::PostgresStore,
+    sidecar::SidecarStore,
+    Backend, ChangeKind, EntityRecord, EntityStore, PolicyStore, StoreError, TenantStore,
+};
+use tenant::{DefaultDecision, FailMode, TenantMeta, TenantStatus};
+use tls::ReloadingTls;
+
+/// A tenant's active policy set as shared between coalesced loads.
+type PolicyLoad = Result<Arc<PolicySnapshot>, Arc<StoreError>>;
+/// A decision as shared between coalesced evaluations.
+type DecisionLoad = Result<&'static str, Arc<EvalError>>;
+
+// Startup warm-up: tenants loaded at once, and retry delay while the store is down
+const WARM_UP_CONCURRENCY: usize = 8;
+const WARM_UP_RETRY: Duration = Duration::from_secs(2);
+// Unknown tenant ids remembered, so /check for random ids doesn't query the store
+// each time (it runs before the rate limit). Short, since new tenants are not announced.
+const UNKNOWN_TENANTS_MAX: u64 = 10_000;
+const UNKNOWN_TENANT_TTL: Duration = Duration::from_secs(5);
 
 #[derive(Clone)]
 struct AppState {
+    // Decision for tenants without an active policy set (`DEFAULT_ALLOW`);
+    // `tenants.settings.default_decision` overrides it per tenant.
     default_decision_allow: bool,
-    db: PgPool,
-    redis_client: redis::Client,
-    // In-memory policy cache per tenant (version + PolicySet)
-    policies_cache: Arc<RwLock<HashMap<Uuid, (i32, PolicySet)>>>,
+    // Batched audit writes; logged instead without a database (file store without DATABASE_URL)
+    audit: Arc<AuditWriter>,
+    // Where tenants, policy sets and entities are read from
+    tenant_store: Arc<dyn TenantStore>,
+    policy_store: Arc<dyn PolicyStore>,
+    entity_store: Arc<dyn EntityStore>,
+    // Attribute cache behind `entity_store` (Postgres store, `ENTITY_CACHE_MAX_MB`)
+    entity_cache: Option<Arc<CachedEntityStore>>,
+    // Signed bundles for edge PDPs (BUNDLE_SIGNING_KEY_PATH, Postgres store only)
+    bundles: Option<Arc<BundleBuilder>>,
+    // Change feed for sidecars (Postgres store only)
+    change_feed: Option<Arc<PostgresStore>>,
+    // Sidecar snapshot freshness, checked on every /check (POLICY_STORE=sidecar)
+    sidecar: Option<Arc<SidecarStore>>,
+    // Invalidation listener connections, reported by /ready
+    invalidation_health: Option<Arc<ListenerHealth>>,
+    // Decision cache, rate limit and invalidation; `None` when REDIS_URL is empty
+    redis: Option<Arc<SharedRedis>>,
+    // In-process decision cache in front of Redis (`DECISION_L1_MAX_ENTRIES=0` disables)
+    decision_l1: Option<Arc<decision_cache::L1>>,
+    // Bumped by every invalidation; decisions evaluated across one are not cached
+    decision_generations: Arc<decision_cache::Generations>,
+    // Active policy set per tenant as immutable snapshots; reads take no lock
+    policies_cache: Arc<PolicyCache>,
+    // Tenant status + settings, invalidated on the same channel as policies
+    tenants_cache: Arc<RwLock<HashMap<Uuid, TenantMeta>>>,
+    unknown_tenants: Arc<moka::sync::Cache<Uuid, ()>>,
+    // Coalesce concurrent policy loads per tenant and evaluations per decision key
+    policy_flights: Arc<SingleFlight<Uuid, PolicyLoad>>,
+    // Keyed by invalidation generation too, so requests after an invalidation don't
+    // join an evaluation that started before it
+    decision_flights: Arc<SingleFlight<(u64, String), DecisionLoad>>,
+    // Startup load of every active tenant's policies, reported by /ready
+    warm_up: Arc<WarmUp>,
     rate_limit_rps_default: u32,
     claims_secret: String,
+    // Optional in-PDP JWT verification (JWT_VERIFY=1)
+    jwt: Option<Arc<JwtVerifier>>,
+    // Optional break-glass tokens (BREAK_GLASS_SECRET)
+    break_glass: Option<Arc<BreakGlass>>,
+    // API keys / OIDC for /admin routes
+    admin_auth: Arc<AdminAuth>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -63,6 +164,9 @@ struct EntityRequest {
     // Usamos `serde(default)` para que los atributos sean opcionales
     #[serde(default = "default_json_object")]
     attributes: Value,
+    // Parent UIDs, e.g. `Group::"admins"`
+    #[serde(default)]
+    parents: Vec<String>,
 }
 
 #[derive(Deserialize, Clone)]
@@ -72,6 +176,19 @@ enum EntityInput {
     Full(EntityRequest),
 }
 
+impl EntityInput {
+    fn into_record(self) -> EntityRecord {
+        match self {
+            EntityInput::Uid(s) => EntityRecord::bare(&s), // Si es string, no hay atributos inline.
+            EntityInput::Full(e) => EntityRecord {
+                uid: format!(r#"{}::"{}""#, e.entity_type, e.id),
+                attrs: e.attributes,
+                parents: e.parents,
+            },
+        }
+    }
+}
+
 #[derive(Deserialize)]
 struct AdminTestRequest {
     policies_override: Option<Vec<String>>,
@@ -92,12 +209,29 @@ enum PDPError {
     Db(#[from] sqlx::Error),
     #[error("redis error: {0}")]
     Redis(#[from] redis::RedisError),
-    #[error("cedar error: {0}")]
-    Cedar(String),
     #[error("other: {0}")]
     Other(String),
 }
 
+/// Why a request could not be evaluated; the message is the deny reason.
+#[derive(Error, Debug)]
+enum EvalError {
+    #[error("invalid principal UID")]
+    Principal,
+    #[error("invalid resource UID")]
+    Resource,
+    #[error("invalid action")]
+    Action,
+    #[error("invalid context")]
+    Context,
+    #[error("invalid entities")]
+    Entities,
+    #[error("invalid request")]
+    Request,
+    #[error("entity load error: {0}")]
+    Store(#[from] StoreError),
+}
+
 fn verify_claims_sig(
     secret: &str,
     tenant: &str,
@@ -137,35 +271,214 @@ async fn main() -> anyhow::Result<()> {
         .map(|v| v == "1" || v.to_lowercase() == "true")
         .unwrap_or(false);
 
+    // POLICY_STORE=file|bundle|sidecar: tenant data in memory; Postgres and Redis optional
+    let backend = Backend::from_env()?;
+    let default_url = |url: &str| (backend == Backend::Postgres).then(|| url.to_string());
+
     // DB
     let db_url = env::var("DATABASE_URL")
-        .unwrap_or_else(|_| "postgres://postgres:postgres@db:5432/abac".into());
-    let db = PgPoolOptions::new()
-        .max_connections(10)
-        .connect(&db_url)
-        .await?;
+        .ok()
+        .or_else(|| default_url("postgres://postgres:postgres@db:5432/abac"));
+    let db = match db_url {
+        Some(url) => Some(
+            PgPoolOptions::new()
+                .max_connections(10)
+                .connect(&url)
+                .await?,
+        ),
+        None => None,
+    };
+
+    // `pdp rls-check`: report tenant isolation for DATABASE_URL's role and exit
+    if env::args().nth(1).as_deref() == Some("rls-check") {
+        let db = db
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("rls-check needs DATABASE_URL"))?;
+        let report = rls::check(db).await?;
+        println!("{report}");
+        std::process::exit(if report.enforced() { 0 } else { 1 });
+    }
+    // `pdp bundle <tenant-id> <file>`: write a signed bundle of the tenant and exit
+    if env::args().nth(1).as_deref() == Some("bundle") {
+        let (Some(tenant), Some(out)) = (env::args().nth(2), env::args().nth(3)) else {
+            anyhow::bail!("usage: pdp bundle <tenant-id> <file>");
+        };
+        let tenant = Uuid::parse_str(&tenant)?;
+        let db = db
+            .clone()
+            .ok_or_else(|| anyhow::anyhow!("bundle needs DATABASE_URL"))?;
+        let builder = BundleBuilder::from_env(Arc::new(PostgresStore::new(db)))?
+            .ok_or_else(|| anyhow::anyhow!("bundle needs BUNDLE_SIGNING_KEY_PATH"))?;
+        let bundle = builder
+            .build(tenant)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("unknown tenant {tenant}"))?;
+        std::fs::write(&out, serde_json::to_vec(&bundle)?)?;
+        println!("{out}: {}", bundle.manifest);
+        return Ok(());
+    }
+    if let Some(db) = &db {
+        rls::check_on_startup(db, rls::RlsCheckMode::from_env()).await?;
+    }
 
     // Redis
-    let redis_url = env::var("REDIS_URL").unwrap_or_else(|_| "redis://redis:6379".into());
-    let redis_client = redis::Client::open(redis_url.clone())?;
+    let redis_url = env::var("REDIS_URL")
+        .ok()
+        .or_else(|| default_url("redis://redis:6379"))
+        .filter(|url| !url.is_empty());
+    // REDIS_MODE=standalone|cluster|sentinel
+    let topology = redis_url
+        .map(|urls| Topology::from_env(&urls))
+        .transpose()?;
+    if topology.is_none() {
+        info!("Redis disabled: no shared decision cache, rate limit or pub/sub invalidation");
+    }
+    // One shared connection for caching and rate limiting, opened in the background
+    let redis = topology.clone().map(|topology| {
+        let redis = Arc::new(SharedRedis::from_env(topology));
+        let connecting = redis.clone();
+        tokio::spawn(async move { connecting.connect().await });
+        redis
+    });
 
-    // In-memory policies cache + invalidation (pub/sub)
-    let policies_cache: Arc<RwLock<HashMap<Uuid, (i32, PolicySet)>>> =
+    // In-memory policies cache + invalidation (Redis pub/sub and/or Postgres LISTEN)
+    let policies_cache = Arc::new(PolicyCache::new());
+    let tenants_cache: Arc<RwLock<HashMap<Uuid, TenantMeta>>> =
         Arc::new(RwLock::new(HashMap::new()));
-    spawn_redis_invalidation_listener(redis_client.clone(), policies_cache.clone()).await?;
+    let (invalidations, invalidations_rx) = mpsc::unbounded_channel();
+
+    // Tenants reloaded by the file / bundle / sidecar stores drop their cached policy set and meta
+    let (reloaded, mut reloaded_rx) = mpsc::unbounded_channel();
+    let reload_tx = invalidations.clone();
+    tokio::spawn(async move {
+        while let Some(tid) = reloaded_rx.recv().await {
+            if reload_tx
+                .send(Event::Invalidate(InvalidationMsg::tenant(tid)))
+                .is_err()
+            {
+                return;
+            }
+        }
+    });
+
+    let mut bundles = None;
+    let mut change_feed = None;
+    let mut sidecar = None;
+    let mut entity_cache = None;
+    let (tenant_store, policy_store, entity_store): (
+        Arc<dyn TenantStore>,
+        Arc<dyn PolicyStore>,
+        Arc<dyn EntityStore>,
+    ) = match backend {
+        Backend::File => {
+            let fs = FileStore::from_env()?;
+            fs.spawn_watch(reloaded);
+            (fs.clone(), fs.clone(), fs)
+        }
+        Backend::Bundle => {
+            let bs = BundleStore::from_env().await?;
+            bs.spawn_watch(reloaded);
+            (bs.clone(), bs.clone(), bs)
+        }
+        Backend::Sidecar => {
+            let sc = SidecarStore::from_env(db.clone()).await?;
+            sc.spawn_watch(reloaded);
+            sidecar = Some(sc.clone());
+            (sc.clone(), sc.clone(), sc)
+        }
+        Backend::Postgres => {
+            let db = db
+                .clone()
+                .ok_or_else(|| anyhow::anyhow!("no DATABASE_URL"))?;
+            let pg_store = Arc::new(PostgresStore::new(db));
+            // Serves /admin/bundles/{tenant} when BUNDLE_SIGNING_KEY_PATH is set
+            bundles = BundleBuilder::from_env(pg_store.clone())?.map(Arc::new);
+            if let Some(b) = &bundles {
+                info!("bundle signing enabled, public key {}", b.public_key());
+            }
+            change_feed = Some(pg_store.clone());
+            pg_store.spawn_change_feed_pruning();
+            entity_cache = CachedEntityStore::from_env(pg_store.clone()).map(Arc::new);
+            let entities: Arc<dyn EntityStore> = match &entity_cache {
+                Some(cache) => cache.clone(),
+                None => pg_store.clone(),
+            };
+            (pg_store.clone(), pg_store, entities)
+        }
+    };
+
+    // Invalidation transports; every (re)connect resyncs the caches against the store
+    let transports = Transports::from_env(topology.is_some(), backend == Backend::Postgres)?;
+    let invalidation_health = ListenerHealth::from_env(transports).map(Arc::new);
+    if let Some(health) = &invalidation_health {
+        if let (true, Some(topology)) = (transports.redis, &topology) {
+            invalidation::spawn_redis_listener(
+                topology.clone(),
+                invalidations.clone(),
+                health.clone(),
+            );
+        }
+        if let (true, Some(db)) = (transports.postgres, &db) {
+            invalidation::spawn_postgres_listener(
+                db.clone(),
+                invalidations.clone(),
+                health.clone(),
+            );
+        }
+    }
+
+    let jwt = JwtVerifier::from_env().await?;
+    let break_glass = BreakGlass::from_env()?.map(Arc::new);
+    let admin_auth = Arc::new(AdminAuth::from_env().await?);
+
+    // Decisions are audited off the request path (AUDIT_* settings)
+    let audit = Arc::new(AuditWriter::from_env(db.clone())?);
 
     let state = AppState {
         default_decision_allow,
-        db,
-        redis_client,
+        audit: audit.clone(),
+        tenant_store,
+        policy_store,
+        entity_store,
+        entity_cache,
+        bundles,
+        change_feed,
+        sidecar,
+        invalidation_health,
+        redis,
+        decision_l1: decision_cache::L1::from_env().map(Arc::new),
+        decision_generations: Arc::default(),
         policies_cache,
+        tenants_cache,
+        unknown_tenants: Arc::new(unknown_tenants_cache()),
+        policy_flights: Arc::new(SingleFlight::new("policies")),
+        decision_flights: Arc::new(SingleFlight::new("decision")),
+        warm_up: Arc::default(),
         rate_limit_rps_default,
         claims_secret,
+        jwt,
+        break_glass,
+        admin_auth,
     };
+    tokio::spawn(warm_up(state.clone()));
+    {
+        let state = state.clone();
+        tokio::spawn(invalidation::dispatch(invalidations_rx, move |event| {
+            let state = state.clone();
+            async move { apply_invalidation(&state, event).await }
+        }));
+    }
 
     // HTTP server
-    let app = Router::new()
-        .route("/ready", get(|| async { "ok" }))
+    let tls = ReloadingTls::from_env()?;
+    let shutdown = shutdown_signal().boxed().shared();
+    let admin = Router::new()
+        .route("/admin/validate", post(admin_validate))
+        .route("/admin/test", post(admin_test))
+        .route("/admin/bundles/:tenant", get(admin_bundle))
+        .route("/admin/changes/:tenant", get(admin_changes));
+    let mut app = Router::new()
+        .route("/ready", get(ready))
         .route(
             "/metrics",
             get(move || {
@@ -173,21 +486,65 @@ async fn main() -> anyhow::Result<()> {
                 async move { h.render() }
             }),
         )
-        .route("/admin/validate", post(admin_validate))
-        .route("/admin/test", post(admin_test))
         // admitir /check, /check/ y /check/* (Envoy hace /check + path original)
         .route("/check", post(check_base).get(check_base))
         .route("/check/", post(check_base).get(check_base))
-        .route("/check/*rest", post(check_with_rest).get(check_with_rest))
-        .with_state(state);
+        .route("/check/*rest", post(check_with_rest).get(check_with_rest));
+
+    // ADMIN_LISTEN_ADDR moves /admin/* off the data-plane listener
+    match env::var("ADMIN_LISTEN_ADDR") {
+        Ok(admin_addr) => {
+            let admin_addr: SocketAddr = admin_addr.parse()?;
+            let admin_app = admin.with_state(state.clone());
+            info!("PDP admin listening on {}", admin_addr);
+            let admin_listener = TcpListener::bind(admin_addr).await?;
+            let admin_tls = tls.clone();
+            let admin_shutdown = shutdown.clone();
+            tokio::spawn(async move {
+                if let Err(e) =
+                    tls::serve(admin_listener, admin_app, admin_tls, admin_shutdown).await
+                {
+                    error!("admin listener failed: {e}");
+                }
+            });
+        }
+        Err(_) => app = app.merge(admin),
+    }
+    let app = app.with_state(state);
 
-    let addr: SocketAddr = "0.0.0.0:8081".parse().unwrap();
+    let addr: SocketAddr = env::var("PDP_LISTEN_ADDR")
+        .unwrap_or_else(|_| "0.0.0.0:8081".into())
+        .parse()?;
     info!("PDP listening on {}", addr);
     let listener = TcpListener::bind(addr).await?;
-    axum::serve(listener, app).await?;
+    tls::serve(listener, app, tls, shutdown.clone()).await?;
+
+    // In-flight requests are done; write the audit records they queued
+    audit.shutdown().await;
+    info!("PDP stopped");
     Ok(())
 }
 
+/// SIGTERM or Ctrl-C: the listeners stop accepting and finish their requests.
+async fn shutdown_signal() {
+    let terminate = async {
+        match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+            Ok(mut term) => {
+                term.recv().await;
+            }
+            Err(e) => {
+                warn!("cannot listen for SIGTERM: {e}");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    tokio::select! {
+        _ = signal::ctrl_c() => {}
+        _ = terminate => {}
+    }
+    info!("shutting down");
+}
+
 async fn check_base(
     State(state): State<AppState>,
     headers: HeaderMap,
@@ -208,21 +565,139 @@ async fn check_with_rest(
 
 async fn admin_validate(
     State(_state): State<AppState>,
+    caller: AdminPrincipal,
     Json(req): Json<AdminValidateRequest>,
-) -> Json<AdminValidateResponse> {
+) -> Result<Json<AdminValidateResponse>, (StatusCode, Json<AuthzDecision>)> {
+    admin_auth::require(&caller, Permission::Validate, None)?;
     let parse_result = parse_policy_set_strings(&req.policies);
     let (ok, errors) = match parse_result {
         Ok(_) => (true, Vec::new()),
         Err(errs) => (false, errs),
     };
 
-    Json(AdminValidateResponse { ok, errors })
+    Ok(Json(AdminValidateResponse { ok, errors }))
+}
+
+/// Signed bundle of the tenant's current state. Answers 304 when `If-None-Match`
+/// matches the payload hash, so edge PDPs can poll cheaply.
+async fn admin_bundle(
+    State(state): State<AppState>,
+    caller: AdminPrincipal,
+    Path(tenant): Path<Uuid>,
+    headers: HeaderMap,
+) -> Result<Response, (StatusCode, Json<AuthzDecision>)> {
+    admin_auth::require(&caller, Permission::Bundle, Some(tenant))?;
+    let error = |status: StatusCode, reason: &str| {
+        (
+            status,
+            Json(AuthzDecision {
+                decision: "DENY".into(),
+                reason: reason.into(),
+            }),
+        )
+    };
+    let Some(bundles) = &state.bundles else {
+        return Err(error(StatusCode::NOT_FOUND, "bundle signing disabled"));
+    };
+    let (version, payload) = match bundles.payload(tenant).await {
+        Ok(Some(p)) => p,
+        Ok(None) => return Err(error(StatusCode::NOT_FOUND, "tenant unknown")),
+        Err(e) => {
+            error!("bundle build error: {e:?}");
+            return Err(error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "bundle build error",
+            ));
+        }
+    };
+    let etag = format!("\"{}\"", bundle::digest(&payload));
+    if headers
+        .get(header::IF_NONE_MATCH)
+        .is_some_and(|v| v.as_bytes() == etag.as_bytes())
+    {
+        return Ok((StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response());
+    }
+    let signed = bundles.sign(tenant, version, payload);
+    Ok(([(header::ETAG, etag)], Json(signed)).into_response())
+}
+
+#[derive(Deserialize)]
+struct ChangesQuery {
+    since: Option<i64>,
+}
+
+/// The tenant's change feed after `since`, for sidecars; only the cursor without it.
+async fn admin_changes(
+    State(state): State<AppState>,
+    caller: AdminPrincipal,
+    Path(tenant): Path<Uuid>,
+    Query(q): Query<ChangesQuery>,
+) -> Result<Json<store::ChangeFeed>, (StatusCode, Json<AuthzDecision>)> {
+    admin_auth::require(&caller, Permission::Bundle, Some(tenant))?;
+    let error = |status: StatusCode, reason: &str| {
+        (
+            status,
+            Json(AuthzDecision {
+                decision: "DENY".into(),
+                reason: reason.into(),
+            }),
+        )
+    };
+    let Some(feed) = &state.change_feed else {
+        return Err(error(
+            StatusCode::NOT_FOUND,
+            "change feed needs the Postgres store",
+        ));
+    };
+    match feed.changes(tenant, q.since).await {
+        Ok(changes) => Ok(Json(changes)),
+        Err(e) => {
+            error!("change feed error: {e:?}");
+            Err(error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "change feed error",
+            ))
+        }
+    }
+}
+
+/// "ok", plus sidecar staleness and invalidation listener state when they apply.
+/// 503 until a sidecar's first sync, once it is stale with `SIDECAR_STALE_MODE=fail-closed`,
+/// or once every invalidation listener is down for `INVALIDATION_MAX_DOWN_SECS`.
+async fn ready(State(state): State<AppState>) -> (StatusCode, String) {
+    let checks: Vec<_> = [
+        Some(state.warm_up.readiness()),
+        state.sidecar.as_ref().map(|s| s.readiness()),
+        state.invalidation_health.as_ref().map(|h| h.readiness()),
+        state.redis.as_ref().map(|r| r.readiness()),
+    ]
+    .into_iter()
+    .flatten()
+    .collect();
+    if checks.is_empty() {
+        return (StatusCode::OK, "ok".into());
+    }
+    let ready = checks.iter().all(|(ok, _)| *ok);
+    let msg = checks
+        .into_iter()
+        .map(|(_, msg)| msg)
+        .collect::<Vec<_>>()
+        .join("; ");
+    if ready {
+        (StatusCode::OK, msg)
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, msg)
+    }
 }
 
 async fn admin_test(
     State(state): State<AppState>,
+    caller: AdminPrincipal,
     Json(req): Json<AdminTestRequest>,
 ) -> (StatusCode, Json<AuthzDecision>) {
+    if let Err(rejection) = admin_auth::require(&caller, Permission::Test, req.tenant_id) {
+        return rejection;
+    }
     let AdminTestRequest {
         policies_override,
         tenant_id,
@@ -232,227 +707,130 @@ async fn admin_test(
         context,
     } = req;
 
-    let (principal_uid_str, principal_inline_attrs) = match principal {
-        EntityInput::Uid(s) => (s, json!({})), // Si es string, no hay atributos inline.
-        EntityInput::Full(e) => (
-            format!(r#"{}::"{}""#, e.entity_type, e.id),
-            e.attributes,
-        ),
-    };
-
-    let (resource_uid_str, resource_inline_attrs) = match resource {
-        EntityInput::Uid(s) => (s, json!({})), // Si es string, no hay atributos inline.
-        EntityInput::Full(e) => (
-            format!(r#"{}::"{}""#, e.entity_type, e.id),
-            e.attributes,
-        ),
-    };
-
-    let mut reason_origin = String::from("override");
-    let principal_attrs: Value;
-    let resource_attrs: Value;
-
-    let policy_set = if let Some(policies) = policies_override {
-        principal_attrs = principal_inline_attrs;
-        resource_attrs = resource_inline_attrs;
-        if let Some(tid) = tenant_id {
-            if let Err(e) = set_tenant_context(&state.db, tid).await {
-                error!("set_config app.tenant_id failed: {e}");
-                return (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    Json(AuthzDecision {
-                        decision: "DENY".into(),
-                        reason: "tenant set failed".into(),
-                    }),
-                );
-            }
-        }
-
-        match parse_policy_set_strings(&policies) {
-            Ok(pset) => pset,
-            Err(errs) => {
-                return (
-                    StatusCode::BAD_REQUEST,
-                    Json(AuthzDecision {
-                        decision: "DENY".into(),
-                        reason: errs.join("; "),
-                    }),
-                );
-            }
-        }
-    } else {
-        let tenant = match tenant_id {
-            Some(t) => t,
-            None => {
-                return (
-                    StatusCode::BAD_REQUEST,
-                    Json(AuthzDecision {
-                        decision: "DENY".into(),
-                        reason: "tenant_id is required when policies_override is not provided"
-                            .into(),
-                    }),
-                );
-            }
-        };
-
-        if let Err(e) = set_tenant_context(&state.db, tenant).await {
-            error!("set_config app.tenant_id failed: {e}");
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(AuthzDecision {
-                    decision: "DENY".into(),
-                    reason: "tenant set failed".into(),
-                }),
-            );
-        }
-
-        principal_attrs = match load_attrs(&state.db, "principals", &principal_uid_str).await {
-            Ok(attrs) => attrs,
-            Err(e) => {
-                warn!("load principal attrs error: {e:?}");
-                json!({})
-            }
-        };
-        resource_attrs = match load_attrs(&state.db, "resources", &resource_uid_str).await {
-            Ok(attrs) => attrs,
-            Err(e) => {
-                warn!("load resource attrs error: {e:?}");
-                json!({})
-            }
-        };
-        
-        match load_policies_for_tenant(&state, tenant).await {
-            Ok((version, pset)) => {
-                reason_origin = format!("active v{}", version);
-                pset
-            }
-            Err(PDPError::Other(msg)) if msg == "no active policy_set" => {
-                return (
-                    StatusCode::NOT_FOUND,
-                    Json(AuthzDecision { decision: "DENY".into(), reason: "no active policy_set".into() })
-                );
-            }
-            Err(e) => {
-                error!("load policies error: {e:?}");
-                return (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    Json(AuthzDecision {
-                        decision: "DENY".into(),
-                        reason: "policy load error".into(),
-                    }),
-                );
-            }
-        }
-    };
-
+    let principal = principal.into_record();
+    let resource = resource.into_record();
     let action_str = action.unwrap_or_else(|| "read".to_string());
     let ctx_json = context.unwrap_or_else(|| json!({}));
 
-  /*   let principal_attrs = match load_attrs(&state.db, "principals", &principal).await {
-        Ok(attrs) => attrs,
-        Err(e) => {
-            warn!("load principal attrs error: {e:?}");
-            json!({})
-        }
-    };
-    let resource_attrs = match load_attrs(&state.db, "resources", &resource).await {
-        Ok(attrs) => attrs,
-        Err(e) => {
-            warn!("load resource attrs error: {e:?}");
-            json!({})
-        }
-    }; */
-
-    let auid = match EntityUid::from_str(&principal_uid_str) {
-        Ok(u) => u,
-        Err(_) => {
-            return (
-                StatusCode::BAD_REQUEST,
-                Json(AuthzDecision {
-                    decision: "DENY".into(),
-                    reason: "invalid principal UID".into(),
-                }),
-            );
-        }
-    };
-    let ruid = match EntityUid::from_str(&resource_uid_str) {
-        Ok(u) => u,
-        Err(_) => {
-            return (
-                StatusCode::BAD_REQUEST,
-                Json(AuthzDecision {
-                    decision: "DENY".into(),
-                    reason: "invalid resource UID".into(),
-                }),
-            );
-        }
-    };
-    let action_uid = match EntityUid::from_str(&format!(r#"Action::"{}""#, action_str)) {
-        Ok(u) => u,
-        Err(_) => {
-            return (
-                StatusCode::BAD_REQUEST,
-                Json(AuthzDecision {
-                    decision: "DENY".into(),
-                    reason: "invalid action".into(),
-                }),
+    // With policies_override, entities come inline from the request.
+    let inline_store;
+    let (override_set, active);
+    let (policy_set, entity_store, tenant, reason_origin): (_, &dyn EntityStore, _, _) =
+        if let Some(policies) = policies_override {
+            let pset = match parse_policy_set_strings(&policies) {
+                Ok(pset) => pset,
+                Err(errs) => {
+                    return (
+                        StatusCode::BAD_REQUEST,
+                        Json(AuthzDecision {
+                            decision: "DENY".into(),
+                            reason: errs.join("; "),
+                        }),
+                    );
+                }
+            };
+            let tenant = tenant_id.unwrap_or_default();
+            inline_store = MemoryStore::new();
+            inline_store.put_tenant(
+                tenant,
+                MemoryTenant::new(None, [principal.clone(), resource.clone()]),
             );
-        }
-    };
+            override_set = PolicyIndex::new(pset);
+            (
+                &override_set,
+                &inline_store,
+                tenant,
+                String::from("override"),
+            )
+        } else {
+            let tenant = match tenant_id {
+                Some(t) => t,
+                None => {
+                    return (
+                        StatusCode::BAD_REQUEST,
+                        Json(AuthzDecision {
+                            decision: "DENY".into(),
+                            reason: "tenant_id is required when policies_override is not provided"
+                                .into(),
+                        }),
+                    );
+                }
+            };
 
-    let ctx_cedar = match cedar_policy::Context::from_json_value(ctx_json.clone(), None) {
-        Ok(ctx) => ctx,
-        Err(e) => {
-            warn!("invalid context provided for admin test: {e:?}");
-            return (
-                StatusCode::BAD_REQUEST,
-                Json(AuthzDecision {
-                    decision: "DENY".into(),
-                    reason: "invalid context".into(),
-                }),
-            );
-        }
-    };
+            match load_policies_for_tenant(&state, tenant).await {
+                Ok(snapshot) => {
+                    active = snapshot;
+                    (
+                        &active.policies,
+                        state.entity_store.as_ref(),
+                        tenant,
+                        format!("active v{}", active.version),
+                    )
+                }
+                Err(StoreError::NoActivePolicySet) => {
+                    let meta = load_tenant_meta(&state, tenant).await.ok().flatten();
+                    let decision = if default_allows(&state, meta.as_ref()) {
+                        "ALLOW"
+                    } else {
+                        "DENY"
+                    };
+                    return (
+                        StatusCode::OK,
+                        Json(AuthzDecision {
+                            decision: decision.into(),
+                            reason: format!(
+                                "default {} (no active policy_set)",
+                                decision.to_lowercase()
+                            ),
+                        }),
+                    );
+                }
+                Err(e) => {
+                    error!("load policies error: {e:?}");
+                    return (
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        Json(AuthzDecision {
+                            decision: "DENY".into(),
+                            reason: "policy load error".into(),
+                        }),
+                    );
+                }
+            }
+        };
 
-    let entities = match build_entities(&principal_uid_str, &resource_uid_str, &principal_attrs, &resource_attrs) {
-        Ok(entities) => entities,
-        Err(PDPError::Other(reason)) => {
-            return (
-                StatusCode::BAD_REQUEST,
-                Json(AuthzDecision {
-                    decision: "DENY".into(),
-                    reason,
-                }),
-            );
-        }
-        Err(e) => {
-            error!("entities build error: {e:?}");
+    let decision = match evaluate(
+        entity_store,
+        tenant,
+        policy_set,
+        &principal.uid,
+        &resource.uid,
+        &action_str,
+        ctx_json,
+    )
+    .await
+    {
+        Ok(d) => d,
+        Err(EvalError::Store(e)) => {
+            error!("load entities error: {e:?}");
             return (
                 StatusCode::INTERNAL_SERVER_ERROR,
                 Json(AuthzDecision {
                     decision: "DENY".into(),
-                    reason: "invalid entities".into(),
+                    reason: "entity load error".into(),
                 }),
             );
         }
-    };
-
-    let req = match Request::new(Some(auid), Some(action_uid), Some(ruid), ctx_cedar, None) {
-        Ok(r) => r,
-        Err(_) => {
+        Err(e) => {
             return (
                 StatusCode::BAD_REQUEST,
                 Json(AuthzDecision {
                     decision: "DENY".into(),
-                    reason: "invalid request".into(),
+                    reason: e.to_string(),
                 }),
             );
         }
     };
-
-    let authz = Authorizer::new();
-    let resp = authz.is_authorized(&req, &policy_set, &entities);
-    let decision_str = if resp.decision() == Decision::Allow {
+    let decision_str = if decision == Decision::Allow {
         "ALLOW"
     } else {
         "DENY"
@@ -481,18 +859,29 @@ async fn check_impl(
 ) -> (StatusCode, Json<AuthzDecision>) {
     let started = Instant::now();
 
-    let tenant_id = match headers
-        .get("x-tenant-id")
-        .and_then(|v| v.to_str().ok())
-        .and_then(|s| Uuid::parse_str(s).ok())
-    {
-        Some(t) => t,
-        None => return deny("missing x-tenant-id"),
-    };
-
-    let principal = match headers.get("x-principal").and_then(|v| v.to_str().ok()) {
-        Some(p) => p.to_string(),
-        None => return deny("missing x-principal"),
+    // --- Identity: verified token claims when JWT_VERIFY is on, forwarded headers otherwise ---
+    let (tenant_id, principal) = match &state.jwt {
+        Some(verifier) => match verifier.verify(&headers).await {
+            Ok(id) => {
+                if let Err(PDPError::Other(reason)) =
+                    headers_agree(&headers, id.tenant_id, &id.principal)
+                {
+                    metrics::counter!("pdp_jwt_rejected_total", "reason" => "mismatch")
+                        .increment(1);
+                    return unauthorized(&reason);
+                }
+                (id.tenant_id, id.principal)
+            }
+            Err(e) => {
+                metrics::counter!("pdp_jwt_rejected_total", "reason" => e.label()).increment(1);
+                return unauthorized(&e.to_string());
+            }
+        },
+        None => match header_identity(&headers) {
+            Ok(v) => v,
+            Err(PDPError::MissingHeader(h)) => return deny(&format!("missing {h}")),
+            Err(e) => return deny(&e.to_string()),
+        },
     };
 
     let resource = match headers.get("x-resource").and_then(|v| v.to_str().ok()) {
@@ -506,9 +895,6 @@ async fn check_impl(
         .unwrap_or("read")
         .to_string();
 
-    if let Some("1") = headers.get("x-allow").and_then(|v| v.to_str().ok()) {
-        return allow("allowed by x-allow: 1");
-    }
     // --- Validate claims signature if applicable (defense of headers forged by client) ---
     if let (Some(sig), Some(tid), Some(pri)) = (
         headers.get("x-claims-sig").and_then(|v| v.to_str().ok()),
@@ -534,26 +920,59 @@ async fn check_impl(
         }
     }
 
+    // --- Sidecar: no decisions from a snapshot past SIDECAR_MAX_STALENESS_SECS ---
+    if let Some(sidecar) = &state.sidecar {
+        if let Err(reason) = sidecar.check_fresh(tenant_id) {
+            metrics::counter!("pdp_sidecar_stale_denied_total").increment(1);
+            record_latency(started.elapsed());
+            return deny(&reason);
+        }
+    }
+
+    // --- Tenant lifecycle: only active tenants get decisions ---
+    let tenant_meta = match load_tenant_meta(&state, tenant_id).await {
+        Ok(Some(meta)) => meta,
+        Ok(None) => {
+            metrics::counter!("pdp_tenant_denied_total", "status" => "unknown").increment(1);
+            record_latency(started.elapsed());
+            return deny("tenant unknown");
+        }
+        Err(e) => {
+            error!("load tenant error: {e:?}");
+            record_latency(started.elapsed());
+            return deny("tenant load error");
+        }
+    };
+    if tenant_meta.status != TenantStatus::Active {
+        metrics::counter!("pdp_tenant_denied_total", "status" => tenant_meta.status.as_str())
+            .increment(1);
+        record_latency(started.elapsed());
+        return deny(&format!("tenant {}", tenant_meta.status.as_str()));
+    }
+
     // --- Rate limit by tenant ---
     // Simple policy: N RPS per tenant (approximate TOKEN BUCKET with counter/sec)
+    // Skipped while Redis is unavailable
     let mut over_limit = false;
-    if let Ok(mut conn) = get_redis_conn(&state.redis_client).await {
+    if let Some(redis) = &state.redis {
         let now = time::OffsetDateTime::now_utc().unix_timestamp();
         let window = format!("{}", now); // 1s bucket
-        let rate_key = format!("rl:{}:{}", tenant_id, window);
+                                         // Hash tag: the tenant's keys share a cluster slot
+        let rate_key = format!("rl:{{{}}}:{}", tenant_id, window);
         let limit = state.rate_limit_rps_default as i64;
 
-        match conn.incr::<_, _, i64>(&rate_key, 1).await {
-            Ok(count) => {
-                let _: redis::RedisResult<()> = conn.expire::<_, ()>(&rate_key, 2).await;
-                if count > limit {
-                    over_limit = true;
-                }
-            }
-            Err(e) => {
-                warn!("ratelimit redis error: {e}");
-            }
-        }
+        let count = redis
+            .run("ratelimit", |mut conn| async move {
+                let (count,): (i64,) = redis::pipe()
+                    .incr(&rate_key, 1)
+                    .expire(&rate_key, 2)
+                    .ignore()
+                    .query_async(&mut conn)
+                    .await?;
+                Ok(count)
+            })
+            .await;
+        over_limit = count.is_some_and(|count| count > limit);
     }
     if over_limit {
         metrics::counter!("pdp_ratelimit_rejected_total").increment(1);
@@ -566,142 +985,207 @@ async fn check_impl(
         );
     }
 
+    // --- Break-glass (audited, never cached) ---
+    if let Some(token) = headers
+        .get(BREAK_GLASS_HEADER)
+        .and_then(|v| v.to_str().ok())
+    {
+        // Refused attempts are audited as well: forged or replayed tokens show up there
+        let refused = |reason: String| AuditRecord {
+            tenant_id,
+            principal: principal.clone(),
+            resource: resource.clone(),
+            action: action_str.clone(),
+            decision: "DENY",
+            policy_set_version: None,
+            latency_ms: started.elapsed().as_millis() as i32,
+            source: "break_glass",
+            reason: Some(reason),
+        };
+        let Some(bg) = &state.break_glass else {
+            metrics::counter!("pdp_break_glass_total", "outcome" => "disabled").increment(1);
+            let reason = "break-glass disabled";
+            state.audit.record(refused(reason.into())).await;
+            record_latency(started.elapsed());
+            return deny(reason);
+        };
+        match bg.verify(token, tenant_id, &principal) {
+            Ok(grant) => {
+                metrics::counter!("pdp_break_glass_total", "outcome" => "granted").increment(1);
+                warn!(
+                    "break-glass ALLOW tenant={} principal={} resource={} action={} justification={:?}",
+                    tenant_id, principal, resource, action_str, grant.justification
+                );
+                state
+                    .audit
+                    .record(AuditRecord {
+                        tenant_id,
+                        principal: principal.clone(),
+                        resource: resource.clone(),
+                        action: action_str.clone(),
+                        decision: "ALLOW",
+                        policy_set_version: None,
+                        latency_ms: started.elapsed().as_millis() as i32,
+                        source: "break_glass",
+                        reason: Some(grant.justification.clone()),
+                    })
+                    .await;
+                record_latency(started.elapsed());
+                return allow("break-glass");
+            }
+            Err(e) => {
+                metrics::counter!("pdp_break_glass_total", "outcome" => "rejected").increment(1);
+                warn!(
+                    "break-glass rejected tenant={} principal={}: {e}",
+                    tenant_id, principal
+                );
+                let reason = format!("break-glass rejected: {e}");
+                state.audit.record(refused(reason.clone())).await;
+                record_latency(started.elapsed());
+                return deny(&reason);
+            }
+        }
+    }
+
     let ctx_json = json!({
         "timeOfDay": "workhours",
         "path": original_path,
     });
 
-    // Cache key
-    let cache_key = make_cache_key(
-        &tenant_id,
-        &principal,
-        &resource,
-        &action_str,
-        &ctx_json.to_string(),
-    );
-
-    // Redis GET
-    if let Ok(mut conn) = get_redis_conn(&state.redis_client).await {
-        let cached: redis::RedisResult<Option<String>> = conn.get(&cache_key).await;
-        if let Ok(Some(v)) = cached {
-            metrics::counter!("pdp_cache_hits_total").increment(1);
-
+    // Active Policies
+    let snapshot = match load_policies_for_tenant(&state, tenant_id).await {
+        Ok(snapshot) => snapshot,
+        // Not an error: the tenant runs on its default decision until a set is activated.
+        // Not cached in Redis so activating a policy set takes effect immediately.
+        Err(StoreError::NoActivePolicySet) => {
+            let decision = if default_allows(&state, Some(&tenant_meta)) {
+                "ALLOW"
+            } else {
+                "DENY"
+            };
+            metrics::counter!("pdp_default_decisions_total", "decision" => decision).increment(1);
+            state
+                .audit
+                .record(AuditRecord {
+                    tenant_id,
+                    principal: principal.clone(),
+                    resource: resource.clone(),
+                    action: action_str.clone(),
+                    decision,
+                    policy_set_version: None,
+                    latency_ms: started.elapsed().as_millis() as i32,
+                    source: "default",
+                    reason: Some("no active policy_set".into()),
+                })
+                .await;
             record_latency(started.elapsed());
-            return if v == "ALLOW" {
-                allow("cache hit")
+            return if decision == "ALLOW" {
+                allow("default allow (no active policy_set)")
             } else {
-                deny("cache hit")
+                deny("default deny (no active policy_set)")
             };
         }
-    }
-    metrics::counter!("pdp_cache_misses_total").increment(1);
-
-    // DB: RLS tenant
-    if let Err(e) = set_tenant_context(&state.db, tenant_id).await {
-        error!("set_config app.tenant_id failed: {e}");
-        record_latency(started.elapsed());
-        return deny("tenant set failed");
-    }
-
-    // Active Policies
-    let (version, pset) = match load_policies_for_tenant(&state, tenant_id).await {
-        Ok(v) => v,
         Err(e) => {
             error!("load policies error: {e:?}");
             record_latency(started.elapsed());
-            return deny("policy load error");
+            return fail(&tenant_meta, "policy load error");
         }
     };
 
-    // Attributes
-    let principal_attrs = load_attrs(&state.db, "principals", &principal)
-        .await
-        .unwrap_or(json!({}));
-    let resource_attrs = load_attrs(&state.db, "resources", &resource)
-        .await
-        .unwrap_or(json!({}));
-
-    // Cedar UIDs
-    let auid = match EntityUid::from_str(&principal) {
-        Ok(u) => u,
-        Err(_) => return deny("invalid principal UID"),
-    };
-    let ruid = match EntityUid::from_str(&resource) {
-        Ok(u) => u,
-        Err(_) => return deny("invalid resource UID"),
-    };
-    let action_uid = match EntityUid::from_str(&format!(r#"Action::"{}""#, action_str)) {
-        Ok(u) => u,
-        Err(_) => return deny("invalid action"),
-    };
-
-    // Context
-    let ctx_val = serde_json::to_value(&ctx_json).unwrap();
-    let ctx_cedar =
-        cedar_policy::Context::from_json_value(ctx_val, None).map_err(|e| e.to_string());
-    if ctx_cedar.is_err() {
-        return deny("invalid context");
+    // Malformed UIDs are refused before the cache, so they never match a cached decision
+    if let Err(e) = request_uids(&principal, &resource, &action_str) {
+        return deny(&e.to_string());
     }
-    let entities = match build_entities(&principal, &resource, &principal_attrs, &resource_attrs) {
-        Ok(entities) => entities,
-        Err(PDPError::Other(reason)) => {
-            return (
-                StatusCode::FORBIDDEN,
-                Json(AuthzDecision {
-                    decision: "DENY".into(),
-                    reason,
-                }),
-            );
+
+    // Decision cache, per tenant and policy set version
+    let cache_key = decision_cache::key(
+        tenant_id,
+        snapshot.version,
+        &principal,
+        &resource,
+        &action_str,
+        &ctx_json.to_string(),
+    );
+    let generation = state.decision_generations.current(tenant_id);
+    if let Some(l1) = &state.decision_l1 {
+        if let Some(v) = l1.get(&cache_key) {
+            metrics::counter!("pdp_cache_hits_total", "tier" => "l1").increment(1);
+            record_latency(started.elapsed());
+            return cached_decision(v);
         }
-        Err(e) => {
-            error!("entities build error: {e:?}");
-            return deny("invalid entities");
+        metrics::counter!("pdp_cache_misses_total", "tier" => "l1").increment(1);
+    }
+    let cached = match &state.redis {
+        Some(redis) => {
+            let key = &cache_key;
+            redis
+                .run("cache_get", |mut conn| async move {
+                    decision_cache::get(&mut conn, key).await
+                })
+                .await
+                .flatten()
         }
+        None => None,
     };
+    if let Some(v) = cached {
+        metrics::counter!("pdp_cache_hits_total", "tier" => "l2").increment(1);
+        let (p, r) = (&principal, &resource);
+        cache_decision_l1(&state, tenant_id, generation, p, r, &cache_key, &v);
 
-    // Request (note: Cedar v3 expects Option<EntityUid> for P/A/R and Context)
-    let req = match Request::new(
-        Some(auid),
-        Some(action_uid),
-        Some(ruid),
-        ctx_cedar.unwrap(),
-        None,
-    ) {
-        Ok(r) => r,
-        Err(_) => return deny("invalid request"),
-    };
-
-    // Authorize
-    let authz = Authorizer::new();
-    let resp = authz.is_authorized(&req, &pset, &entities);
-    let decision = if resp.decision() == Decision::Allow {
-        "ALLOW"
-    } else {
-        "DENY"
-    };
-
-    // Cache SET
-    if let Ok(mut conn) = get_redis_conn(&state.redis_client).await {
-        let _: redis::RedisResult<()> = conn
-            .set_ex(&cache_key, decision, REDIS_DECISIONS_TTL_SECS as u64)
-            .await;
+        record_latency(started.elapsed());
+        return cached_decision(&v);
     }
+    metrics::counter!("pdp_cache_misses_total", "tier" => "l2").increment(1);
+
+    // Cedar, with principal/resource attributes and parents from the entity store.
+    // Identical concurrent misses share one evaluation and cache write.
+    let evaluated = state
+        .decision_flights
+        .run((generation, cache_key.clone()), || {
+            let (state, principal, resource) = (&state, &principal, &resource);
+            let (action, key, policies) = (&action_str, &cache_key, &snapshot.policies);
+            async move {
+                let entities = state.entity_store.as_ref();
+                let decision = match evaluate(
+                    entities, tenant_id, policies, principal, resource, action, ctx_json,
+                )
+                .await
+                .map_err(Arc::new)?
+                {
+                    Decision::Allow => "ALLOW",
+                    Decision::Deny => "DENY",
+                };
+                let (p, r) = (principal, resource);
+                cache_decision(state, tenant_id, generation, p, r, key, decision).await;
+                Ok(decision)
+            }
+        })
+        .await;
+    let decision = match evaluated {
+        Ok(decision) => decision,
+        Err(e) if matches!(*e, EvalError::Store(_)) => {
+            error!("load entities error: {e:?}");
+            record_latency(started.elapsed());
+            return fail(&tenant_meta, "entity load error");
+        }
+        Err(e) => return deny(&e.to_string()),
+    };
 
     // Audit
-    let latency_ms = started.elapsed().as_millis() as i32;
-    let _ = sqlx::query(
-        "INSERT INTO audit_logs (tenant_id, principal, resource, action, decision, policy_set_version, latency_ms)
-         VALUES ($1,$2,$3,$4,$5,$6,$7)"
-    )
-    .bind(tenant_id)
-    .bind(&principal)
-    .bind(&resource)
-    .bind(&action_str)
-    .bind(decision)
-    .bind(version)
-    .bind(latency_ms)
-    .execute(&state.db)
-    .await;
+    state
+        .audit
+        .record(AuditRecord {
+            tenant_id,
+            principal: principal.clone(),
+            resource: resource.clone(),
+            action: action_str.clone(),
+            decision,
+            policy_set_version: Some(snapshot.version),
+            latency_ms: started.elapsed().as_millis() as i32,
+            source: "cedar",
+            reason: None,
+        })
+        .await;
 
     record_latency(started.elapsed());
 
@@ -712,6 +1196,36 @@ async fn check_impl(
     }
 }
 
+/// Tenant and principal as forwarded by Envoy (`x-tenant-id`, `x-principal`).
+fn header_identity(headers: &HeaderMap) -> Result<(Uuid, String), PDPError> {
+    let tenant = headers
+        .get("x-tenant-id")
+        .and_then(|v| v.to_str().ok())
+        .ok_or(PDPError::MissingHeader("x-tenant-id"))?;
+    let tenant_id = Uuid::parse_str(tenant).map_err(|_| PDPError::InvalidTenant)?;
+    let principal = headers
+        .get("x-principal")
+        .and_then(|v| v.to_str().ok())
+        .ok_or(PDPError::MissingHeader("x-principal"))?;
+    Ok((tenant_id, principal.to_string()))
+}
+
+/// Forwarded identity headers are optional with a verified token, but must match it.
+fn headers_agree(headers: &HeaderMap, tenant_id: Uuid, principal: &str) -> Result<(), PDPError> {
+    if let Some(v) = headers.get("x-tenant-id") {
+        let hdr = v.to_str().ok().and_then(|s| Uuid::parse_str(s).ok());
+        if hdr != Some(tenant_id) {
+            return Err(PDPError::Other("x-tenant-id does not match token".into()));
+        }
+    }
+    if let Some(v) = headers.get("x-principal") {
+        if v.to_str().ok() != Some(principal) {
+            return Err(PDPError::Other("x-principal does not match token".into()));
+        }
+    }
+    Ok(())
+}
+
 fn allow(reason: &str) -> (StatusCode, Json<AuthzDecision>) {
     (
         StatusCode::OK,
@@ -730,21 +1244,40 @@ fn deny(reason: &str) -> (StatusCode, Json<AuthzDecision>) {
         }),
     )
 }
+/// Decision for a tenant without an active policy set: tenant setting, else `DEFAULT_ALLOW`.
+fn default_allows(state: &AppState, meta: Option<&TenantMeta>) -> bool {
+    match meta.and_then(|m| m.settings.default_decision) {
+        Some(d) => d == DefaultDecision::Allow,
+        None => state.default_decision_allow,
+    }
+}
 
-fn make_cache_key(
-    tenant: &Uuid,
-    principal: &str,
-    resource: &str,
-    action: &str,
-    context_json: &str,
-) -> String {
-    let mut h = Sha256::new();
-    h.update(tenant.as_bytes());
-    h.update(principal.as_bytes());
-    h.update(resource.as_bytes());
-    h.update(action.as_bytes());
-    h.update(context_json.as_bytes());
-    format!("pdp:decision:{:x}", h.finalize())
+/// Answer for internal failures, per the tenant's fail mode (closed by default).
+fn fail(meta: &TenantMeta, reason: &str) -> (StatusCode, Json<AuthzDecision>) {
+    match meta.settings.fail_mode {
+        FailMode::Open => {
+            metrics::counter!("pdp_fail_open_total").increment(1);
+            allow(&format!("fail-open: {reason}"))
+        }
+        FailMode::Closed => deny(reason),
+    }
+}
+fn unauthorized(reason: &str) -> (StatusCode, Json<AuthzDecision>) {
+    (
+        StatusCode::UNAUTHORIZED,
+        Json(AuthzDecision {
+            decision: "DENY".into(),
+            reason: reason.into(),
+        }),
+    )
+}
+
+fn cached_decision(v: &str) -> (StatusCode, Json<AuthzDecision>) {
+    if v == "ALLOW" {
+        allow("cache hit")
+    } else {
+        deny("cache hit")
+    }
 }
 
 fn parse_policy_set_strings(policies: &[String]) -> Result<PolicySet, Vec<String>> {
@@ -781,24 +1314,24 @@ fn split_type_and_id(uid: &str) -> Option<(String, String)> {
     Some((typ, id))
 }
 
-fn build_entities(
-    principal: &str,
-    resource: &str,
-    principal_attrs: &Value,
-    resource_attrs: &Value,
-) -> Result<Entities, PDPError> {
-    let (p_type, p_id) = split_type_and_id(principal)
-        .ok_or_else(|| PDPError::Other("invalid principal UID format".into()))?;
-    let (r_type, r_id) = split_type_and_id(resource)
-        .ok_or_else(|| PDPError::Other("invalid resource UID format".into()))?;
-
-    // Construye directamente el formato de objeto correcto.
-    let entities_json = json!([
-        { "uid": { "type": p_type, "id": p_id }, "attrs": principal_attrs.clone(), "parents": [] },
-        { "uid": { "type": r_type, "id": r_id }, "attrs": resource_attrs.clone(),  "parents": [] }
-    ]);
-
-    // Intenta parsear solo el formato correcto.
+/// Cedar JSON form (`{"uid", "attrs", "parents"}`) of an entity record.
+fn entity_json(e: &EntityRecord) -> Result<Value, EvalError> {
+    let uid_json = |uid: &str| {
+        split_type_and_id(uid)
+            .map(|(typ, id)| json!({ "type": typ, "id": id }))
+            .ok_or(EvalError::Entities)
+    };
+    let parents = e
+        .parents
+        .iter()
+        .map(|p| uid_json(p))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(json!({ "uid": uid_json(&e.uid)?, "attrs": e.attrs, "parents": parents }))
+}
+
+fn build_entities(records: &[EntityRecord]) -> Result<Entities, EvalError> {
+    let entities_json = Value::Array(records.iter().map(entity_json).collect::<Result<_, _>>()?);
+
     match Entities::from_json_value(entities_json.clone(), None) {
         Ok(entities) => Ok(entities),
         Err(e) => {
@@ -806,86 +1339,178 @@ fn build_entities(
                 "cedar entities parse failed with object format. err={e:?} json={}",
                 entities_json
             );
-            Err(PDPError::Other("invalid entities".into()))
+            Err(EvalError::Entities)
         }
     }
 }
 
-async fn set_tenant_context(db: &PgPool, tenant_id: Uuid) -> Result<(), PDPError> {
-    sqlx::query("SELECT set_config('app.tenant_id', $1, true)")
-        .bind(tenant_id.to_string())
-        .execute(db)
-        .await?;
-    Ok(())
+/// Principal, resource and action UIDs of a request.
+fn request_uids(
+    principal: &str,
+    resource: &str,
+    action: &str,
+) -> Result<(EntityUid, EntityUid, EntityUid), EvalError> {
+    let auid = EntityUid::from_str(principal).map_err(|_| EvalError::Principal)?;
+    let ruid = EntityUid::from_str(resource).map_err(|_| EvalError::Resource)?;
+    let action_uid =
+        EntityUid::from_str(&format!(r#"Action::"{}""#, action)).map_err(|_| EvalError::Action)?;
+    Ok((auid, ruid, action_uid))
 }
 
-async fn load_policies_for_tenant(
+/// Evaluates one request against a tenant's policy set. Principal and resource
+/// come from the entity store; unknown ones are evaluated without attributes.
+async fn evaluate(
+    store: &dyn EntityStore,
+    tenant: Uuid,
+    policies: &PolicyIndex,
+    principal: &str,
+    resource: &str,
+    action: &str,
+    ctx: Value,
+) -> Result<Decision, EvalError> {
+    let (auid, ruid, action_uid) = request_uids(principal, resource, action)?;
+    let ctx = cedar_policy::Context::from_json_value(ctx, None).map_err(|_| EvalError::Context)?;
+
+    let mut records = store.entities(tenant, &[principal, resource]).await?;
+    for uid in [principal, resource] {
+        if !records.iter().any(|e| e.uid == uid) {
+            records.push(EntityRecord::bare(uid));
+        }
+    }
+    let entities = build_entities(&records)?;
+
+    // Only the policies whose scope can match this action and these entity types
+    let pset = policies.slice(&auid, &action_uid, &ruid);
+
+    // Request (note: Cedar v3 expects Option<EntityUid> for P/A/R and Context)
+    let req = Request::new(Some(auid), Some(action_uid), Some(ruid), ctx, None)
+        .map_err(|_| EvalError::Request)?;
+    Ok(Authorizer::new()
+        .is_authorized(&req, &pset, &entities)
+        .decision())
+}
+
+fn unknown_tenants_cache() -> moka::sync::Cache<Uuid, ()> {
+    moka::sync::Cache::builder()
+        .max_capacity(UNKNOWN_TENANTS_MAX)
+        .time_to_live(UNKNOWN_TENANT_TTL)
+        .build()
+}
+
+async fn load_tenant_meta(
     state: &AppState,
     tenant: Uuid,
-) -> Result<(i32, PolicySet), PDPError> {
-    // memoria
-    if let Some((ver, set)) = state.policies_cache.read().await.get(&tenant).cloned() {
-        return Ok((ver, set));
-    }
-
-    // versión activa
-    let row_opt = sqlx::query(
-        r#"
-        SELECT ps.version
-        FROM policy_sets ps
-        WHERE ps.tenant_id = $1 AND ps.status='active'
-        ORDER BY ps.version DESC
-        LIMIT 1
-        "#,
-    )
-    .bind(tenant)
-    .fetch_optional(&state.db)
-    .await?;
-
-    let row = row_opt.ok_or_else(|| PDPError::Other("no active policy_set".into()))?;
-    let version: i32 = row.try_get("version")?;
-
-    // políticas de esa versión
-    let rows = sqlx::query(
-        r#"
-        SELECT p.cedar
-        FROM policies p
-        JOIN policy_sets ps ON p.policy_set_id = ps.id
-        WHERE ps.tenant_id = $1 AND ps.version = $2
-        "#,
-    )
-    .bind(tenant)
-    .bind(version)
-    .fetch_all(&state.db)
-    .await?;
+) -> Result<Option<TenantMeta>, StoreError> {
+    if let Some(meta) = state.tenants_cache.read().await.get(&tenant).cloned() {
+        return Ok(Some(meta));
+    }
+    if state.unknown_tenants.contains_key(&tenant) {
+        return Ok(None);
+    }
+    let meta = state.tenant_store.tenant(tenant).await?;
+    match &meta {
+        Some(m) => {
+            state.tenants_cache.write().await.insert(tenant, m.clone());
+        }
+        None => state.unknown_tenants.insert(tenant, ()),
+    }
+    Ok(meta)
+}
 
-    let mut pset = PolicySet::new();
-    for (idx, r) in rows.iter().enumerate() {
-        let cedar_text: String = r.try_get("cedar")?;
-        let pol = Policy::parse(Some(format!("p{}", idx)), &cedar_text)
-            .map_err(|e| PDPError::Cedar(format!("{e:?}")))?;
-        pset.add(pol)
-            .map_err(|e| PDPError::Cedar(format!("{e:?}")))?;
+async fn load_policies_for_tenant(
+    state: &AppState,
+    tenant: Uuid,
+) -> Result<Arc<PolicySnapshot>, StoreError> {
+    if let Some(snapshot) = state.policies_cache.get(tenant) {
+        return Ok(snapshot);
     }
 
+    // Concurrent misses for the tenant share one store read and parse. A reload that
+    // lands while it runs wins: the set read here may be the one it replaced.
     state
-        .policies_cache
-        .write()
+        .policy_flights
+        .run(tenant, || async {
+            let generation = state.policies_cache.generation(tenant);
+            let (version, policies) = state
+                .policy_store
+                .active_policy_set(tenant)
+                .await
+                .map_err(Arc::new)?;
+            let snapshot = Arc::new(PolicySnapshot::new(version, policies));
+            state
+                .policies_cache
+                .insert_if(tenant, generation, snapshot.clone());
+            Ok(snapshot)
+        })
         .await
-        .insert(tenant, (version, pset.clone()));
-    Ok((version, pset))
+        .map_err(StoreError::shared)
+}
+
+/// Whether no invalidation of the tenant happened since `generation` was read.
+/// Counts `pdp_decision_cache_stale_total` when one did.
+fn still_fresh(state: &AppState, tenant: Uuid, generation: u64) -> bool {
+    let fresh = state.decision_generations.current(tenant) == generation;
+    if !fresh {
+        metrics::counter!("pdp_decision_cache_stale_total").increment(1);
+    }
+    fresh
 }
 
-async fn load_attrs(
-    db: &PgPool,
-    table: &str,
-    cedar_uid: &str,
-) -> Result<serde_json::Value, PDPError> {
-    let sql = format!("SELECT attrs FROM {} WHERE cedar_uid=$1 LIMIT 1", table);
-    let row = sqlx::query(&sql).bind(cedar_uid).fetch_optional(db).await?;
-    Ok(row
-        .and_then(|r| r.try_get::<serde_json::Value, _>("attrs").ok())
-        .unwrap_or(json!({})))
+/// Writes a decision to the L1 unless an invalidation of the tenant came after
+/// `generation`; one that lands during the write takes the entry back out.
+/// `false` if the decision was not kept.
+fn cache_decision_l1(
+    state: &AppState,
+    tenant: Uuid,
+    generation: u64,
+    principal: &str,
+    resource: &str,
+    key: &str,
+    decision: &str,
+) -> bool {
+    if !still_fresh(state, tenant, generation) {
+        return false;
+    }
+    if let Some(l1) = &state.decision_l1 {
+        l1.put(tenant, principal, resource, key, decision);
+        if !still_fresh(state, tenant, generation) {
+            l1.remove(key);
+            return false;
+        }
+    }
+    true
+}
+
+/// Writes a fresh decision to the L1 and Redis, fenced like [`cache_decision_l1`].
+async fn cache_decision(
+    state: &AppState,
+    tenant: Uuid,
+    generation: u64,
+    principal: &str,
+    resource: &str,
+    key: &str,
+    decision: &'static str,
+) {
+    if !cache_decision_l1(
+        state, tenant, generation, principal, resource, key, decision,
+    ) {
+        return;
+    }
+    if let Some(redis) = &state.redis {
+        redis
+            .run("cache_put", |mut conn| async move {
+                decision_cache::put(&mut conn, tenant, &[principal, resource], key, decision).await
+            })
+            .await;
+        // The flush may have run before the write landed
+        if !still_fresh(state, tenant, generation) {
+            redis
+                .run("cache_delete", |mut conn| async move {
+                    decision_cache::delete(&mut conn, key).await
+                })
+                .await;
+        }
+    }
 }
 
 fn record_latency(dur: Duration) {
@@ -896,48 +1521,850 @@ fn record_latency(dur: Duration) {
     metrics::histogram!("pdp_latency_ms").record(ms);
 }
 
-async fn get_redis_conn(
-    client: &redis::Client,
-) -> Result<MultiplexedConnection, redis::RedisError> {
-    client.get_multiplexed_tokio_connection().await
+/// A carried status is applied to the cached tenant right away; otherwise the
+/// tenant is reloaded from the store on next use.
+async fn invalidate_tenant(
+    tenants: &RwLock<HashMap<Uuid, TenantMeta>>,
+    tid: Uuid,
+    status: Option<TenantStatus>,
+) {
+    let mut tenants = tenants.write().await;
+    match (status, tenants.get_mut(&tid)) {
+        (Some(status), Some(meta)) => meta.status = status,
+        _ => {
+            tenants.remove(&tid);
+        }
+    }
 }
 
-async fn spawn_redis_invalidation_listener(
-    client: redis::Client,
-    cache: Arc<RwLock<HashMap<Uuid, (i32, PolicySet)>>>,
-) -> anyhow::Result<()> {
-    tokio::spawn(async move {
-        // For pub/sub, "non-multiplexed" connection
-        match client.get_tokio_connection().await {
-            Ok(conn) => {
-                let mut pubsub = conn.into_pubsub();
-                if let Err(e) = pubsub.subscribe(REDIS_INVALIDATION_CHANNEL).await {
-                    warn!("redis subscribe error: {e}");
-                    return;
+/// Loads the tenant's active policy set and swaps it into the cache. When the new
+/// set does not parse, the previous one keeps serving and
+/// `pdp_policy_reload_failing{tenant}` is raised; other store errors drop the
+/// cached set so the next request loads it. `false` if nothing could be loaded.
+async fn reload_policies(state: &AppState, tid: Uuid) -> bool {
+    let failing = metrics::gauge!("pdp_policy_reload_failing", "tenant" => tid.to_string());
+    match state.policy_store.active_policy_set(tid).await {
+        Ok((version, policies)) => {
+            let snapshot = Arc::new(PolicySnapshot::new(version, policies));
+            state.policies_cache.insert(tid, snapshot);
+            failing.set(0.0);
+            info!("Reloaded policies for tenant {tid} (v{version})");
+            true
+        }
+        Err(StoreError::NoActivePolicySet) => {
+            state.policies_cache.remove(tid);
+            failing.set(0.0);
+            info!("Tenant {tid} has no active policy set");
+            true
+        }
+        Err(e @ StoreError::InvalidPolicy(_)) => {
+            metrics::counter!("pdp_policy_reload_failures_total").increment(1);
+            let previous = state.policies_cache.get(tid).map(|s| s.version);
+            match previous {
+                Some(v) => {
+                    failing.set(1.0);
+                    error!("tenant {tid}: new policy set rejected, still serving v{v}: {e}");
                 }
-                info!(
-                    "Subscribed to Redis invalidation channel {}",
-                    REDIS_INVALIDATION_CHANNEL
-                );
-                loop {
-                    if let Some(msg) = pubsub.on_message().next().await {
-                        if let Ok(payload) = msg.get_payload::<String>() {
-                            if let Ok(v) = serde_json::from_str::<serde_json::Value>(&payload) {
-                                if let Some(tid) = v
-                                    .get("tenant_id")
-                                    .and_then(|x| x.as_str())
-                                    .and_then(|s| uuid::Uuid::parse_str(s).ok())
-                                {
-                                    cache.write().await.remove(&tid);
-                                    tracing::info!("Invalidated policies cache for tenant {}", tid);
-                                }
-                            }
-                        }
-                    }
+                None => error!("tenant {tid}: policy set rejected, nothing to serve: {e}"),
+            }
+            false
+        }
+        Err(e) => {
+            metrics::counter!("pdp_policy_reload_failures_total").increment(1);
+            state.policies_cache.remove(tid);
+            warn!("tenant {tid}: policy reload failed, loading on next use: {e}");
+            false
+        }
+    }
+}
+
+#[derive(Default)]
+struct WarmUp {
+    total: AtomicUsize,
+    loaded: AtomicUsize,
+    done: AtomicBool,
+}
+
+impl WarmUp {
+    fn readiness(&self) -> (bool, String) {
+        let total = self.total.load(Ordering::Relaxed);
+        if self.done.load(Ordering::Acquire) {
+            (true, format!("warm-up: {total} tenants"))
+        } else {
+            let loaded = self.loaded.load(Ordering::Relaxed);
+            (false, format!("warming up ({loaded}/{total} tenants)"))
+        }
+    }
+}
+
+/// Loads every active tenant's policy set, retrying the tenant list until the
+/// store answers. Tenants that fail are loaded on first use instead.
+async fn warm_up(state: AppState) {
+    let started = Instant::now();
+    let tenants = loop {
+        match state.tenant_store.active_tenants().await {
+            Ok(tenants) => break tenants,
+            Err(e) => {
+                warn!("warm-up: listing tenants failed, retrying: {e}");
+                tokio::time::sleep(WARM_UP_RETRY).await;
+            }
+        }
+    };
+    let warm_up = &state.warm_up;
+    warm_up.total.store(tenants.len(), Ordering::Relaxed);
+    let failed = AtomicUsize::new(0);
+    futures::stream::iter(tenants)
+        .for_each_concurrent(WARM_UP_CONCURRENCY, |tid| {
+            let (state, failed) = (&state, &failed);
+            async move {
+                if !reload_policies(state, tid).await {
+                    failed.fetch_add(1, Ordering::Relaxed);
                 }
+                state.warm_up.loaded.fetch_add(1, Ordering::Relaxed);
+            }
+        })
+        .await;
+    warm_up.done.store(true, Ordering::Release);
+    info!(
+        "warm-up: loaded {} tenants in {:?} ({} failed)",
+        warm_up.total.load(Ordering::Relaxed),
+        started.elapsed(),
+        failed.load(Ordering::Relaxed)
+    );
+}
+
+/// Entity messages drop that principal's or resource's cached attributes and the
+/// decisions computed for it (L1 and Redis). Tenant-wide invalidations reload the
+/// policy set, drop the cached meta and all the tenant's decisions, and its
+/// attributes unless only policies changed.
+async fn apply_invalidation(state: &AppState, event: Event) {
+    match event {
+        Event::Invalidate(msg) => {
+            let tid = msg.tenant_id;
+            // Before any flush, so evaluations in flight don't write back what it drops
+            state.decision_generations.bump(tid);
+            if msg.entity().is_none() {
+                let status = msg.status.as_deref().and_then(|s| s.parse().ok());
+                invalidate_tenant(&state.tenants_cache, tid, status).await;
+                state.unknown_tenants.invalidate(&tid);
+                // Before the flush below, so no decision is recomputed with the old set
+                reload_policies(state, tid).await;
+            }
+            if let Some(cache) = &state.entity_cache {
+                if msg.kind != Some(ChangeKind::PolicySet) {
+                    cache.invalidate(tid, msg.entity());
+                }
+            }
+            if let Some(l1) = &state.decision_l1 {
+                l1.invalidate(tid, msg.entity());
+            }
+            let Some(redis) = &state.redis else {
+                return;
+            };
+            let entity = msg.entity();
+            let flushed = redis
+                .run_background("cache_flush", |mut conn| async move {
+                    match entity {
+                        Some(uid) => decision_cache::flush_entity(&mut conn, tid, uid).await,
+                        None => decision_cache::flush_tenant(&mut conn, tid).await,
+                    }
+                })
+                .await;
+            if let Some(n) = flushed {
+                metrics::counter!("pdp_decision_cache_flushed_total").increment(n as u64);
+                tracing::debug!(
+                    "flushed {n} cached decisions for tenant {tid} {}",
+                    entity.unwrap_or("(all)")
+                );
             }
-            Err(e) => warn!("redis pubsub connect error: {e}"),
         }
-    });
-    Ok(())
+        Event::Resync => resync_caches(state).await,
+    }
+}
+
+/// After a listener (re)connects: invalidations sent while it was down are lost, so
+/// reload cached policy sets that are no longer the active version, drop all tenant meta,
+/// cached attributes and the L1, and flush the Redis decisions of every tenant with a
+/// cached policy set. A missed entity invalidation leaves the policy version unchanged,
+/// so decisions keyed by it would otherwise live out their TTLs.
+async fn resync_caches(state: &AppState) {
+    let cache = &state.policies_cache;
+    // Loads in flight may have read a set whose invalidation was missed
+    cache.clear_generations();
+    state.tenants_cache.write().await.clear();
+    state.unknown_tenants.invalidate_all();
+    if let Some(entities) = &state.entity_cache {
+        entities.clear();
+    }
+    state.decision_generations.bump_all();
+    if let Some(l1) = &state.decision_l1 {
+        l1.clear();
+    }
+    let cached = cache.versions();
+    if let Some(redis) = &state.redis {
+        let mut flushed = 0;
+        for &(tid, _) in &cached {
+            flushed += redis
+                .run_background("cache_flush", |mut conn| async move {
+                    decision_cache::flush_tenant(&mut conn, tid).await
+                })
+                .await
+                .unwrap_or(0);
+        }
+        metrics::counter!("pdp_decision_cache_flushed_total").increment(flushed as u64);
+    }
+    let mut reloaded = 0;
+    for (tid, version) in &cached {
+        match state.policy_store.active_version(*tid).await {
+            Ok(Some(active)) if active == *version => continue,
+            Ok(_) => {
+                reload_policies(state, *tid).await;
+            }
+            Err(e) => {
+                warn!("resync: tenant {tid}: {e}, dropping its cached policy set");
+                cache.remove(*tid);
+            }
+        }
+        reloaded += 1;
+    }
+    metrics::counter!("pdp_invalidation_resyncs_total").increment(1);
+    info!(
+        "invalidation resync: reloaded {reloaded} of {} cached policy sets",
+        cached.len()
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TENANT: Uuid = Uuid::from_u128(1);
+
+    fn store(entities: Vec<EntityRecord>) -> MemoryStore {
+        let store = MemoryStore::new();
+        store.put_tenant(TENANT, MemoryTenant::new(None, entities));
+        store
+    }
+
+    fn policies(src: &[&str]) -> PolicyIndex {
+        let src = src.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        PolicyIndex::new(parse_policy_set_strings(&src).unwrap())
+    }
+
+    fn entity(uid: &str, attrs: Value, parents: &[&str]) -> EntityRecord {
+        EntityRecord {
+            uid: uid.into(),
+            attrs,
+            parents: parents.iter().map(|p| p.to_string()).collect(),
+        }
+    }
+
+    /// State over `store` without Redis, caches or credentials; audit records are captured.
+    fn app(store: MemoryStore) -> AppState {
+        let store = Arc::new(store);
+        AppState {
+            default_decision_allow: false,
+            audit: Arc::new(AuditWriter::capturing()),
+            tenant_store: store.clone(),
+            policy_store: store.clone(),
+            entity_store: store,
+            entity_cache: None,
+            bundles: None,
+            change_feed: None,
+            sidecar: None,
+            invalidation_health: None,
+            redis: None,
+            decision_l1: None,
+            decision_generations: Arc::default(),
+            policies_cache: Arc::new(PolicyCache::new()),
+            tenants_cache: Arc::default(),
+            unknown_tenants: Arc::new(unknown_tenants_cache()),
+            policy_flights: Arc::new(SingleFlight::new("policies")),
+            decision_flights: Arc::new(SingleFlight::new("decision")),
+            warm_up: Arc::default(),
+            rate_limit_rps_default: 100,
+            claims_secret: String::new(),
+            jwt: None,
+            break_glass: None,
+            admin_auth: Arc::default(),
+        }
+    }
+
+    /// An active tenant whose policy set `version` holds `src`.
+    fn tenant_with(version: i32, src: &[&str]) -> MemoryTenant {
+        let src = src.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        MemoryTenant::new(
+            Some((version, parse_policy_set_strings(&src).unwrap())),
+            vec![],
+        )
+    }
+
+    /// A policy store whose reads fail.
+    struct FailingPolicies(fn() -> StoreError);
+
+    #[async_trait::async_trait]
+    impl PolicyStore for FailingPolicies {
+        async fn active_policy_set(&self, _: Uuid) -> Result<(i32, PolicySet), StoreError> {
+            Err((self.0)())
+        }
+    }
+
+    /// Holds a store's first read open: it signals `reached` once it has read, then
+    /// waits for `release`. Later reads go straight through.
+    #[derive(Default)]
+    struct Hold {
+        held: AtomicBool,
+        reached: tokio::sync::Notify,
+        release: tokio::sync::Notify,
+    }
+
+    impl Hold {
+        async fn first_read(&self) {
+            if !self.held.swap(true, Ordering::SeqCst) {
+                self.reached.notify_one();
+                self.release.notified().await;
+            }
+        }
+    }
+
+    struct HeldPolicies(Arc<MemoryStore>, Hold);
+
+    #[async_trait::async_trait]
+    impl PolicyStore for HeldPolicies {
+        async fn active_policy_set(&self, tenant: Uuid) -> Result<(i32, PolicySet), StoreError> {
+            let read = self.0.active_policy_set(tenant).await;
+            self.1.first_read().await;
+            read
+        }
+    }
+
+    struct HeldEntities(Arc<MemoryStore>, Hold);
+
+    #[async_trait::async_trait]
+    impl EntityStore for HeldEntities {
+        async fn entities(
+            &self,
+            tenant: Uuid,
+            uids: &[&str],
+        ) -> Result<Vec<EntityRecord>, StoreError> {
+            let read = self.0.entities(tenant, uids).await;
+            self.1.first_read().await;
+            read
+        }
+    }
+
+    /// `/check` for `User::"alice"` reading `Document::"x"` in TENANT, plus `extra` headers.
+    async fn check(state: &AppState, extra: &[(&'static str, &str)]) -> (StatusCode, String) {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-tenant-id", TENANT.to_string().parse().unwrap());
+        headers.insert("x-principal", r#"User::"alice""#.parse().unwrap());
+        headers.insert("x-resource", r#"Document::"x""#.parse().unwrap());
+        headers.insert("x-action", "read".parse().unwrap());
+        for (name, value) in extra {
+            headers.insert(*name, value.parse().unwrap());
+        }
+        let (status, Json(decision)) =
+            check_impl(state.clone(), headers, Method::GET, "/docs/x").await;
+        (status, format!("{} {}", decision.decision, decision.reason))
+    }
+
+    #[tokio::test]
+    async fn evaluates_with_attributes_from_store() {
+        let pset = policies(&[r#"permit(principal, action == Action::"read", resource)
+            when { principal.department == resource.department };"#]);
+        let store = store(vec![
+            entity(r#"User::"alice""#, json!({"department": "eng"}), &[]),
+            entity(r#"Document::"spec""#, json!({"department": "eng"}), &[]),
+            entity(r#"Document::"payroll""#, json!({"department": "hr"}), &[]),
+        ]);
+        let eval = |resource: &'static str, action: &'static str| {
+            evaluate(
+                &store,
+                TENANT,
+                &pset,
+                r#"User::"alice""#,
+                resource,
+                action,
+                json!({}),
+            )
+        };
+
+        assert_eq!(
+            eval(r#"Document::"spec""#, "read").await.unwrap(),
+            Decision::Allow
+        );
+        assert_eq!(
+            eval(r#"Document::"payroll""#, "read").await.unwrap(),
+            Decision::Deny
+        );
+        assert_eq!(
+            eval(r#"Document::"spec""#, "write").await.unwrap(),
+            Decision::Deny
+        );
+    }
+
+    #[tokio::test]
+    async fn evaluates_parents_from_store() {
+        let pset = policies(&[r#"permit(principal in Group::"admins", action, resource);"#]);
+        let store = store(vec![entity(
+            r#"User::"root""#,
+            json!({}),
+            &[r#"Group::"admins""#],
+        )]);
+
+        for (principal, expected) in [
+            (r#"User::"root""#, Decision::Allow),
+            (r#"User::"bob""#, Decision::Deny),
+        ] {
+            let got = evaluate(
+                &store,
+                TENANT,
+                &pset,
+                principal,
+                r#"Document::"x""#,
+                "read",
+                json!({}),
+            )
+            .await
+            .unwrap();
+            assert_eq!(got, expected, "{principal}");
+        }
+    }
+
+    #[tokio::test]
+    async fn unknown_entities_and_tenants_have_no_attributes() {
+        let pset = policies(&[
+            r#"permit(principal, action, resource) unless { principal has department };"#,
+        ]);
+        let other_tenant = store(vec![]);
+
+        let got = evaluate(
+            &other_tenant,
+            Uuid::from_u128(2),
+            &pset,
+            r#"User::"ghost""#,
+            r#"Document::"x""#,
+            "read",
+            json!({}),
+        )
+        .await
+        .unwrap();
+        assert_eq!(got, Decision::Allow);
+    }
+
+    #[tokio::test]
+    async fn rejects_malformed_input() {
+        let pset = policies(&[]);
+        let store = store(vec![]);
+
+        let err = evaluate(
+            &store,
+            TENANT,
+            &pset,
+            "alice",
+            r#"Document::"x""#,
+            "read",
+            json!({}),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.to_string(), "invalid principal UID");
+        let err = evaluate(
+            &store,
+            TENANT,
+            &pset,
+            r#"User::"a""#,
+            r#"Document::"x""#,
+            "read",
+            json!([1]),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.to_string(), "invalid context");
+        // Would share a cache key with `Doc::"x"` / `read` if fields were concatenated
+        assert!(matches!(
+            request_uids(r#"User::"a""#, r#"Doc::"x"r"#, "ead"),
+            Err(EvalError::Resource)
+        ));
+    }
+
+    #[test]
+    fn forwarded_headers_must_match_token() {
+        let mut headers = HeaderMap::new();
+        assert!(headers_agree(&headers, TENANT, "User::\"a\"").is_ok());
+        headers.insert("x-tenant-id", TENANT.to_string().parse().unwrap());
+        headers.insert("x-principal", "User::\"a\"".parse().unwrap());
+        assert!(headers_agree(&headers, TENANT, "User::\"a\"").is_ok());
+        assert!(headers_agree(&headers, TENANT, "User::\"b\"").is_err());
+        assert!(headers_agree(&headers, Uuid::from_u128(2), "User::\"a\"").is_err());
+    }
+
+    #[tokio::test]
+    async fn x_allow_header_does_not_bypass_policies() {
+        let store = MemoryStore::new();
+        store.put_tenant(TENANT, tenant_with(1, &[]));
+        let state = app(store);
+
+        let (status, decision) = check(&state, &[("x-allow", "1")]).await;
+        assert_eq!(
+            (status, decision.as_str()),
+            (StatusCode::FORBIDDEN, "DENY cedar deny")
+        );
+        let audited = state.audit.captured();
+        assert_eq!(audited.len(), 1);
+        assert_eq!((audited[0].decision, audited[0].source), ("DENY", "cedar"));
+    }
+
+    #[tokio::test]
+    async fn break_glass_attempts_are_audited() {
+        let store = MemoryStore::new();
+        store.put_tenant(TENANT, tenant_with(1, &[]));
+        let mut state = app(store);
+
+        // Without BREAK_GLASS_SECRET the header is refused
+        let (_, decision) = check(&state, &[(BREAK_GLASS_HEADER, "x.y.z")]).await;
+        assert_eq!(decision, "DENY break-glass disabled");
+
+        state.break_glass = Some(Arc::new(BreakGlass::new(
+            b"bg-secret",
+            Some([TENANT].into()),
+            900,
+        )));
+        let now = time::OffsetDateTime::now_utc().unix_timestamp();
+        let token = |secret: &[u8]| {
+            jsonwebtoken::encode(
+                &jsonwebtoken::Header::default(),
+                &json!({
+                    "tid": TENANT.to_string(),
+                    "sub": r#"User::"alice""#,
+                    "justification": "INC-42",
+                    "iat": now,
+                    "exp": now + 60,
+                }),
+                &jsonwebtoken::EncodingKey::from_secret(secret),
+            )
+            .unwrap()
+        };
+        let (_, decision) = check(&state, &[(BREAK_GLASS_HEADER, &token(b"forged"))]).await;
+        assert!(
+            decision.starts_with("DENY break-glass rejected"),
+            "{decision}"
+        );
+        let (_, decision) = check(&state, &[(BREAK_GLASS_HEADER, &token(b"bg-secret"))]).await;
+        assert_eq!(decision, "ALLOW break-glass");
+
+        let audited = state.audit.captured();
+        let audited: Vec<_> = audited
+            .iter()
+            .map(|r| (r.decision, r.source, r.reason.as_deref().unwrap_or("")))
+            .collect();
+        assert_eq!(audited.len(), 3);
+        assert_eq!(audited[0], ("DENY", "break_glass", "break-glass disabled"));
+        assert_eq!((audited[1].0, audited[1].1), ("DENY", "break_glass"));
+        assert!(
+            audited[1].2.starts_with("break-glass rejected"),
+            "{}",
+            audited[1].2
+        );
+        assert_eq!(audited[2], ("ALLOW", "break_glass", "INC-42"));
+    }
+
+    #[tokio::test]
+    async fn only_active_tenants_get_decisions() {
+        let store = MemoryStore::new();
+        let mut suspended = tenant_with(1, &[r#"permit(principal, action, resource);"#]);
+        suspended.meta.status = TenantStatus::Suspended;
+        store.put_tenant(TENANT, suspended);
+        let state = app(store);
+
+        let (status, decision) = check(&state, &[]).await;
+        assert_eq!(
+            (status, decision.as_str()),
+            (StatusCode::FORBIDDEN, "DENY tenant suspended")
+        );
+
+        let unknown = app(MemoryStore::new());
+        let (_, decision) = check(&unknown, &[]).await;
+        assert_eq!(decision, "DENY tenant unknown");
+        assert!(state.audit.captured().is_empty());
+        assert!(unknown.audit.captured().is_empty());
+    }
+
+    /// A tenant store counting lookups.
+    struct CountingTenants(Arc<MemoryStore>, AtomicUsize);
+
+    #[async_trait::async_trait]
+    impl TenantStore for CountingTenants {
+        async fn tenant(&self, tenant: Uuid) -> Result<Option<TenantMeta>, StoreError> {
+            self.1.fetch_add(1, Ordering::SeqCst);
+            self.0.tenant(tenant).await
+        }
+
+        async fn active_tenants(&self) -> Result<Vec<Uuid>, StoreError> {
+            self.0.active_tenants().await
+        }
+    }
+
+    #[tokio::test]
+    async fn unknown_tenants_are_remembered_until_invalidated() {
+        let memory = Arc::new(MemoryStore::new());
+        let mut state = app(MemoryStore::new());
+        let tenants = Arc::new(CountingTenants(memory.clone(), AtomicUsize::new(0)));
+        state.tenant_store = tenants.clone();
+        state.policy_store = memory.clone();
+        state.entity_store = memory.clone();
+
+        for _ in 0..3 {
+            assert_eq!(check(&state, &[]).await.1, "DENY tenant unknown");
+        }
+        assert_eq!(tenants.1.load(Ordering::SeqCst), 1);
+
+        // Created and announced: served right away
+        memory.put_tenant(
+            TENANT,
+            tenant_with(1, &[r#"permit(principal, action, resource);"#]),
+        );
+        let msg = Event::Invalidate(InvalidationMsg::tenant(TENANT));
+        apply_invalidation(&state, msg).await;
+        assert_eq!(check(&state, &[]).await.1, "ALLOW cedar allow");
+        assert_eq!(tenants.1.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn store_errors_follow_the_tenant_fail_mode() {
+        for (fail_mode, expected) in [
+            (FailMode::Closed, "DENY policy load error"),
+            (FailMode::Open, "ALLOW fail-open: policy load error"),
+        ] {
+            let store = MemoryStore::new();
+            let mut tenant = tenant_with(1, &[]);
+            tenant.meta.settings.fail_mode = fail_mode;
+            store.put_tenant(TENANT, tenant);
+            let mut state = app(store);
+            state.policy_store = Arc::new(FailingPolicies(|| {
+                StoreError::Db(sqlx::Error::PoolTimedOut)
+            }));
+
+            let (_, decision) = check(&state, &[]).await;
+            assert_eq!(decision, expected, "{fail_mode:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn tenants_without_policy_set_get_the_default_decision() {
+        for (default_allow, tenant_default, expected) in [
+            (false, None, "DENY default deny (no active policy_set)"),
+            (true, None, "ALLOW default allow (no active policy_set)"),
+            (
+                true,
+                Some(DefaultDecision::Deny),
+                "DENY default deny (no active policy_set)",
+            ),
+            (
+                false,
+                Some(DefaultDecision::Allow),
+                "ALLOW default allow (no active policy_set)",
+            ),
+        ] {
+            let store = store(vec![]);
+            if let Some(d) = tenant_default {
+                let mut tenant = MemoryTenant::new(None, vec![]);
+                tenant.meta.settings.default_decision = Some(d);
+                store.put_tenant(TENANT, tenant);
+            }
+            let mut state = app(store);
+            state.default_decision_allow = default_allow;
+
+            let (_, decision) = check(&state, &[]).await;
+            assert_eq!(decision, expected, "{default_allow} {tenant_default:?}");
+            let audited = state.audit.captured();
+            assert_eq!(audited.len(), 1);
+            assert_eq!(audited[0].source, "default");
+            assert_eq!(
+                audited[0].decision,
+                &expected[..expected.find(' ').unwrap()]
+            );
+            assert_eq!(audited[0].policy_set_version, None);
+            assert_eq!(audited[0].reason.as_deref(), Some("no active policy_set"));
+        }
+    }
+
+    #[test]
+    fn rejected_reload_keeps_serving_the_previous_set() {
+        let store = MemoryStore::new();
+        store.put_tenant(TENANT, tenant_with(1, &[]));
+        let mut state = app(store);
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let metrics = recorder.handle();
+
+        metrics::with_local_recorder(&recorder, || {
+            futures::executor::block_on(async {
+                assert!(reload_policies(&state, TENANT).await);
+                state.policy_store = Arc::new(FailingPolicies(|| {
+                    StoreError::InvalidPolicy("policy0: unexpected token".into())
+                }));
+                assert!(!reload_policies(&state, TENANT).await);
+            })
+        });
+        assert_eq!(state.policies_cache.get(TENANT).unwrap().version, 1);
+        let rendered = metrics.render();
+        assert!(
+            rendered.contains(&format!(
+                "pdp_policy_reload_failing{{tenant=\"{TENANT}\"}} 1"
+            )),
+            "{rendered}"
+        );
+        assert!(
+            rendered.contains("pdp_policy_reload_failures_total 1"),
+            "{rendered}"
+        );
+    }
+
+    #[tokio::test]
+    async fn decisions_evaluated_across_an_entity_invalidation_are_not_cached() {
+        let alice =
+            |department| entity(r#"User::"alice""#, json!({ "department": department }), &[]);
+        let memory = Arc::new(MemoryStore::new());
+        let mut tenant = tenant_with(
+            1,
+            &[r#"permit(principal, action, resource) when { principal.department == "eng" };"#],
+        );
+        tenant.entities = [(alice("eng").uid.clone(), alice("eng"))].into();
+        memory.put_tenant(TENANT, tenant);
+        let mut state = app(MemoryStore::new());
+        state.tenant_store = memory.clone();
+        state.policy_store = memory.clone();
+        let held = Arc::new(HeldEntities(memory.clone(), Hold::default()));
+        state.entity_store = held.clone();
+        let ttl = Duration::from_secs(60);
+        state.decision_l1 = Some(Arc::new(decision_cache::L1::new(100, ttl, ttl)));
+
+        // Reads department=eng, then alice moves to hr and is invalidated
+        let before = tokio::spawn({
+            let state = state.clone();
+            async move { check(&state, &[]).await.1 }
+        });
+        held.1.reached.notified().await;
+        memory.put_entity(TENANT, alice("hr"));
+        let msg = InvalidationMsg {
+            uid: Some(alice("hr").uid),
+            ..InvalidationMsg::tenant(TENANT)
+        };
+        apply_invalidation(&state, Event::Invalidate(msg)).await;
+
+        // A request after the invalidation does not join the held evaluation
+        assert_eq!(check(&state, &[]).await.1, "DENY cedar deny");
+        held.1.release.notify_one();
+        assert_eq!(before.await.unwrap(), "ALLOW cedar allow");
+        // The stale allow was not written over the fresh deny
+        assert_eq!(check(&state, &[]).await.1, "DENY cache hit");
+    }
+
+    /// Against the Redis at `TEST_REDIS_URL`; skipped without it.
+    #[tokio::test]
+    async fn resync_flushes_decisions_cached_before_a_missed_invalidation() {
+        let Ok(url) = env::var("TEST_REDIS_URL") else {
+            eprintln!("TEST_REDIS_URL not set, skipping");
+            return;
+        };
+        let topology = Topology::new("standalone", &url, String::new()).unwrap();
+        let redis = Arc::new(SharedRedis::new(
+            topology,
+            Duration::from_secs(1),
+            5,
+            Duration::from_secs(1),
+        ));
+        tokio::spawn({
+            let redis = redis.clone();
+            async move { redis.connect().await }
+        });
+        let flush = || {
+            redis.run_background("cache_flush", |mut conn| async move {
+                decision_cache::flush_tenant(&mut conn, TENANT).await
+            })
+        };
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while flush().await.is_none() {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .expect("no redis connection");
+
+        let alice =
+            |department| entity(r#"User::"alice""#, json!({ "department": department }), &[]);
+        let memory = MemoryStore::new();
+        let mut tenant = tenant_with(
+            1,
+            &[r#"permit(principal, action, resource) when { principal.department == "eng" };"#],
+        );
+        tenant.entities = [(alice("eng").uid.clone(), alice("eng"))].into();
+        memory.put_tenant(TENANT, tenant);
+        let mut state = app(MemoryStore::new());
+        let memory = Arc::new(memory);
+        state.tenant_store = memory.clone();
+        state.policy_store = memory.clone();
+        state.entity_store = memory.clone();
+        state.redis = Some(redis.clone());
+
+        assert_eq!(check(&state, &[]).await.1, "ALLOW cedar allow");
+        assert_eq!(check(&state, &[]).await.1, "ALLOW cache hit");
+
+        // alice moves to hr while the listener is down: the policy version stays 1
+        memory.put_entity(TENANT, alice("hr"));
+        apply_invalidation(&state, Event::Resync).await;
+        assert_eq!(check(&state, &[]).await.1, "DENY cedar deny");
+    }
+
+    #[tokio::test]
+    async fn reloads_win_over_lazy_loads_they_overlap() {
+        let memory = Arc::new(MemoryStore::new());
+        memory.put_tenant(TENANT, tenant_with(1, &[]));
+        let mut state = app(MemoryStore::new());
+        let held = Arc::new(HeldPolicies(memory.clone(), Hold::default()));
+        state.policy_store = held.clone();
+
+        // A /check miss reads v1 and stalls; v2 is activated and reloaded meanwhile
+        let lazy = tokio::spawn({
+            let state = state.clone();
+            async move { load_policies_for_tenant(&state, TENANT).await.unwrap() }
+        });
+        held.1.reached.notified().await;
+        memory.put_tenant(TENANT, tenant_with(2, &[]));
+        assert!(reload_policies(&state, TENANT).await);
+        held.1.release.notify_one();
+
+        assert_eq!(lazy.await.unwrap().version, 1);
+        assert_eq!(state.policies_cache.get(TENANT).unwrap().version, 2);
+    }
+
+    #[tokio::test]
+    async fn not_ready_until_warm_up_finishes() {
+        let store = MemoryStore::new();
+        store.put_tenant(TENANT, tenant_with(3, &[]));
+        let state = app(store);
+
+        let (status, msg) = ready(State(state.clone())).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE, "{msg}");
+        assert!(state.policies_cache.get(TENANT).is_none());
+
+        warm_up(state.clone()).await;
+        let (status, msg) = ready(State(state.clone())).await;
+        assert_eq!(
+            (status, msg.as_str()),
+            (StatusCode::OK, "warm-up: 1 tenants")
+        );
+        assert_eq!(state.policies_cache.get(TENANT).unwrap().version, 3);
+    }
+
+    #[tokio::test]
+    async fn memory_store_without_policy_set() {
+        let store = store(vec![]);
+        assert!(matches!(
+            store.active_policy_set(TENANT).await,
+            Err(StoreError::NoActivePolicySet)
+        ));
+        assert!(store.tenant(TENANT).await.unwrap().is_some());
+        assert!(store.tenant(Uuid::from_u128(2)).await.unwrap().is_none());
+    }
 }
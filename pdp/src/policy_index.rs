@@ -0,0 +1,330 @@
+//! Per-tenant policy index: slices a policy set down to the policies whose scope
+//! can match a request's action and principal and resource entity types.
+//!
+//! Built when the set is loaded. A policy whose scope names actions
+//! (`action == A`, `action in [A, B]`) or pins an entity type
+//! (`principal == User::"x"`, `resource is Doc`) only joins the slices for those;
+//! policies with an unconstrained scope are in every slice. A policy outside its
+//! scope is never satisfied and its conditions never run, so a slice gives the
+//! same decision, reasons and errors as the full set. Slices are assembled on
+//! first use and kept with the index; unknown actions and types share one slice.
+
+use arc_swap::ArcSwap;
+use cedar_policy::{
+    ActionConstraint, EntityTypeName, EntityUid, Policy, PolicySet, PrincipalConstraint,
+    ResourceConstraint,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+/// What a policy's scope requires of a request; `None`: anything.
+struct Scope {
+    actions: Option<Vec<EntityUid>>,
+    principal: Option<EntityTypeName>,
+    resource: Option<EntityTypeName>,
+}
+
+impl Scope {
+    fn of(policy: &Policy) -> Self {
+        let actions = match policy.action_constraint() {
+            ActionConstraint::Any => None,
+            ActionConstraint::Eq(uid) => Some(vec![uid]),
+            ActionConstraint::In(uids) => Some(uids),
+        };
+        // `in` alone also matches the group's members, whatever their type
+        let principal = match policy.principal_constraint() {
+            PrincipalConstraint::Any | PrincipalConstraint::In(_) => None,
+            PrincipalConstraint::Eq(uid) => Some(uid.type_name().clone()),
+            PrincipalConstraint::Is(ty) | PrincipalConstraint::IsIn(ty, _) => Some(ty),
+        };
+        let resource = match policy.resource_constraint() {
+            ResourceConstraint::Any | ResourceConstraint::In(_) => None,
+            ResourceConstraint::Eq(uid) => Some(uid.type_name().clone()),
+            ResourceConstraint::Is(ty) | ResourceConstraint::IsIn(ty, _) => Some(ty),
+        };
+        Self {
+            actions,
+            principal,
+            resource,
+        }
+    }
+
+    fn admits(&self, key: &SliceKey) -> bool {
+        let action = match (&self.actions, &key.action) {
+            (None, _) => true,
+            (Some(actions), Some(action)) => actions.contains(action),
+            (Some(_), None) => false,
+        };
+        action
+            && (self.principal.is_none() || self.principal == key.principal)
+            && (self.resource.is_none() || self.resource == key.resource)
+    }
+}
+
+/// A request's action and types, `None` where no policy names them.
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct SliceKey {
+    action: Option<EntityUid>,
+    principal: Option<EntityTypeName>,
+    resource: Option<EntityTypeName>,
+}
+
+pub struct PolicyIndex {
+    all: Arc<PolicySet>,
+    // Empty for sets with templates, which are always evaluated whole
+    scopes: Vec<(Policy, Scope)>,
+    actions: HashSet<EntityUid>,
+    principals: HashSet<EntityTypeName>,
+    resources: HashSet<EntityTypeName>,
+    slices: ArcSwap<HashMap<SliceKey, Arc<PolicySet>>>,
+}
+
+impl PolicyIndex {
+    pub fn new(policies: PolicySet) -> Self {
+        let sliceable =
+            policies.templates().next().is_none() && policies.policies().all(Policy::is_static);
+        let scopes: Vec<_> = match sliceable {
+            true => policies
+                .policies()
+                .map(|p| (p.clone(), Scope::of(p)))
+                .collect(),
+            false => Vec::new(),
+        };
+        let (mut actions, mut principals, mut resources) =
+            (HashSet::new(), HashSet::new(), HashSet::new());
+        for (_, scope) in &scopes {
+            actions.extend(scope.actions.iter().flatten().cloned());
+            principals.extend(scope.principal.clone());
+            resources.extend(scope.resource.clone());
+        }
+        Self {
+            all: Arc::new(policies),
+            scopes,
+            actions,
+            principals,
+            resources,
+            slices: ArcSwap::default(),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn all(&self) -> &PolicySet {
+        &self.all
+    }
+
+    /// The policies that can apply to a request.
+    pub fn slice(
+        &self,
+        principal: &EntityUid,
+        action: &EntityUid,
+        resource: &EntityUid,
+    ) -> Arc<PolicySet> {
+        // An action's groups come from its entity, which is only loaded for an
+        // evaluation when the action is also the principal or the resource
+        if self.scopes.is_empty() || action == principal || action == resource {
+            return self.all.clone();
+        }
+        let key = SliceKey {
+            action: self.actions.get(action).cloned(),
+            principal: self.principals.get(principal.type_name()).cloned(),
+            resource: self.resources.get(resource.type_name()).cloned(),
+        };
+        if let Some(slice) = self.slices.load().get(&key) {
+            return slice.clone();
+        }
+
+        let mut slice = PolicySet::new();
+        for (policy, scope) in &self.scopes {
+            if scope.admits(&key) {
+                slice
+                    .add(policy.clone())
+                    .expect("static policies with ids unique in the full set");
+            }
+        }
+        let slice = Arc::new(slice);
+        self.slices.rcu(|slices| {
+            let mut slices = HashMap::clone(slices);
+            slices.insert(key.clone(), slice.clone());
+            slices
+        });
+        slice
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cedar_policy::{Authorizer, Context, Entities, Request};
+    use serde_json::{json, Value};
+    use std::str::FromStr;
+
+    /// xorshift64; fixed seeds keep failures reproducible.
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, n: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % n as u64) as usize
+        }
+
+        fn pick<'a>(&mut self, items: &[&'a str]) -> &'a str {
+            items[self.below(items.len())]
+        }
+    }
+
+    const ACTIONS: [&str; 5] = ["read", "write", "delete", "share", "all"];
+
+    fn random_policy(rng: &mut Rng, id: usize) -> Policy {
+        let effect = rng.pick(&["permit", "permit", "forbid"]);
+        let principal = rng.pick(&[
+            "principal",
+            r#"principal == User::"u1""#,
+            r#"principal == Service::"s0""#,
+            r#"principal in Group::"g0""#,
+            r#"principal in Group::"g1""#,
+            "principal is User",
+            r#"principal is Service in Group::"g1""#,
+            "principal is Group",
+        ]);
+        let action = match rng.below(4) {
+            0 => "action".to_string(),
+            1 => format!(r#"action == Action::"{}""#, rng.pick(&ACTIONS)),
+            2 => format!(
+                r#"action in [Action::"{}", Action::"{}"]"#,
+                rng.pick(&ACTIONS),
+                rng.pick(&ACTIONS)
+            ),
+            _ => r#"action in Action::"all""#.to_string(),
+        };
+        let resource = rng.pick(&[
+            "resource",
+            r#"resource == Doc::"d2""#,
+            r#"resource in Folder::"f0""#,
+            "resource is Doc",
+            "resource is Folder",
+            r#"resource is Doc in Folder::"f1""#,
+            "resource is Action",
+        ]);
+        // Some conditions fail on entities without the attribute
+        let condition = rng.pick(&[
+            "",
+            "when { context.n > 3 }",
+            "when { principal.level >= 2 }",
+            "unless { resource.public }",
+            "when { principal.level > context.n }",
+        ]);
+        let src = format!("{effect}({principal}, {action}, {resource}) {condition};");
+        Policy::parse(Some(format!("p{id}")), src).unwrap()
+    }
+
+    fn uid(ty: &str, id: &str) -> Value {
+        json!({ "type": ty, "id": id })
+    }
+
+    /// Every principal and resource a request can name, with attributes and parents.
+    fn universe() -> Vec<(EntityUid, Value)> {
+        let mut entities = Vec::new();
+        let mut add = |ty: &str, id: String, attrs: Value, parents: Vec<Value>| {
+            let euid = EntityUid::from_str(&format!(r#"{ty}::"{id}""#)).unwrap();
+            let json = json!({ "uid": uid(ty, &id), "attrs": attrs, "parents": parents });
+            entities.push((euid, json));
+        };
+        for i in 0..4 {
+            let group = uid("Group", &format!("g{}", i % 2));
+            add("User", format!("u{i}"), json!({ "level": i }), vec![group]);
+        }
+        for i in 0..2 {
+            add(
+                "Service",
+                format!("s{i}"),
+                json!({}),
+                vec![uid("Group", "g1")],
+            );
+        }
+        add("Group", "g0".into(), json!({}), vec![]);
+        for i in 0..4 {
+            let folder = uid("Folder", &format!("f{}", i % 2));
+            add(
+                "Doc",
+                format!("d{i}"),
+                json!({ "public": i == 0 }),
+                vec![folder],
+            );
+        }
+        add("Folder", "f0".into(), json!({}), vec![uid("Folder", "f1")]);
+        // An action as the resource brings its groups into the evaluation
+        for action in ACTIONS {
+            let parents = if action == "all" {
+                vec![]
+            } else {
+                vec![uid("Action", "all")]
+            };
+            add("Action", action.into(), json!({}), parents);
+        }
+        entities
+    }
+
+    #[test]
+    fn slices_decide_like_the_full_set() {
+        let universe = universe();
+        let mut sliced = 0;
+        for seed in [1, 7, 42] {
+            let mut rng = Rng(seed);
+            let mut pset = PolicySet::new();
+            for id in 0..200 {
+                pset.add(random_policy(&mut rng, id)).unwrap();
+            }
+            let index = PolicyIndex::new(pset);
+            let total = index.all().policies().count();
+
+            for _ in 0..500 {
+                let (principal, p_json) = &universe[rng.below(universe.len())];
+                let (resource, r_json) = &universe[rng.below(universe.len())];
+                let action =
+                    EntityUid::from_str(&format!(r#"Action::"{}""#, rng.pick(&ACTIONS))).unwrap();
+                // Like evaluate(): only the principal and the resource are loaded
+                let records = match principal == resource {
+                    true => json!([p_json]),
+                    false => json!([p_json, r_json]),
+                };
+                let entities = Entities::from_json_value(records, None).unwrap();
+                let context = Context::from_json_value(json!({ "n": rng.below(6) }), None).unwrap();
+                let request = Request::new(
+                    Some(principal.clone()),
+                    Some(action.clone()),
+                    Some(resource.clone()),
+                    context,
+                    None,
+                )
+                .unwrap();
+
+                let slice = index.slice(principal, &action, resource);
+                let full = Authorizer::new().is_authorized(&request, index.all(), &entities);
+                let part = Authorizer::new().is_authorized(&request, &slice, &entities);
+                let what = format!("seed {seed}: {principal} {action} {resource}");
+                assert_eq!(full.decision(), part.decision(), "{what}");
+                let reasons = |r: &cedar_policy::Response| {
+                    let mut ids: Vec<_> =
+                        r.diagnostics().reason().map(|id| id.to_string()).collect();
+                    ids.sort();
+                    ids
+                };
+                assert_eq!(reasons(&full), reasons(&part), "{what}");
+                assert_eq!(
+                    full.diagnostics().errors().count(),
+                    part.diagnostics().errors().count(),
+                    "{what}"
+                );
+                sliced += total - slice.policies().count();
+            }
+        }
+        assert!(
+            sliced > 0,
+            "no request was evaluated against a smaller slice"
+        );
+    }
+}
@@ -0,0 +1,601 @@
+//! The PDP's Redis connection, shared by the decision cache, rate limiting and
+//! invalidation flushes, behind a circuit breaker.
+//!
+//! `REDIS_MODE` picks the [`Topology`]: `standalone` (default, one `REDIS_URL`),
+//! `cluster` (`REDIS_URL` lists seed nodes) or `sentinel` (`REDIS_URL` lists the
+//! sentinels, which name the master of `REDIS_SENTINEL_MASTER`, default
+//! `mymaster`). Every key the PDP writes for a tenant carries the `{<tenant>}` hash
+//! tag, so multi-key pipelines and deletes stay in one cluster slot.
+//!
+//! One connection is opened in the background at startup: a `ConnectionManager` to
+//! the node or the sentinels' master, or a cluster connection that follows slot
+//! moves; both are multiplexed and reconnect by themselves. With sentinel, a failed
+//! call asks the sentinels again and a new master gets a new connection. `/check`
+//! calls ([`SharedRedis::run`]) get `REDIS_TIMEOUT_MS` (default 10) each. After
+//! `REDIS_BREAKER_FAILURES` (default 5) errors or timeouts in a row the breaker
+//! opens and those calls are skipped without touching Redis; after
+//! `REDIS_BREAKER_COOLDOWN_SECS` (default 5) one call goes through as a probe and
+//! closes it again if it succeeds. Until the first connection is up, calls are
+//! skipped too.
+//!
+//! `pdp_redis_breaker_state` is 0 closed, 1 half-open, 2 open; transitions are
+//! counted in `pdp_redis_breaker_transitions_total{state}` and calls in
+//! `pdp_redis_ops_total{op, result="ok"|"error"|"timeout"|"skipped"}`.
+
+use arc_swap::ArcSwapOption;
+use redis::{
+    aio::{ConnectionLike, ConnectionManager, PubSub},
+    cluster::ClusterClient,
+    cluster_async::ClusterConnection,
+    sentinel::{Sentinel, SentinelNodeConnectionInfo},
+    Cmd, ConnectionAddr, ConnectionInfo, IntoConnectionInfo, Pipeline, RedisFuture, RedisResult,
+    TlsMode, Value,
+};
+use std::{
+    env,
+    future::Future,
+    sync::{Arc, Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+use tokio::sync::Notify;
+use tracing::{info, warn};
+
+const DEFAULT_TIMEOUT_MS: u64 = 10;
+const DEFAULT_BREAKER_FAILURES: u32 = 5;
+const DEFAULT_BREAKER_COOLDOWN_SECS: u64 = 5;
+// Invalidation flushes run off the request path and may take longer
+const BACKGROUND_TIMEOUT: Duration = Duration::from_secs(2);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+const CONNECT_RETRY: Duration = Duration::from_secs(5);
+const DEFAULT_SENTINEL_MASTER: &str = "mymaster";
+
+/// Where Redis runs.
+#[derive(Clone)]
+pub enum Topology {
+    Standalone(redis::Client),
+    Cluster {
+        client: ClusterClient,
+        nodes: Vec<ConnectionInfo>,
+    },
+    /// Credentials and TLS in the sentinel URLs are used for the master too.
+    Sentinel {
+        sentinels: Vec<ConnectionInfo>,
+        master: String,
+    },
+}
+
+impl Topology {
+    /// `urls` is `REDIS_URL`; `REDIS_MODE` and `REDIS_SENTINEL_MASTER` come from the
+    /// environment.
+    pub fn from_env(urls: &str) -> anyhow::Result<Self> {
+        let mode = env::var("REDIS_MODE").unwrap_or_else(|_| "standalone".into());
+        let master =
+            env::var("REDIS_SENTINEL_MASTER").unwrap_or_else(|_| DEFAULT_SENTINEL_MASTER.into());
+        Self::new(&mode, urls, master)
+    }
+
+    pub fn new(mode: &str, urls: &str, master: String) -> anyhow::Result<Self> {
+        let nodes = urls
+            .split(',')
+            .map(str::trim)
+            .filter(|url| !url.is_empty())
+            .map(|url| url.into_connection_info())
+            .collect::<RedisResult<Vec<_>>>()?;
+        match (mode, nodes.len()) {
+            (_, 0) => anyhow::bail!("REDIS_URL lists no node"),
+            ("standalone", 1) => Ok(Self::Standalone(redis::Client::open(nodes[0].clone())?)),
+            ("standalone", _) => {
+                anyhow::bail!("REDIS_URL lists several nodes; set REDIS_MODE=cluster|sentinel")
+            }
+            ("cluster", _) => Ok(Self::Cluster {
+                client: ClusterClient::builder(nodes.clone())
+                    .connection_timeout(CONNECT_TIMEOUT)
+                    .build()?,
+                nodes,
+            }),
+            ("sentinel", _) => Ok(Self::Sentinel {
+                sentinels: nodes,
+                master,
+            }),
+            (other, _) => {
+                anyhow::bail!("unknown REDIS_MODE {other:?} (standalone|cluster|sentinel)")
+            }
+        }
+    }
+
+    /// The current master, as the sentinels see it.
+    async fn master(sentinels: &[ConnectionInfo], master: &str) -> RedisResult<redis::Client> {
+        let node = SentinelNodeConnectionInfo {
+            tls_mode: match sentinels[0].addr {
+                ConnectionAddr::TcpTls { insecure: true, .. } => Some(TlsMode::Insecure),
+                ConnectionAddr::TcpTls { .. } => Some(TlsMode::Secure),
+                _ => None,
+            },
+            redis_connection_info: Some(sentinels[0].redis.clone()),
+        };
+        Sentinel::build(sentinels.to_vec())?
+            .async_master_for(master, Some(&node))
+            .await
+    }
+
+    /// A pub/sub connection for invalidations. A cluster forwards PUBLISH to every
+    /// node, so any seed node will do; with sentinel, the master.
+    pub async fn pubsub(&self) -> RedisResult<PubSub> {
+        match self {
+            Self::Standalone(client) => client.get_async_pubsub().await,
+            Self::Cluster { nodes, .. } => {
+                let mut failed = None;
+                for node in nodes {
+                    match redis::Client::open(node.clone())?.get_async_pubsub().await {
+                        Ok(pubsub) => return Ok(pubsub),
+                        Err(e) => failed = Some(e),
+                    }
+                }
+                Err(failed.expect("a cluster has seed nodes"))
+            }
+            Self::Sentinel { sentinels, master } => {
+                Self::master(sentinels, master)
+                    .await?
+                    .get_async_pubsub()
+                    .await
+            }
+        }
+    }
+}
+
+/// A connection to the configured topology; clones share it.
+#[derive(Clone)]
+pub enum RedisConn {
+    Node(Box<ConnectionManager>),
+    Cluster(ClusterConnection),
+}
+
+impl ConnectionLike for RedisConn {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+        match self {
+            Self::Node(conn) => conn.req_packed_command(cmd),
+            Self::Cluster(conn) => conn.req_packed_command(cmd),
+        }
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        cmd: &'a Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> RedisFuture<'a, Vec<Value>> {
+        match self {
+            Self::Node(conn) => conn.req_packed_commands(cmd, offset, count),
+            Self::Cluster(conn) => conn.req_packed_commands(cmd, offset, count),
+        }
+    }
+
+    fn get_db(&self) -> i64 {
+        match self {
+            Self::Node(conn) => conn.get_db(),
+            Self::Cluster(conn) => conn.get_db(),
+        }
+    }
+}
+
+async fn manager(client: redis::Client) -> RedisResult<ConnectionManager> {
+    ConnectionManager::new_with_backoff_and_timeouts(
+        client,
+        2,
+        100,
+        3,
+        Duration::MAX,
+        CONNECT_TIMEOUT,
+    )
+    .await
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    Closed,
+    HalfOpen,
+    Open,
+}
+
+impl State {
+    fn as_str(self) -> &'static str {
+        match self {
+            State::Closed => "closed",
+            State::HalfOpen => "half_open",
+            State::Open => "open",
+        }
+    }
+}
+
+struct Breaker {
+    threshold: u32,
+    cooldown: Duration,
+    inner: Mutex<BreakerInner>,
+}
+
+struct BreakerInner {
+    state: State,
+    failures: u32,
+    // When the state was entered
+    since: Instant,
+}
+
+impl Breaker {
+    fn new(threshold: u32, cooldown: Duration) -> Self {
+        metrics::gauge!("pdp_redis_breaker_state").set(0.0);
+        Self {
+            threshold: threshold.max(1),
+            cooldown,
+            inner: Mutex::new(BreakerInner {
+                state: State::Closed,
+                failures: 0,
+                since: Instant::now(),
+            }),
+        }
+    }
+
+    /// Whether a call may go to Redis now. Past the cooldown an open breaker lets
+    /// one probe through; a probe that never reports back is replaced after another.
+    fn allow(&self) -> bool {
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        match inner.state {
+            State::Closed => true,
+            State::Open | State::HalfOpen if inner.since.elapsed() >= self.cooldown => {
+                inner.set(State::HalfOpen);
+                true
+            }
+            State::Open | State::HalfOpen => false,
+        }
+    }
+
+    fn record(&self, ok: bool) {
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        if ok {
+            inner.failures = 0;
+            if inner.state != State::Closed {
+                inner.set(State::Closed);
+                info!("Redis is back; caching and rate limiting resume");
+            }
+            return;
+        }
+        inner.failures += 1;
+        let trip = match inner.state {
+            State::Closed => inner.failures >= self.threshold,
+            State::HalfOpen => true,
+            State::Open => false,
+        };
+        if trip {
+            inner.set(State::Open);
+            warn!(
+                "Redis circuit open after {} failures; skipping caching and rate limiting for {:?}",
+                inner.failures, self.cooldown
+            );
+        }
+    }
+
+    fn state(&self) -> State {
+        self.inner
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .state
+    }
+}
+
+impl BreakerInner {
+    fn set(&mut self, state: State) {
+        if self.state != state {
+            metrics::counter!("pdp_redis_breaker_transitions_total", "state" => state.as_str())
+                .increment(1);
+        }
+        metrics::gauge!("pdp_redis_breaker_state").set(match state {
+            State::Closed => 0.0,
+            State::HalfOpen => 1.0,
+            State::Open => 2.0,
+        });
+        self.state = state;
+        self.since = Instant::now();
+    }
+}
+
+pub struct SharedRedis {
+    topology: Topology,
+    conn: ArcSwapOption<RedisConn>,
+    // Sentinel: a call failed, ask the sentinels whether the master moved
+    rediscover: Notify,
+    breaker: Breaker,
+    timeout: Duration,
+}
+
+impl SharedRedis {
+    pub fn from_env(topology: Topology) -> Self {
+        let num = |name: &str, default: u64| {
+            env::var(name)
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(default)
+        };
+        let failures = num("REDIS_BREAKER_FAILURES", DEFAULT_BREAKER_FAILURES.into());
+        Self::new(
+            topology,
+            Duration::from_millis(num("REDIS_TIMEOUT_MS", DEFAULT_TIMEOUT_MS)),
+            failures.try_into().unwrap_or(u32::MAX),
+            Duration::from_secs(num(
+                "REDIS_BREAKER_COOLDOWN_SECS",
+                DEFAULT_BREAKER_COOLDOWN_SECS,
+            )),
+        )
+    }
+
+    pub fn new(topology: Topology, timeout: Duration, failures: u32, cooldown: Duration) -> Self {
+        Self {
+            topology,
+            conn: ArcSwapOption::empty(),
+            rediscover: Notify::new(),
+            breaker: Breaker::new(failures, cooldown),
+            timeout,
+        }
+    }
+
+    /// Opens the shared connection, retrying until Redis answers. With sentinel it
+    /// keeps running and moves the connection when the master changes.
+    pub async fn connect(&self) {
+        let mut current = String::new();
+        loop {
+            let opened = match &self.topology {
+                Topology::Standalone(client) => {
+                    let addr = client.get_connection_info().addr.to_string();
+                    manager(client.clone())
+                        .await
+                        .map(|conn| Some((RedisConn::Node(Box::new(conn)), addr)))
+                }
+                Topology::Cluster { client, .. } => client
+                    .get_async_connection()
+                    .await
+                    .map(|conn| Some((RedisConn::Cluster(conn), "cluster".into()))),
+                Topology::Sentinel { sentinels, master } => {
+                    match Topology::master(sentinels, master).await {
+                        Ok(client) => {
+                            let addr = client.get_connection_info().addr.to_string();
+                            if addr == current {
+                                // Same master: the manager reconnects by itself
+                                Ok(None)
+                            } else {
+                                manager(client)
+                                    .await
+                                    .map(|conn| Some((RedisConn::Node(Box::new(conn)), addr)))
+                            }
+                        }
+                        Err(e) => Err(e),
+                    }
+                }
+            };
+            match opened {
+                Ok(Some((conn, addr))) => {
+                    self.conn.store(Some(Arc::new(conn)));
+                    info!("Redis connected ({addr})");
+                    current = addr;
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    warn!("Redis connect failed, retrying in {CONNECT_RETRY:?}: {e}");
+                    tokio::time::sleep(CONNECT_RETRY).await;
+                    continue;
+                }
+            }
+            if !matches!(self.topology, Topology::Sentinel { .. }) {
+                return;
+            }
+            self.rediscover.notified().await;
+        }
+    }
+
+    /// Runs a `/check` call within the time budget. `None` when it was skipped
+    /// (breaker open, not connected yet), failed or timed out.
+    pub async fn run<T, F, Fut>(&self, op: &'static str, call: F) -> Option<T>
+    where
+        F: FnOnce(RedisConn) -> Fut,
+        Fut: Future<Output = RedisResult<T>>,
+    {
+        match self.conn.load_full() {
+            Some(conn) if self.breaker.allow() => self.call(op, self.timeout, &conn, call).await,
+            _ => {
+                metrics::counter!("pdp_redis_ops_total", "op" => op, "result" => "skipped")
+                    .increment(1);
+                None
+            }
+        }
+    }
+
+    /// Like [`run`](Self::run) for work off the request path, which the breaker
+    /// doesn't hold back.
+    pub async fn run_background<T, F, Fut>(&self, op: &'static str, call: F) -> Option<T>
+    where
+        F: FnOnce(RedisConn) -> Fut,
+        Fut: Future<Output = RedisResult<T>>,
+    {
+        let conn = self.conn.load_full()?;
+        self.call(op, BACKGROUND_TIMEOUT, &conn, call).await
+    }
+
+    async fn call<T, F, Fut>(
+        &self,
+        op: &'static str,
+        timeout: Duration,
+        conn: &RedisConn,
+        call: F,
+    ) -> Option<T>
+    where
+        F: FnOnce(RedisConn) -> Fut,
+        Fut: Future<Output = RedisResult<T>>,
+    {
+        let (result, value) = match tokio::time::timeout(timeout, call(conn.clone())).await {
+            Ok(Ok(value)) => ("ok", Some(value)),
+            Ok(Err(e)) => {
+                warn!("redis {op} error: {e}");
+                ("error", None)
+            }
+            Err(_) => ("timeout", None),
+        };
+        self.breaker.record(value.is_some());
+        if value.is_none() {
+            // Only heard with sentinel, where connect() keeps waiting for it
+            self.rediscover.notify_one();
+        }
+        metrics::counter!("pdp_redis_ops_total", "op" => op, "result" => result).increment(1);
+        value
+    }
+
+    /// Always ready: without Redis the PDP only loses caching and rate limiting.
+    pub fn readiness(&self) -> (bool, String) {
+        let state = match (self.conn.load().as_ref(), self.breaker.state()) {
+            (None, _) => "connecting",
+            (Some(_), State::Closed) => "up",
+            (Some(_), State::HalfOpen) => "probing",
+            (Some(_), State::Open) => "down",
+        };
+        (true, format!("redis: {state}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decision_cache;
+    use futures::StreamExt;
+    use redis::AsyncCommands;
+    use uuid::Uuid;
+
+    #[test]
+    fn breaker_opens_probes_and_closes() {
+        let breaker = Breaker::new(3, Duration::from_millis(20));
+        breaker.record(false);
+        breaker.record(false);
+        breaker.record(true);
+        breaker.record(false);
+        breaker.record(false);
+        assert_eq!(breaker.state(), State::Closed);
+        breaker.record(false);
+        assert_eq!(breaker.state(), State::Open);
+        assert!(!breaker.allow());
+
+        std::thread::sleep(Duration::from_millis(25));
+        // One probe; it fails and the breaker opens again
+        assert!(breaker.allow());
+        assert!(!breaker.allow());
+        breaker.record(false);
+        assert_eq!(breaker.state(), State::Open);
+
+        std::thread::sleep(Duration::from_millis(25));
+        assert!(breaker.allow());
+        breaker.record(true);
+        assert_eq!(breaker.state(), State::Closed);
+        assert!(breaker.allow());
+    }
+
+    #[test]
+    fn parses_topologies() {
+        let single = "redis://127.0.0.1:6379";
+        let three = "redis://a:7000, redis://b:7001,redis://c:7002";
+        let master = || "mymaster".to_string();
+        assert!(matches!(
+            Topology::new("standalone", single, master()),
+            Ok(Topology::Standalone(_))
+        ));
+        assert!(Topology::new("standalone", three, master()).is_err());
+        assert!(matches!(
+            Topology::new("cluster", three, master()),
+            Ok(Topology::Cluster { nodes, .. }) if nodes.len() == 3
+        ));
+        assert!(matches!(
+            Topology::new("sentinel", "redis://s:26379", master()),
+            Ok(Topology::Sentinel { sentinels, master }) if sentinels.len() == 1 && master == "mymaster"
+        ));
+        assert!(Topology::new("cluster", " ,", master()).is_err());
+        assert!(Topology::new("replica", single, master()).is_err());
+    }
+
+    /// Caches, flushes and hears an invalidation through each topology that has a
+    /// `TEST_REDIS_*` URL; `scripts/redis-topologies.sh up` starts them locally.
+    #[tokio::test]
+    async fn topologies_cache_and_publish() {
+        let mut ran = false;
+        for (var, mode) in [
+            ("TEST_REDIS_URL", "standalone"),
+            ("TEST_REDIS_CLUSTER_URL", "cluster"),
+            ("TEST_REDIS_SENTINEL_URL", "sentinel"),
+        ] {
+            let Ok(urls) = std::env::var(var) else {
+                continue;
+            };
+            let master = std::env::var("TEST_REDIS_SENTINEL_MASTER")
+                .unwrap_or_else(|_| DEFAULT_SENTINEL_MASTER.into());
+            let topology = Topology::new(mode, &urls, master).unwrap();
+            round_trip(mode, topology).await;
+            ran = true;
+        }
+        if !ran {
+            eprintln!("TEST_REDIS_URL / TEST_REDIS_CLUSTER_URL / TEST_REDIS_SENTINEL_URL not set, skipping");
+        }
+    }
+
+    async fn round_trip(mode: &str, topology: Topology) {
+        let redis = Arc::new(SharedRedis::new(
+            topology.clone(),
+            Duration::from_secs(1),
+            5,
+            Duration::from_secs(1),
+        ));
+        // Keeps running with sentinel
+        let connecting = redis.clone();
+        let task = tokio::spawn(async move { connecting.connect().await });
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while redis.conn.load().is_none() {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .unwrap_or_else(|_| panic!("{mode}: no connection"));
+
+        // Index and entries of a tenant must land in one slot for the pipeline
+        let tenant = Uuid::new_v4();
+        let key = decision_cache::key(tenant, 1, "User::\"a\"", "Doc::\"d\"", "read", "{}");
+        let entities = ["User::\"a\"", "Doc::\"d\""];
+        let (k, e) = (key.clone(), entities);
+        let stored = redis
+            .run_background("test", |mut conn| async move {
+                decision_cache::put(&mut conn, tenant, &e, &k, "ALLOW").await?;
+                decision_cache::get(&mut conn, &k).await
+            })
+            .await;
+        assert_eq!(stored, Some(Some("ALLOW".into())), "{mode}");
+        let flushed = redis
+            .run_background("test", |mut conn| async move {
+                decision_cache::flush_tenant(&mut conn, tenant).await
+            })
+            .await;
+        assert_eq!(flushed, Some(1), "{mode}");
+        let gone = redis
+            .run_background("test", |mut conn| async move {
+                decision_cache::get(&mut conn, &key).await
+            })
+            .await;
+        assert_eq!(gone, Some(None), "{mode}");
+
+        let mut pubsub = topology.pubsub().await.unwrap();
+        let channel = format!("pdp:test:{tenant}");
+        pubsub.subscribe(&channel).await.unwrap();
+        let published = redis
+            .run_background("test", |mut conn| {
+                let channel = channel.clone();
+                async move { conn.publish::<_, _, i64>(channel, "hello").await }
+            })
+            .await;
+        assert!(published.is_some(), "{mode}");
+        let message = tokio::time::timeout(Duration::from_secs(5), pubsub.on_message().next())
+            .await
+            .unwrap_or_else(|_| panic!("{mode}: no message"))
+            .unwrap();
+        assert_eq!(message.get_payload::<String>().unwrap(), "hello", "{mode}");
+        task.abort();
+    }
+}
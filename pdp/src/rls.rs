@@ -0,0 +1,207 @@
+//! Checks that row-level security is actually enforced for the PDP's database role.
+//!
+//! `ENABLE ROW LEVEL SECURITY` does nothing for superusers, roles with `BYPASSRLS`
+//! or (without `FORCE ROW LEVEL SECURITY`) the table owner. Runs at startup
+//! (`RLS_CHECK=enforce|warn|off`, default `enforce`) and as `pdp rls-check`.
+
+use sqlx::{PgPool, Row};
+use std::{env, fmt};
+use tracing::{error, info};
+
+/// Tables whose rows are filtered by `app.tenant_id`.
+pub const TENANT_TABLES: &[&str] = &[
+    "policy_sets",
+    "policies",
+    "principals",
+    "resources",
+    "attributes",
+    "audit_logs",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RlsCheckMode {
+    Enforce,
+    Warn,
+    Off,
+}
+
+impl RlsCheckMode {
+    pub fn from_env() -> Self {
+        match env::var("RLS_CHECK").as_deref() {
+            Ok("warn") => RlsCheckMode::Warn,
+            Ok("off") => RlsCheckMode::Off,
+            _ => RlsCheckMode::Enforce,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct RlsReport {
+    pub role: String,
+    pub problems: Vec<String>,
+}
+
+impl RlsReport {
+    pub fn enforced(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+impl fmt::Display for RlsReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.enforced() {
+            return write!(
+                f,
+                "RLS enforced for role {:?} on {} tenant tables",
+                self.role,
+                TENANT_TABLES.len()
+            );
+        }
+        write!(f, "RLS NOT enforced for role {:?}:", self.role)?;
+        for p in &self.problems {
+            write!(f, "\n  - {p}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Inspects the current role and every tenant table.
+pub async fn check(db: &PgPool) -> Result<RlsReport, sqlx::Error> {
+    let row = sqlx::query(
+        "SELECT current_user::text AS role, rolsuper, rolbypassrls FROM pg_roles WHERE rolname = current_user",
+    )
+    .fetch_one(db)
+    .await?;
+    let role: String = row.try_get("role")?;
+    let mut problems = Vec::new();
+    if row.try_get::<bool, _>("rolsuper")? {
+        problems.push("role is a superuser".to_string());
+    }
+    if row.try_get::<bool, _>("rolbypassrls")? {
+        problems.push("role has BYPASSRLS".to_string());
+    }
+
+    for table in TENANT_TABLES {
+        let row = sqlx::query(
+            r#"
+            SELECT c.relrowsecurity,
+                   c.relforcerowsecurity,
+                   pg_has_role(current_user, c.relowner, 'USAGE') AS is_owner,
+                   (SELECT count(*) FROM pg_policy p WHERE p.polrelid = c.oid) AS policies
+            FROM pg_class c
+            WHERE c.oid = to_regclass($1)
+            "#,
+        )
+        .bind(table)
+        .fetch_optional(db)
+        .await?;
+        let Some(row) = row else {
+            problems.push(format!("{table}: table not found"));
+            continue;
+        };
+        if !row.try_get::<bool, _>("relrowsecurity")? {
+            problems.push(format!("{table}: row level security not enabled"));
+        }
+        if row.try_get::<i64, _>("policies")? == 0 {
+            problems.push(format!("{table}: no RLS policies"));
+        }
+        if row.try_get::<bool, _>("is_owner")? && !row.try_get::<bool, _>("relforcerowsecurity")? {
+            problems.push(format!(
+                "{table}: role owns the table and FORCE ROW LEVEL SECURITY is off"
+            ));
+        }
+    }
+
+    Ok(RlsReport { role, problems })
+}
+
+/// Startup check: fails in `enforce` mode, logs loudly in `warn` mode.
+pub async fn check_on_startup(db: &PgPool, mode: RlsCheckMode) -> anyhow::Result<()> {
+    if mode == RlsCheckMode::Off {
+        return Ok(());
+    }
+    let report = check(db).await?;
+    metrics::gauge!("pdp_rls_enforced").set(if report.enforced() { 1.0 } else { 0.0 });
+    if report.enforced() {
+        info!("{report}");
+        return Ok(());
+    }
+    match mode {
+        RlsCheckMode::Enforce => {
+            anyhow::bail!("{report}\n(set RLS_CHECK=warn to start anyway)")
+        }
+        _ => {
+            error!("TENANT ISOLATION IS NOT ENFORCED BY THE DATABASE. {report}");
+            Ok(())
+        }
+    }
+}
+
+/// Needs `TEST_DATABASE_URL` (a superuser) and `TEST_PDP_DATABASE_URL`, as in
+/// `db.rs`; skipped when either is unset.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::{postgres::PgPoolOptions, Executor};
+    use uuid::Uuid;
+
+    #[tokio::test]
+    async fn reports_roles_that_bypass_rls() {
+        let Some((superuser, _)) = crate::db::test_pools().await else {
+            return;
+        };
+        let report = check(&superuser).await.unwrap();
+        assert!(
+            report.problems.contains(&"role is a superuser".to_string()),
+            "{report}"
+        );
+        let err = check_on_startup(&superuser, RlsCheckMode::Enforce)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("RLS NOT enforced"), "{err}");
+        check_on_startup(&superuser, RlsCheckMode::Warn)
+            .await
+            .unwrap();
+        check_on_startup(&superuser, RlsCheckMode::Off)
+            .await
+            .unwrap();
+
+        // A BYPASSRLS role, taken on with SET ROLE
+        let role = format!("rls_bypass_{}", Uuid::new_v4().simple());
+        superuser
+            .execute(format!("CREATE ROLE {role} NOLOGIN BYPASSRLS").as_str())
+            .await
+            .unwrap();
+        let set_role = format!("SET ROLE {role}");
+        let bypass = PgPoolOptions::new()
+            .max_connections(1)
+            .after_connect(move |conn, _| {
+                let set_role = set_role.clone();
+                Box::pin(async move { conn.execute(set_role.as_str()).await.map(|_| ()) })
+            })
+            .connect_with((*superuser.connect_options()).clone())
+            .await
+            .unwrap();
+        let report = check(&bypass).await.unwrap();
+        let bypass_err = check_on_startup(&bypass, RlsCheckMode::Enforce).await;
+        bypass.close().await;
+        superuser
+            .execute(format!("DROP ROLE {role}").as_str())
+            .await
+            .unwrap();
+
+        assert_eq!(report.role, role);
+        assert_eq!(report.problems, vec!["role has BYPASSRLS".to_string()]);
+        assert!(bypass_err.is_err());
+    }
+
+    #[tokio::test]
+    async fn passes_for_the_pdp_role() {
+        let Some((_, pdp)) = crate::db::test_pools().await else {
+            return;
+        };
+        let report = check(&pdp).await.unwrap();
+        assert!(report.enforced(), "{report}");
+        check_on_startup(&pdp, RlsCheckMode::Enforce).await.unwrap();
+    }
+}
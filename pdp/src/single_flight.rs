@@ -0,0 +1,129 @@
+//! Request coalescing: while a call for a key is in flight, further calls for the
+//! same key wait for it and share its result instead of repeating the work.
+//!
+//! If the caller running the work is dropped (client went away), one of the waiters
+//! runs it instead. Coalesced calls are counted in
+//! `pdp_singleflight_coalesced_total{kind}`.
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    hash::Hash,
+    sync::{Arc, Mutex, PoisonError},
+};
+use tokio::sync::OnceCell;
+
+pub struct SingleFlight<K, V> {
+    kind: &'static str,
+    calls: Mutex<HashMap<K, Arc<OnceCell<V>>>>,
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> SingleFlight<K, V> {
+    /// `kind` labels the metric.
+    pub fn new(kind: &'static str) -> Self {
+        Self {
+            kind,
+            calls: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub async fn run<F, Fut>(&self, key: K, work: F) -> V
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = V>,
+    {
+        let cell = {
+            let mut calls = self.calls.lock().unwrap_or_else(PoisonError::into_inner);
+            match calls.get(&key) {
+                Some(cell) => {
+                    metrics::counter!("pdp_singleflight_coalesced_total", "kind" => self.kind)
+                        .increment(1);
+                    cell.clone()
+                }
+                None => calls.entry(key.clone()).or_default().clone(),
+            }
+        };
+        let flight = Flight {
+            group: self,
+            key,
+            cell,
+        };
+        flight.cell.get_or_init(work).await.clone()
+    }
+}
+
+/// Removes the map entry once the call is done, or when the last caller of an
+/// unfinished call is dropped, so the next call for the key starts afresh.
+struct Flight<'a, K: Hash + Eq, V> {
+    group: &'a SingleFlight<K, V>,
+    key: K,
+    cell: Arc<OnceCell<V>>,
+}
+
+impl<K: Hash + Eq, V> Drop for Flight<'_, K, V> {
+    fn drop(&mut self) {
+        let mut calls = self
+            .group
+            .calls
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let current = calls
+            .get(&self.key)
+            .is_some_and(|cell| Arc::ptr_eq(cell, &self.cell));
+        // Two references: the map's and ours, so nobody is waiting
+        if current && (self.cell.initialized() || Arc::strong_count(&self.cell) == 2) {
+            calls.remove(&self.key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    #[tokio::test]
+    async fn concurrent_calls_share_one_run() {
+        let group = SingleFlight::new("test");
+        let runs = AtomicUsize::new(0);
+        let work = || async {
+            runs.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            42
+        };
+        let results = futures::future::join_all((0..10).map(|_| group.run(1, work))).await;
+        assert_eq!(results, vec![42; 10]);
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+        // Finished calls are not reused
+        assert_eq!(group.run(1, || async { 7 }).await, 7);
+        assert!(group.calls.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_waiter_takes_over_from_a_dropped_caller() {
+        let group = SingleFlight::new("test");
+        let first = group.run(1, std::future::pending::<i32>);
+        // Polls the first call once, so it owns the flight, then drops it
+        assert!(tokio::time::timeout(Duration::from_millis(10), first)
+            .await
+            .is_err());
+        assert!(group.calls.lock().unwrap().is_empty());
+
+        let mut slow = Box::pin(group.run(2, std::future::pending::<i32>));
+        let mut waiter = Box::pin(group.run(2, || async { 5 }));
+        // Both join the flight; the waiter waits on the slow call's work. Biased so
+        // the slow call is polled first and owns the flight
+        tokio::select! {
+            biased;
+            _ = &mut slow => unreachable!(),
+            _ = &mut waiter => unreachable!(),
+            _ = tokio::time::sleep(Duration::from_millis(10)) => {}
+        }
+        drop(slow);
+        assert_eq!(waiter.await, 5);
+        assert!(group.calls.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,206 @@
+//! Attribute cache in front of an [`EntityStore`].
+//!
+//! Entries are keyed by (tenant, Cedar UID) and also remember UIDs the store does
+//! not know, so unknown principals or resources don't go back to the database.
+//! Memory is bounded by an estimate of each entry's size (`ENTITY_CACHE_MAX_MB`,
+//! default 64, `0` disables the cache); `ENTITY_CACHE_TTL_SECS` (default 300) is a
+//! backstop for missed invalidations. Entity change events drop single entries,
+//! tenant-wide ones the whole tenant.
+
+use super::{EntityRecord, EntityStore, StoreError};
+use async_trait::async_trait;
+use moka::sync::Cache;
+use std::{
+    env,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use uuid::Uuid;
+
+const DEFAULT_MAX_MB: u64 = 64;
+const DEFAULT_TTL_SECS: u64 = 300;
+// Key, map and Arc overhead per entry, on top of the UIDs and attributes
+const ENTRY_OVERHEAD: usize = 128;
+
+type Key = (Uuid, String);
+/// `None`: the store has no such entity.
+type Entry = Option<Arc<EntityRecord>>;
+
+pub struct CachedEntityStore {
+    inner: Arc<dyn EntityStore>,
+    cache: Cache<Key, Entry>,
+    // Bumped by every invalidation; a load that raced one is not cached
+    generation: AtomicU64,
+}
+
+fn weight((_, uid): &Key, entry: &Entry) -> u32 {
+    let record = entry.as_ref().map_or(0, |r| {
+        r.attrs.to_string().len() + r.parents.iter().map(String::len).sum::<usize>()
+    });
+    (ENTRY_OVERHEAD + uid.len() + record)
+        .try_into()
+        .unwrap_or(u32::MAX)
+}
+
+impl CachedEntityStore {
+    /// `None` when `ENTITY_CACHE_MAX_MB` is `0`.
+    pub fn from_env(inner: Arc<dyn EntityStore>) -> Option<Self> {
+        let num = |name: &str, default: u64| {
+            env::var(name)
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(default)
+        };
+        let max_mb = num("ENTITY_CACHE_MAX_MB", DEFAULT_MAX_MB);
+        (max_mb > 0).then(|| {
+            Self::new(
+                inner,
+                max_mb * 1024 * 1024,
+                Duration::from_secs(num("ENTITY_CACHE_TTL_SECS", DEFAULT_TTL_SECS)),
+            )
+        })
+    }
+
+    pub fn new(inner: Arc<dyn EntityStore>, max_bytes: u64, ttl: Duration) -> Self {
+        let cache = Cache::builder()
+            .max_capacity(max_bytes)
+            .weigher(weight)
+            .time_to_live(ttl)
+            .support_invalidation_closures()
+            .build();
+        Self {
+            inner,
+            cache,
+            generation: AtomicU64::new(0),
+        }
+    }
+
+    /// Drops one entity of the tenant, or with `None` all of them.
+    pub fn invalidate(&self, tenant: Uuid, uid: Option<&str>) {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        match uid {
+            Some(uid) => self.cache.invalidate(&(tenant, uid.to_string())),
+            None => {
+                // Only fails without support_invalidation_closures(), which new() sets
+                let _ = self
+                    .cache
+                    .invalidate_entries_if(move |(t, _), _| *t == tenant);
+            }
+        }
+    }
+
+    pub fn clear(&self) {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        self.cache.invalidate_all();
+    }
+}
+
+#[async_trait]
+impl EntityStore for CachedEntityStore {
+    async fn entities(&self, tenant: Uuid, uids: &[&str]) -> Result<Vec<EntityRecord>, StoreError> {
+        let mut found = Vec::with_capacity(uids.len());
+        let mut missing = Vec::new();
+        for uid in uids {
+            match self.cache.get(&(tenant, uid.to_string())) {
+                Some(entry) => found.extend(entry.map(|r| (*r).clone())),
+                None => missing.push(*uid),
+            }
+        }
+        metrics::counter!("pdp_entity_cache_hits_total")
+            .increment((uids.len() - missing.len()) as u64);
+        metrics::counter!("pdp_entity_cache_misses_total").increment(missing.len() as u64);
+        if missing.is_empty() {
+            metrics::counter!("pdp_entity_lookups_total", "source" => "cache").increment(1);
+            return Ok(found);
+        }
+        metrics::counter!("pdp_entity_lookups_total", "source" => "store").increment(1);
+
+        let generation = self.generation.load(Ordering::SeqCst);
+        let loaded = self.inner.entities(tenant, &missing).await?;
+        if self.generation.load(Ordering::SeqCst) == generation {
+            for uid in &missing {
+                let entry = loaded.iter().find(|r| r.uid == *uid).cloned().map(Arc::new);
+                self.cache.insert((tenant, uid.to_string()), entry);
+            }
+        }
+        found.extend(loaded);
+        Ok(found)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::memory::{MemoryStore, MemoryTenant};
+    use serde_json::json;
+    use std::sync::atomic::AtomicUsize;
+
+    const TENANT: Uuid = Uuid::from_u128(1);
+
+    /// Counts calls that reach the wrapped store.
+    struct Counting {
+        inner: MemoryStore,
+        calls: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl EntityStore for Counting {
+        async fn entities(
+            &self,
+            tenant: Uuid,
+            uids: &[&str],
+        ) -> Result<Vec<EntityRecord>, StoreError> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            self.inner.entities(tenant, uids).await
+        }
+    }
+
+    fn alice(department: &str) -> EntityRecord {
+        EntityRecord {
+            uid: r#"User::"alice""#.into(),
+            attrs: json!({ "department": department }),
+            parents: vec![],
+        }
+    }
+
+    #[tokio::test]
+    async fn caches_known_and_unknown_entities_until_invalidated() {
+        let memory = MemoryStore::new();
+        memory.put_tenant(TENANT, MemoryTenant::new(None, vec![alice("eng")]));
+        let counting = Arc::new(Counting {
+            inner: memory,
+            calls: AtomicUsize::new(0),
+        });
+        let store = CachedEntityStore::new(counting.clone(), 1 << 20, Duration::from_secs(60));
+        let uids = [r#"User::"alice""#, r#"Doc::"missing""#];
+        let calls = || counting.calls.load(Ordering::SeqCst);
+
+        assert_eq!(
+            store.entities(TENANT, &uids).await.unwrap(),
+            vec![alice("eng")]
+        );
+        assert_eq!(
+            store.entities(TENANT, &uids).await.unwrap(),
+            vec![alice("eng")]
+        );
+        assert_eq!(calls(), 1);
+
+        counting.inner.put_entity(TENANT, alice("hr"));
+        store.invalidate(TENANT, Some(r#"User::"alice""#));
+        assert_eq!(
+            store.entities(TENANT, &uids).await.unwrap(),
+            vec![alice("hr")]
+        );
+        assert_eq!(calls(), 2);
+        // Only alice was reloaded; the negative entry for the document stayed cached
+        store.entities(TENANT, &uids[1..]).await.unwrap();
+        assert_eq!(calls(), 2);
+
+        store.invalidate(TENANT, None);
+        store.entities(TENANT, &uids[1..]).await.unwrap();
+        assert_eq!(calls(), 3);
+    }
+}
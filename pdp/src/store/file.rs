@@ -0,0 +1,429 @@
+//! File backend for policies kept in Git (`POLICY_STORE=file`).
+//!
+//! One directory per tenant under `POLICY_DIR`:
+//!
+//! ```text
+//! $POLICY_DIR/<tenant-uuid>/
+//!   *.cedar                             policies
+//!   schema.cedarschema | schema.json    optional; policies and entities are validated against it
+//!   tenant.json                         optional {"status": "...", "settings": {...}}
+//!   *.json                              entities, Cedar JSON format (arrays, merged)
+//! ```
+//!
+//! The tree is polled every `POLICY_DIR_POLL_SECS` (default 2). A tenant whose files
+//! changed is reloaded and swapped in only if everything parses and validates; a
+//! broken edit keeps serving the last good version. A tenant without `*.cedar` files
+//! has no active policy set. Dotfiles are ignored.
+//!
+//! The version is derived from the contents of the files loaded (a truncated
+//! sha256), not counted per process: decisions are cached under it in Redis, so
+//! it must differ after an edit made while the PDP was down and agree between
+//! replicas serving the same tree.
+
+use super::{
+    memory::{MemoryStore, MemoryTenant},
+    EntityRecord, EntityStore, PolicyStore, StoreError, TenantStore,
+};
+use crate::tenant::{TenantDoc, TenantMeta};
+use anyhow::{anyhow, bail, Context};
+use async_trait::async_trait;
+use cedar_policy::{PolicyId, PolicySet, Schema};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    env, fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex, PoisonError},
+    time::{Duration, SystemTime},
+};
+use tokio::sync::mpsc::UnboundedSender;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+const DEFAULT_POLL_SECS: u64 = 2;
+
+/// Name, mtime and size of every file in a tenant directory.
+type Fingerprint = Vec<(String, Option<SystemTime>, u64)>;
+
+#[derive(Default)]
+struct Seen {
+    fingerprint: Option<Fingerprint>,
+    // Of the last successful load
+    version: Option<i32>,
+}
+
+pub struct FileStore {
+    root: PathBuf,
+    poll: Duration,
+    inner: MemoryStore,
+    seen: Mutex<HashMap<Uuid, Seen>>,
+}
+
+impl FileStore {
+    /// `POLICY_DIR` and `POLICY_DIR_POLL_SECS`.
+    pub fn from_env() -> anyhow::Result<Arc<Self>> {
+        let root =
+            env::var("POLICY_DIR").map_err(|_| anyhow!("POLICY_STORE=file needs POLICY_DIR"))?;
+        let poll = env::var("POLICY_DIR_POLL_SECS")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(DEFAULT_POLL_SECS);
+        Ok(Arc::new(Self::open(root, Duration::from_secs(poll))?))
+    }
+
+    /// Loads every tenant directory under `root`. Broken tenants are logged and left out.
+    pub fn open(root: impl Into<PathBuf>, poll: Duration) -> anyhow::Result<Self> {
+        let root = root.into();
+        if !root.is_dir() {
+            bail!("POLICY_DIR {} is not a directory", root.display());
+        }
+        let store = Self {
+            root,
+            poll,
+            inner: MemoryStore::new(),
+            seen: Mutex::new(HashMap::new()),
+        };
+        let loaded = store.rescan().len();
+        info!(
+            "file store: {loaded} tenants loaded from {}",
+            store.root.display()
+        );
+        Ok(store)
+    }
+
+    /// Reloads tenants whose files changed. Returns the tenants that were swapped or removed.
+    pub fn rescan(&self) -> Vec<Uuid> {
+        let dirs = match tenant_dirs(&self.root) {
+            Ok(d) => d,
+            Err(e) => {
+                warn!("file store: cannot list {}: {e}", self.root.display());
+                return Vec::new();
+            }
+        };
+        let mut seen = self.seen.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut changed = Vec::new();
+
+        seen.retain(|tenant, _| {
+            if dirs.contains_key(tenant) {
+                return true;
+            }
+            self.inner.remove_tenant(*tenant);
+            info!("file store: tenant {tenant} removed");
+            changed.push(*tenant);
+            false
+        });
+
+        for (tenant, dir) in dirs {
+            let entry = seen.entry(tenant).or_default();
+            let fingerprint = fingerprint(&dir);
+            if entry.fingerprint.as_ref() == Some(&fingerprint) {
+                continue;
+            }
+            // Recorded even on failure: the next edit triggers the retry.
+            entry.fingerprint = Some(fingerprint);
+            match load_tenant(&dir) {
+                Ok((version, data)) => {
+                    entry.version = Some(version);
+                    let policies = data.policies.as_ref().map(|(_, p)| p.policies().count());
+                    info!(
+                        "file store: tenant {tenant} loaded v{version} ({} policies, {} entities)",
+                        policies.unwrap_or(0),
+                        data.entities.len()
+                    );
+                    self.inner.put_tenant(tenant, data);
+                    metrics::counter!("pdp_file_store_reloads_total", "result" => "ok")
+                        .increment(1);
+                    changed.push(tenant);
+                }
+                Err(e) => {
+                    metrics::counter!("pdp_file_store_reloads_total", "result" => "error")
+                        .increment(1);
+                    match entry.version {
+                        None => warn!("file store: tenant {tenant} not loaded: {e:#}"),
+                        Some(v) => {
+                            warn!("file store: tenant {tenant} reload failed, keeping v{v}: {e:#}")
+                        }
+                    }
+                }
+            }
+        }
+        metrics::gauge!("pdp_file_store_tenants")
+            .set(seen.values().filter(|s| s.version.is_some()).count() as f64);
+        changed
+    }
+
+    /// Polls the tree and sends every tenant that was swapped or removed.
+    pub fn spawn_watch(self: &Arc<Self>, changed: UnboundedSender<Uuid>) {
+        let store = self.clone();
+        tokio::spawn(async move {
+            let mut tick = tokio::time::interval(store.poll);
+            tick.tick().await;
+            loop {
+                tick.tick().await;
+                for tenant in store.rescan() {
+                    if changed.send(tenant).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+    }
+}
+
+fn tenant_dirs(root: &Path) -> std::io::Result<HashMap<Uuid, PathBuf>> {
+    let mut dirs = HashMap::new();
+    for entry in fs::read_dir(root)? {
+        let path = entry?.path();
+        let tenant = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .and_then(|n| Uuid::parse_str(n).ok());
+        if let (Some(tenant), true) = (tenant, path.is_dir()) {
+            dirs.insert(tenant, path);
+        }
+    }
+    Ok(dirs)
+}
+
+/// Files of a tenant directory, sorted by name, dotfiles skipped.
+fn files(dir: &Path) -> Vec<(String, PathBuf)> {
+    let mut files: Vec<_> = fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|e| e.path())
+        .filter(|p| p.is_file())
+        .filter_map(|p| Some((p.file_name()?.to_str()?.to_string(), p)))
+        .filter(|(name, _)| !name.starts_with('.'))
+        .collect();
+    files.sort();
+    files
+}
+
+fn fingerprint(dir: &Path) -> Fingerprint {
+    files(dir)
+        .into_iter()
+        .map(|(name, path)| {
+            let meta = fs::metadata(&path).ok();
+            let mtime = meta.as_ref().and_then(|m| m.modified().ok());
+            (name, mtime, meta.map_or(0, |m| m.len()))
+        })
+        .collect()
+}
+
+/// The tenant and its version, a hash of every file that was read.
+fn load_tenant(dir: &Path) -> anyhow::Result<(i32, MemoryTenant)> {
+    let mut files = Vec::new();
+    for (name, path) in self::files(dir) {
+        if name.ends_with(".cedar") || name.ends_with(".json") || name == "schema.cedarschema" {
+            let text = fs::read_to_string(&path).with_context(|| format!("{name}: read failed"))?;
+            files.push((name, text));
+        }
+    }
+    // Read once, so the version is that of the contents parsed below
+    let mut h = Sha256::new();
+    for (name, text) in &files {
+        for part in [name.as_bytes(), text.as_bytes()] {
+            h.update((part.len() as u64).to_le_bytes());
+            h.update(part);
+        }
+    }
+    let digest = h.finalize();
+    let version = i32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]]) & i32::MAX;
+
+    let mut schema = None;
+    for (name, text) in &files {
+        let parsed = match name.as_str() {
+            "schema.cedarschema" => Schema::from_cedarschema_str(text)
+                .map(|(s, _warnings)| s)
+                .map_err(|e| anyhow!("{name}: {e}"))?,
+            "schema.json" => Schema::from_json_value(serde_json::from_str(text)?)
+                .map_err(|e| anyhow!("{name}: {e}"))?,
+            _ => continue,
+        };
+        if schema.replace(parsed).is_some() {
+            bail!("both schema.cedarschema and schema.json present");
+        }
+    }
+
+    let mut meta = TenantDoc::default().into_meta()?;
+    let mut pset = PolicySet::new();
+    let mut has_policies = false;
+    let mut entities = Vec::new();
+    for (name, text) in &files {
+        if name.ends_with(".cedar") {
+            has_policies = true;
+            let parsed = PolicySet::from_str(text).map_err(|e| anyhow!("{name}: {e}"))?;
+            if parsed.templates().next().is_some() {
+                bail!("{name}: templates are not supported");
+            }
+            // Parsed ids are policy0, policy1... in every file; prefix the file name.
+            for p in parsed.policies() {
+                pset.add(p.new_id(PolicyId::new(format!("{name}:{}", p.id()))))
+                    .map_err(|e| anyhow!("{name}: {e}"))?;
+            }
+        } else if name == "tenant.json" {
+            let doc: TenantDoc =
+                serde_json::from_str(text).with_context(|| format!("{name}: invalid"))?;
+            meta = doc
+                .into_meta()
+                .with_context(|| format!("{name}: invalid"))?;
+        } else if name.ends_with(".json") && name != "schema.json" {
+            match serde_json::from_str(text).with_context(|| format!("{name}: invalid JSON"))? {
+                Value::Array(items) => entities.extend(items),
+                _ => bail!("{name}: expected an array of entities"),
+            }
+        }
+    }
+
+    let tenant = MemoryTenant::validated(
+        meta,
+        has_policies.then_some((version, pset)),
+        schema.as_ref(),
+        entities,
+    )?;
+    Ok((version, tenant))
+}
+
+#[async_trait]
+impl TenantStore for FileStore {
+    async fn tenant(&self, tenant: Uuid) -> Result<Option<TenantMeta>, StoreError> {
+        self.inner.tenant(tenant).await
+    }
+
+    async fn active_tenants(&self) -> Result<Vec<Uuid>, StoreError> {
+        self.inner.active_tenants().await
+    }
+}
+
+#[async_trait]
+impl PolicyStore for FileStore {
+    async fn active_policy_set(&self, tenant: Uuid) -> Result<(i32, PolicySet), StoreError> {
+        self.inner.active_policy_set(tenant).await
+    }
+}
+
+#[async_trait]
+impl EntityStore for FileStore {
+    async fn entities(&self, tenant: Uuid, uids: &[&str]) -> Result<Vec<EntityRecord>, StoreError> {
+        self.inner.entities(tenant, uids).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TENANT: Uuid = Uuid::from_u128(7);
+
+    struct TempTree {
+        root: PathBuf,
+        writes: std::cell::Cell<u64>,
+    }
+
+    impl TempTree {
+        fn new() -> Self {
+            let root = env::temp_dir().join(format!("pdp-file-store-{}", Uuid::new_v4()));
+            fs::create_dir_all(root.join(TENANT.to_string())).unwrap();
+            Self {
+                root,
+                writes: Default::default(),
+            }
+        }
+
+        fn write(&self, name: &str, contents: &str) {
+            let path = self.root.join(TENANT.to_string()).join(name);
+            fs::write(&path, contents).unwrap();
+            // Distinct mtimes even on filesystems with coarse timestamps.
+            self.writes.set(self.writes.get() + 1);
+            fs::File::options()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(self.writes.get()))
+                .unwrap();
+        }
+    }
+
+    impl Drop for TempTree {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.root);
+        }
+    }
+
+    const SCHEMA: &str = r#"
+        entity Group;
+        entity User in [Group] { department: String };
+        entity Document;
+        action read appliesTo { principal: User, resource: Document };
+    "#;
+
+    #[tokio::test]
+    async fn loads_policies_entities_and_settings() {
+        let tree = TempTree::new();
+        tree.write("schema.cedarschema", SCHEMA);
+        tree.write(
+            "admins.cedar",
+            r#"permit(principal in Group::"admins", action == Action::"read", resource);"#,
+        );
+        tree.write(
+            "users.json",
+            r#"[{"uid": {"type": "User", "id": "root"}, "attrs": {"department": "ops"},
+                 "parents": [{"type": "Group", "id": "admins"}]}]"#,
+        );
+        tree.write("tenant.json", r#"{"settings": {"fail_mode": "open"}}"#);
+
+        let store = FileStore::open(&tree.root, Duration::from_secs(1)).unwrap();
+        let (version, pset) = store.active_policy_set(TENANT).await.unwrap();
+        // Another process (restart, replica) reading the same files agrees on it
+        let other = FileStore::open(&tree.root, Duration::from_secs(1)).unwrap();
+        assert_eq!(other.active_policy_set(TENANT).await.unwrap().0, version);
+        assert!(pset
+            .policy(&PolicyId::new("admins.cedar:policy0"))
+            .is_some());
+        let meta = store.tenant(TENANT).await.unwrap().unwrap();
+        assert_eq!(meta.settings.fail_mode, crate::tenant::FailMode::Open);
+        let users = store.entities(TENANT, &[r#"User::"root""#]).await.unwrap();
+        assert_eq!(users[0].attrs, serde_json::json!({"department": "ops"}));
+        assert_eq!(users[0].parents, [r#"Group::"admins""#]);
+    }
+
+    #[tokio::test]
+    async fn broken_edit_keeps_last_good_version() {
+        let tree = TempTree::new();
+        tree.write("schema.cedarschema", SCHEMA);
+        tree.write("a.cedar", r#"permit(principal, action, resource);"#);
+        let store = FileStore::open(&tree.root, Duration::from_secs(1)).unwrap();
+        let v1 = store.active_policy_set(TENANT).await.unwrap().0;
+
+        // Syntax error, then a policy the schema rejects.
+        tree.write("a.cedar", "permit(principal, action, resource");
+        assert!(store.rescan().is_empty());
+        tree.write(
+            "a.cedar",
+            r#"permit(principal, action, resource) when { principal.age > 3 };"#,
+        );
+        assert!(store.rescan().is_empty());
+        assert_eq!(store.active_policy_set(TENANT).await.unwrap().0, v1);
+
+        tree.write("a.cedar", r#"forbid(principal, action, resource);"#);
+        assert_eq!(store.rescan(), [TENANT]);
+        let (version, pset) = store.active_policy_set(TENANT).await.unwrap();
+        assert_ne!(version, v1);
+        // Edited while no PDP was running: a fresh load sees the new version too
+        let restarted = FileStore::open(&tree.root, Duration::from_secs(1)).unwrap();
+        assert_eq!(
+            restarted.active_policy_set(TENANT).await.unwrap().0,
+            version
+        );
+        assert!(pset
+            .policy(&PolicyId::new("a.cedar:policy0"))
+            .is_some_and(|p| p.effect() == cedar_policy::Effect::Forbid));
+
+        fs::remove_dir_all(tree.root.join(TENANT.to_string())).unwrap();
+        assert_eq!(store.rescan(), [TENANT]);
+        assert!(store.tenant(TENANT).await.unwrap().is_none());
+    }
+}
@@ -0,0 +1,184 @@
+//! In-memory backend: tenants, policy sets and entities held in a map.
+
+use super::{EntityRecord, EntityStore, PolicyStore, StoreError, TenantStore};
+use crate::tenant::{TenantMeta, TenantSettings, TenantStatus};
+use anyhow::anyhow;
+use async_trait::async_trait;
+use cedar_policy::{Entities, EntityUid, PolicySet, Schema, ValidationMode, Validator};
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    sync::{PoisonError, RwLock},
+};
+use uuid::Uuid;
+
+pub struct MemoryTenant {
+    pub meta: TenantMeta,
+    // `None` = no active policy set
+    pub policies: Option<(i32, PolicySet)>,
+    pub entities: HashMap<String, EntityRecord>,
+}
+
+impl MemoryTenant {
+    /// An active tenant with default settings.
+    pub fn new(
+        policies: Option<(i32, PolicySet)>,
+        entities: impl IntoIterator<Item = EntityRecord>,
+    ) -> Self {
+        Self {
+            meta: TenantMeta {
+                status: TenantStatus::Active,
+                settings: TenantSettings::default(),
+            },
+            policies,
+            entities: entities.into_iter().map(|e| (e.uid.clone(), e)).collect(),
+        }
+    }
+
+    /// Tenant data from an untrusted source (files, bundles): policies are checked
+    /// against `schema` when there is one, entities are Cedar JSON.
+    pub fn validated(
+        meta: TenantMeta,
+        policies: Option<(i32, PolicySet)>,
+        schema: Option<&Schema>,
+        entities: Vec<Value>,
+    ) -> anyhow::Result<Self> {
+        if let (Some(schema), Some((_, pset))) = (schema, &policies) {
+            let result = Validator::new(schema.clone()).validate(pset, ValidationMode::default());
+            if !result.validation_passed() {
+                let errors: Vec<_> = result.validation_errors().map(|e| e.to_string()).collect();
+                anyhow::bail!("policies do not validate: {}", errors.join("; "));
+            }
+        }
+        let entities = Entities::from_json_value(Value::Array(entities), schema)
+            .map_err(|e| anyhow!("entities: {e}"))?;
+        Ok(Self {
+            meta,
+            policies,
+            entities: entity_records(&entities)?
+                .into_iter()
+                .map(|e| (e.uid.clone(), e))
+                .collect(),
+        })
+    }
+}
+
+fn entity_records(entities: &Entities) -> anyhow::Result<Vec<EntityRecord>> {
+    entities
+        .iter()
+        .map(|e| {
+            let mut json = e.to_json_value()?;
+            let parents = match json["parents"].take() {
+                Value::Array(ps) => ps
+                    .into_iter()
+                    .map(|p| {
+                        EntityUid::from_json(p)
+                            .map(|uid| uid.to_string())
+                            .map_err(|err| anyhow!("{}: parent: {err}", e.uid()))
+                    })
+                    .collect::<Result<_, _>>()?,
+                _ => Vec::new(),
+            };
+            Ok(EntityRecord {
+                uid: e.uid().to_string(),
+                attrs: json["attrs"].take(),
+                parents,
+            })
+        })
+        .collect()
+}
+
+#[derive(Default)]
+pub struct MemoryStore {
+    tenants: RwLock<HashMap<Uuid, MemoryTenant>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds or replaces a tenant.
+    pub fn put_tenant(&self, tenant: Uuid, data: MemoryTenant) {
+        self.tenants
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(tenant, data);
+    }
+
+    pub fn remove_tenant(&self, tenant: Uuid) {
+        self.tenants
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&tenant);
+    }
+
+    /// Adds or replaces one entity of a loaded tenant.
+    pub fn put_entity(&self, tenant: Uuid, entity: EntityRecord) {
+        if let Some(t) = self
+            .tenants
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get_mut(&tenant)
+        {
+            t.entities.insert(entity.uid.clone(), entity);
+        }
+    }
+
+    pub fn remove_entity(&self, tenant: Uuid, uid: &str) {
+        if let Some(t) = self
+            .tenants
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get_mut(&tenant)
+        {
+            t.entities.remove(uid);
+        }
+    }
+
+    fn with_tenant<T>(&self, tenant: Uuid, f: impl FnOnce(Option<&MemoryTenant>) -> T) -> T {
+        f(self
+            .tenants
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&tenant))
+    }
+}
+
+#[async_trait]
+impl TenantStore for MemoryStore {
+    async fn tenant(&self, tenant: Uuid) -> Result<Option<TenantMeta>, StoreError> {
+        Ok(self.with_tenant(tenant, |t| t.map(|t| t.meta.clone())))
+    }
+
+    async fn active_tenants(&self) -> Result<Vec<Uuid>, StoreError> {
+        let tenants = self.tenants.read().unwrap_or_else(PoisonError::into_inner);
+        Ok(tenants
+            .iter()
+            .filter(|(_, t)| t.meta.status == TenantStatus::Active)
+            .map(|(id, _)| *id)
+            .collect())
+    }
+}
+
+#[async_trait]
+impl PolicyStore for MemoryStore {
+    async fn active_policy_set(&self, tenant: Uuid) -> Result<(i32, PolicySet), StoreError> {
+        self.with_tenant(tenant, |t| t.and_then(|t| t.policies.clone()))
+            .ok_or(StoreError::NoActivePolicySet)
+    }
+}
+
+#[async_trait]
+impl EntityStore for MemoryStore {
+    async fn entities(&self, tenant: Uuid, uids: &[&str]) -> Result<Vec<EntityRecord>, StoreError> {
+        Ok(self.with_tenant(tenant, |t| {
+            t.map(|t| {
+                uids.iter()
+                    .filter_map(|uid| t.entities.get(*uid).cloned())
+                    .collect()
+            })
+            .unwrap_or_default()
+        }))
+    }
+}
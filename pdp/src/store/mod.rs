@@ -0,0 +1,158 @@
+//! Where the PDP reads tenants, policy sets and entities from.
+//!
+//! The evaluation path only talks to these traits; [`postgres::PostgresStore`] is
+//! the production backend, [`file::FileStore`] serves policy directories kept in Git,
+//! [`bundle::BundleStore`] serves signed bundles on edge PDPs,
+//! [`sidecar::SidecarStore`] keeps a synced in-memory snapshot and
+//! [`memory::MemoryStore`] serves inline data and tests.
+
+use crate::tenant::TenantMeta;
+use async_trait::async_trait;
+use cedar_policy::PolicySet;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{env, sync::Arc};
+use thiserror::Error;
+use uuid::Uuid;
+
+pub mod bundle;
+pub mod cached;
+pub mod file;
+pub mod memory;
+pub mod postgres;
+pub mod sidecar;
+
+#[derive(Error, Debug)]
+pub enum StoreError {
+    #[error("no active policy_set")]
+    NoActivePolicySet,
+    #[error("db error: {0}")]
+    Db(#[from] sqlx::Error),
+    #[error("invalid policy: {0}")]
+    InvalidPolicy(String),
+    /// Another request's error, shared by [`crate::single_flight`]
+    #[error("{0}")]
+    Shared(Arc<StoreError>),
+}
+
+impl StoreError {
+    /// Back from a shared error; variants callers match on are rebuilt.
+    pub fn shared(e: Arc<StoreError>) -> Self {
+        match Arc::try_unwrap(e) {
+            Ok(e) => e,
+            Err(e) => match &*e {
+                StoreError::NoActivePolicySet => StoreError::NoActivePolicySet,
+                StoreError::InvalidPolicy(msg) => StoreError::InvalidPolicy(msg.clone()),
+                _ => StoreError::Shared(e),
+            },
+        }
+    }
+}
+
+/// `POLICY_STORE`: where tenants, policy sets and entities come from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    Postgres,
+    File,
+    Bundle,
+    Sidecar,
+}
+
+impl Backend {
+    pub fn from_env() -> anyhow::Result<Self> {
+        match env::var("POLICY_STORE").as_deref() {
+            Ok("postgres") | Err(_) => Ok(Backend::Postgres),
+            Ok("file") => Ok(Backend::File),
+            Ok("bundle") => Ok(Backend::Bundle),
+            Ok("sidecar") => Ok(Backend::Sidecar),
+            Ok(other) => {
+                anyhow::bail!("unknown POLICY_STORE {other:?} (postgres|file|bundle|sidecar)")
+            }
+        }
+    }
+}
+
+/// A principal or resource: Cedar UID (`User::"123"`), attributes and direct parents.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EntityRecord {
+    pub uid: String,
+    #[serde(default = "empty_attrs")]
+    pub attrs: Value,
+    #[serde(default)]
+    pub parents: Vec<String>,
+}
+
+fn empty_attrs() -> Value {
+    Value::Object(Default::default())
+}
+
+impl EntityRecord {
+    /// An entity the store knows nothing about: no attributes, no parents.
+    pub fn bare(uid: &str) -> Self {
+        Self {
+            uid: uid.to_string(),
+            attrs: empty_attrs(),
+            parents: Vec::new(),
+        }
+    }
+}
+
+/// What a change-feed entry touched.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeKind {
+    Tenant,
+    PolicySet,
+    Entity,
+}
+
+/// One entry of a tenant's change feed (`changes` table).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Change {
+    pub seq: i64,
+    pub kind: ChangeKind,
+    /// Cedar UID for [`ChangeKind::Entity`]
+    #[serde(default)]
+    pub uid: Option<String>,
+    /// The entity as of the read; `None` once it is deleted
+    #[serde(default)]
+    pub entity: Option<EntityRecord>,
+}
+
+/// Changes after a cursor, as served by `GET /admin/changes/{tenant}`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ChangeFeed {
+    /// Highest `seq` seen for the tenant; pass it as `since` next time
+    pub cursor: i64,
+    pub changes: Vec<Change>,
+}
+
+#[async_trait]
+pub trait TenantStore: Send + Sync {
+    /// `None` when the tenant does not exist.
+    async fn tenant(&self, tenant: Uuid) -> Result<Option<TenantMeta>, StoreError>;
+
+    /// Tenants with status `active`, whose policies are loaded at startup.
+    async fn active_tenants(&self) -> Result<Vec<Uuid>, StoreError>;
+}
+
+#[async_trait]
+pub trait PolicyStore: Send + Sync {
+    /// Version and policies of the tenant's active policy set.
+    async fn active_policy_set(&self, tenant: Uuid) -> Result<(i32, PolicySet), StoreError>;
+
+    /// Version of the active policy set, `None` without one; checks cached sets.
+    async fn active_version(&self, tenant: Uuid) -> Result<Option<i32>, StoreError> {
+        match self.active_policy_set(tenant).await {
+            Ok((version, _)) => Ok(Some(version)),
+            Err(StoreError::NoActivePolicySet) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[async_trait]
+pub trait EntityStore: Send + Sync {
+    /// The requested entities that exist for the tenant; unknown UIDs are left out.
+    async fn entities(&self, tenant: Uuid, uids: &[&str]) -> Result<Vec<EntityRecord>, StoreError>;
+}
@@ -0,0 +1,417 @@
+//! Postgres backend. Tenant data is read inside a [`TenantTx`] so RLS applies.
+
+use super::{
+    Change, ChangeFeed, ChangeKind, EntityRecord, EntityStore, PolicyStore, StoreError, TenantStore,
+};
+use crate::db::TenantTx;
+use crate::tenant::{TenantMeta, TenantStatus};
+use async_trait::async_trait;
+use cedar_policy::{Policy, PolicySet};
+use sqlx::{postgres::PgRow, PgPool, Row};
+use std::{collections::HashSet, env, sync::Arc, time::Duration};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+const DEFAULT_CHANGE_FEED_RETENTION_SECS: u64 = 7 * 24 * 3600;
+const CHANGE_FEED_PRUNE_EVERY: Duration = Duration::from_secs(3600);
+
+pub struct PostgresStore {
+    db: PgPool,
+}
+
+impl PostgresStore {
+    pub fn new(db: PgPool) -> Self {
+        Self { db }
+    }
+
+    /// `policy_sets.schema` (Cedar JSON schema) of one version, if set.
+    pub async fn policy_set_schema(
+        &self,
+        tenant: Uuid,
+        version: i32,
+    ) -> Result<Option<serde_json::Value>, StoreError> {
+        let mut tx = TenantTx::begin(&self.db, tenant).await?;
+        let schema = sqlx::query_scalar(
+            "SELECT schema FROM policy_sets WHERE tenant_id = $1 AND version = $2",
+        )
+        .bind(tenant)
+        .bind(version)
+        .fetch_optional(&mut *tx)
+        .await?;
+        Ok(schema.flatten())
+    }
+
+    /// Every principal and resource of the tenant, for bundles.
+    pub async fn all_entities(&self, tenant: Uuid) -> Result<Vec<EntityRecord>, StoreError> {
+        let mut tx = TenantTx::begin(&self.db, tenant).await?;
+        let rows = sqlx::query(
+            r#"
+            SELECT cedar_uid, attrs, parents, 0 AS pos FROM principals
+            UNION ALL
+            SELECT cedar_uid, attrs, parents, 1 AS pos FROM resources
+            ORDER BY cedar_uid, pos
+            "#,
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+        dedup_entities(rows)
+    }
+
+    /// The tenant's change feed after `since`, with the current state of changed
+    /// entities. Without `since` only the cursor, to start following the feed.
+    pub async fn changes(
+        &self,
+        tenant: Uuid,
+        since: Option<i64>,
+    ) -> Result<ChangeFeed, StoreError> {
+        // `changes` is not under RLS: ids only
+        let cursor: i64 =
+            sqlx::query_scalar("SELECT COALESCE(MAX(seq), 0) FROM changes WHERE tenant_id = $1")
+                .bind(tenant)
+                .fetch_one(&self.db)
+                .await?;
+        let Some(since) = since else {
+            return Ok(ChangeFeed {
+                cursor,
+                changes: Vec::new(),
+            });
+        };
+        let rows = sqlx::query(
+            r#"
+            SELECT seq, kind, entity_uid FROM changes
+            WHERE tenant_id = $1 AND seq > $2 AND seq <= $3
+            ORDER BY seq
+            "#,
+        )
+        .bind(tenant)
+        .bind(since)
+        .bind(cursor)
+        .fetch_all(&self.db)
+        .await?;
+
+        let mut changes = Vec::with_capacity(rows.len());
+        for r in rows {
+            let kind = match r.try_get::<String, _>("kind")?.as_str() {
+                "tenant" => ChangeKind::Tenant,
+                "policy_set" => ChangeKind::PolicySet,
+                "entity" => ChangeKind::Entity,
+                other => {
+                    warn!("tenant {tenant}: unknown change kind {other:?}, skipped");
+                    continue;
+                }
+            };
+            changes.push(Change {
+                seq: r.try_get("seq")?,
+                kind,
+                uid: r.try_get("entity_uid")?,
+                entity: None,
+            });
+        }
+        let mut uids: Vec<String> = changes.iter().filter_map(|c| c.uid.clone()).collect();
+        uids.sort_unstable();
+        uids.dedup();
+        if !uids.is_empty() {
+            let uids: Vec<&str> = uids.iter().map(String::as_str).collect();
+            let current = self.entities(tenant, &uids).await?;
+            for c in &mut changes {
+                c.entity = current
+                    .iter()
+                    .find(|e| Some(&e.uid) == c.uid.as_ref())
+                    .cloned();
+            }
+        }
+        Ok(ChangeFeed { cursor, changes })
+    }
+
+    /// Deletes feed rows older than `retention` through `prune_changes()` (0010),
+    /// which keeps each tenant's newest row. Returns how many were deleted.
+    pub async fn prune_changes(&self, retention: Duration) -> Result<u64, StoreError> {
+        let deleted: i64 = sqlx::query_scalar("SELECT prune_changes(make_interval(secs => $1))")
+            .bind(retention.as_secs_f64())
+            .fetch_one(&self.db)
+            .await?;
+        Ok(deleted as u64)
+    }
+
+    /// Prunes the change feed every hour down to `CHANGE_FEED_RETENTION_SECS`
+    /// (default 7 days, `0` keeps everything). Every replica may run it.
+    pub fn spawn_change_feed_pruning(self: &Arc<Self>) {
+        let retention = env::var("CHANGE_FEED_RETENTION_SECS")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(DEFAULT_CHANGE_FEED_RETENTION_SECS);
+        if retention == 0 {
+            info!("change feed pruning disabled (CHANGE_FEED_RETENTION_SECS=0)");
+            return;
+        }
+        let retention = Duration::from_secs(retention);
+        let store = self.clone();
+        tokio::spawn(async move {
+            let mut tick = tokio::time::interval(CHANGE_FEED_PRUNE_EVERY);
+            loop {
+                tick.tick().await;
+                match store.prune_changes(retention).await {
+                    Ok(deleted) => {
+                        metrics::counter!("pdp_change_feed_pruned_total").increment(deleted);
+                        if deleted > 0 {
+                            info!("change feed: pruned {deleted} rows");
+                        }
+                    }
+                    Err(e) => warn!("change feed pruning failed: {e}"),
+                }
+            }
+        });
+    }
+}
+
+/// Same UID as principal and resource: the principal row wins. Rows must be
+/// ordered by `cedar_uid, pos`.
+fn dedup_entities(rows: Vec<PgRow>) -> Result<Vec<EntityRecord>, StoreError> {
+    let mut seen = HashSet::with_capacity(rows.len());
+    let mut out: Vec<EntityRecord> = Vec::with_capacity(rows.len());
+    for r in rows {
+        let uid: String = r.try_get("cedar_uid")?;
+        if !seen.insert(uid.clone()) {
+            continue;
+        }
+        out.push(EntityRecord {
+            uid,
+            attrs: r.try_get("attrs")?,
+            parents: r.try_get("parents")?,
+        });
+    }
+    Ok(out)
+}
+
+#[async_trait]
+impl TenantStore for PostgresStore {
+    async fn tenant(&self, tenant: Uuid) -> Result<Option<TenantMeta>, StoreError> {
+        // `tenants` is not under RLS
+        let row = sqlx::query("SELECT status, settings FROM tenants WHERE id = $1")
+            .bind(tenant)
+            .fetch_optional(&self.db)
+            .await?;
+        let Some(row) = row else {
+            return Ok(None);
+        };
+
+        let status_raw: String = row.try_get("status")?;
+        let status = status_raw.parse().unwrap_or_else(|_| {
+            // Unknown states never get decisions.
+            warn!("tenant {tenant}: unknown status {status_raw:?}, treating as suspended");
+            TenantStatus::Suspended
+        });
+        let settings = row
+            .try_get::<serde_json::Value, _>("settings")
+            .ok()
+            .and_then(|v| match serde_json::from_value(v) {
+                Ok(s) => Some(s),
+                Err(e) => {
+                    warn!("tenant {tenant}: invalid settings, using defaults: {e}");
+                    None
+                }
+            })
+            .unwrap_or_default();
+
+        Ok(Some(TenantMeta { status, settings }))
+    }
+
+    async fn active_tenants(&self) -> Result<Vec<Uuid>, StoreError> {
+        Ok(
+            sqlx::query_scalar("SELECT id FROM tenants WHERE status = 'active' ORDER BY id")
+                .fetch_all(&self.db)
+                .await?,
+        )
+    }
+}
+
+#[async_trait]
+impl PolicyStore for PostgresStore {
+    async fn active_policy_set(&self, tenant: Uuid) -> Result<(i32, PolicySet), StoreError> {
+        let mut tx = TenantTx::begin(&self.db, tenant).await?;
+
+        // versión activa
+        let version = active_version(&mut tx, tenant)
+            .await?
+            .ok_or(StoreError::NoActivePolicySet)?;
+
+        // políticas de esa versión
+        let rows = sqlx::query(
+            r#"
+            SELECT p.cedar
+            FROM policies p
+            JOIN policy_sets ps ON p.policy_set_id = ps.id
+            WHERE ps.tenant_id = $1 AND ps.version = $2
+            ORDER BY p.created_at, p.id
+            "#,
+        )
+        .bind(tenant)
+        .bind(version)
+        .fetch_all(&mut *tx)
+        .await?;
+
+        let mut pset = PolicySet::new();
+        for (idx, r) in rows.iter().enumerate() {
+            let cedar_text: String = r.try_get("cedar")?;
+            let pol = Policy::parse(Some(format!("p{}", idx)), &cedar_text)
+                .map_err(|e| StoreError::InvalidPolicy(format!("{e:?}")))?;
+            pset.add(pol)
+                .map_err(|e| StoreError::InvalidPolicy(format!("{e:?}")))?;
+        }
+        Ok((version, pset))
+    }
+
+    async fn active_version(&self, tenant: Uuid) -> Result<Option<i32>, StoreError> {
+        let mut tx = TenantTx::begin(&self.db, tenant).await?;
+        active_version(&mut tx, tenant).await
+    }
+}
+
+async fn active_version(tx: &mut TenantTx, tenant: Uuid) -> Result<Option<i32>, StoreError> {
+    let version = sqlx::query_scalar(
+        r#"
+        SELECT ps.version
+        FROM policy_sets ps
+        WHERE ps.tenant_id = $1 AND ps.status='active'
+        ORDER BY ps.version DESC
+        LIMIT 1
+        "#,
+    )
+    .bind(tenant)
+    .fetch_optional(&mut **tx)
+    .await?;
+    Ok(version)
+}
+
+#[async_trait]
+impl EntityStore for PostgresStore {
+    async fn entities(&self, tenant: Uuid, uids: &[&str]) -> Result<Vec<EntityRecord>, StoreError> {
+        let mut tx = TenantTx::begin(&self.db, tenant).await?;
+        // RLS scopes the lookup as well; the tenant filter lets it use the
+        // (tenant_id, cedar_uid) indexes.
+        let rows = sqlx::query(
+            r#"
+            SELECT cedar_uid, attrs, parents, 0 AS pos FROM principals
+            WHERE tenant_id = $2 AND cedar_uid = ANY($1)
+            UNION ALL
+            SELECT cedar_uid, attrs, parents, 1 AS pos FROM resources
+            WHERE tenant_id = $2 AND cedar_uid = ANY($1)
+            ORDER BY cedar_uid, pos
+            "#,
+        )
+        .bind(uids)
+        .bind(tenant)
+        .fetch_all(&mut *tx)
+        .await?;
+
+        dedup_entities(rows)
+    }
+}
+
+/// Needs `TEST_DATABASE_URL` (setup) and `TEST_PDP_DATABASE_URL` (reads, under
+/// RLS); skipped when either is unset.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn principal_row_wins_over_resource_with_same_uid() {
+        let Some((owner, pdp)) = crate::db::test_pools().await else {
+            return;
+        };
+        let store = PostgresStore::new(pdp);
+        let tenant = Uuid::new_v4();
+        sqlx::query("INSERT INTO tenants (id, name) VALUES ($1, 'entities-test')")
+            .bind(tenant)
+            .execute(&owner)
+            .await
+            .unwrap();
+        for (table, uid, from) in [
+            ("resources", r#"Svc::"api""#, "resource"),
+            ("principals", r#"Svc::"api""#, "principal"),
+            ("resources", r#"Doc::"a""#, "resource"),
+        ] {
+            sqlx::query(&format!(
+                "INSERT INTO {table} (tenant_id, cedar_uid, attrs) VALUES ($1, $2, $3)"
+            ))
+            .bind(tenant)
+            .bind(uid)
+            .bind(json!({ "from": from }))
+            .execute(&owner)
+            .await
+            .unwrap();
+        }
+
+        let got = store
+            .entities(
+                tenant,
+                &[r#"Svc::"api""#, r#"Doc::"a""#, r#"Doc::"missing""#],
+            )
+            .await;
+        sqlx::query("DELETE FROM tenants WHERE id = $1")
+            .bind(tenant)
+            .execute(&owner)
+            .await
+            .unwrap();
+
+        let got: Vec<_> = got
+            .unwrap()
+            .into_iter()
+            .map(|e| (e.uid, e.attrs["from"].clone()))
+            .collect();
+        assert_eq!(
+            got,
+            [
+                (r#"Doc::"a""#.to_string(), json!("resource")),
+                (r#"Svc::"api""#.to_string(), json!("principal")),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn pruning_keeps_each_tenants_newest_change() {
+        let Some((owner, pdp)) = crate::db::test_pools().await else {
+            return;
+        };
+        let store = PostgresStore::new(pdp);
+        let (old, idle) = (Uuid::new_v4(), Uuid::new_v4());
+        // `old`: two changes ten days ago and one now; `idle`: one ten days ago
+        for (tenant, age) in [
+            (old, "10 days"),
+            (old, "10 days"),
+            (old, "0"),
+            (idle, "10 days"),
+        ] {
+            sqlx::query(
+                "INSERT INTO changes (tenant_id, kind, ts) VALUES ($1, 'tenant', now() - $2::interval)",
+            )
+            .bind(tenant)
+            .bind(age)
+            .execute(&owner)
+            .await
+            .unwrap();
+        }
+        let before = store.changes(old, None).await.unwrap().cursor;
+
+        store
+            .prune_changes(Duration::from_secs(7 * 24 * 3600))
+            .await
+            .unwrap();
+        let left = |tenant: Uuid| {
+            sqlx::query_scalar::<_, i64>("SELECT count(*) FROM changes WHERE tenant_id = $1")
+                .bind(tenant)
+                .fetch_one(&owner)
+        };
+        let (old_left, idle_left) = (left(old).await.unwrap(), left(idle).await.unwrap());
+        let after = store.changes(old, None).await.unwrap().cursor;
+        sqlx::query("DELETE FROM changes WHERE tenant_id = ANY($1)")
+            .bind(vec![old, idle])
+            .execute(&owner)
+            .await
+            .unwrap();
+
+        assert_eq!((old_left, idle_left), (1, 1));
+        assert_eq!(after, before);
+    }
+}
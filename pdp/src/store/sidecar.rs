@@ -0,0 +1,578 @@
+//! Sidecar backend (`POLICY_STORE=sidecar`): a full in-memory snapshot of the
+//! tenants in `SIDECAR_TENANTS`, so `/check` never leaves the process.
+//!
+//! Each tenant starts from a snapshot and then follows its change feed every
+//! `SIDECAR_POLL_SECS` (default 1): changed entities are patched in place, tenant
+//! or policy set changes take a new snapshot, and a full resync runs every
+//! `SIDECAR_RESYNC_SECS` (default 300) anyway. The upstream is the database
+//! (`DATABASE_URL`) or, with `SIDECAR_CENTRAL_URL`, a central PDP's
+//! `/admin/changes/{tenant}` and signed `/admin/bundles/{tenant}`
+//! (`BUNDLE_PUBLIC_KEYS`; `SIDECAR_API_KEY` is sent as `x-api-key`).
+//!
+//! Staleness is the time since a tenant last synced. Past `SIDECAR_MAX_STALENESS_SECS`
+//! (default 30) `SIDECAR_STALE_MODE` decides: `fail-closed` (default) denies,
+//! `serve-stale` keeps answering from the snapshot.
+
+use super::{
+    memory::{MemoryStore, MemoryTenant},
+    postgres::PostgresStore,
+    ChangeFeed, ChangeKind, EntityRecord, EntityStore, PolicyStore, StoreError, TenantStore,
+};
+use crate::admin_auth::API_KEY_HEADER;
+use crate::bundle::{self, Bundle};
+use crate::tenant::TenantMeta;
+use anyhow::{bail, Context};
+use async_trait::async_trait;
+use cedar_policy::PolicySet;
+use reqwest::StatusCode;
+use serde_json::Value;
+use sqlx::PgPool;
+use std::{
+    collections::HashMap,
+    env,
+    sync::{Arc, Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+use tokio::sync::mpsc::UnboundedSender;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+const DEFAULT_POLL_SECS: u64 = 1;
+const DEFAULT_RESYNC_SECS: u64 = 300;
+const DEFAULT_MAX_STALENESS_SECS: u64 = 30;
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where a sidecar syncs from.
+#[async_trait]
+pub trait Upstream: Send + Sync {
+    /// Full state of the tenant; `None` when it does not exist.
+    async fn snapshot(&self, tenant: Uuid) -> anyhow::Result<Option<MemoryTenant>>;
+    /// Feed entries after `since`; without `since` only the current cursor.
+    async fn changes(&self, tenant: Uuid, since: Option<i64>) -> anyhow::Result<ChangeFeed>;
+}
+
+#[async_trait]
+impl Upstream for PostgresStore {
+    async fn snapshot(&self, tenant: Uuid) -> anyhow::Result<Option<MemoryTenant>> {
+        let Some(meta) = self.tenant(tenant).await? else {
+            return Ok(None);
+        };
+        let policies = match self.active_policy_set(tenant).await {
+            Ok(p) => Some(p),
+            Err(StoreError::NoActivePolicySet) => None,
+            Err(e) => return Err(e.into()),
+        };
+        let mut data = MemoryTenant::new(policies, self.all_entities(tenant).await?);
+        data.meta = meta;
+        Ok(Some(data))
+    }
+
+    async fn changes(&self, tenant: Uuid, since: Option<i64>) -> anyhow::Result<ChangeFeed> {
+        Ok(PostgresStore::changes(self, tenant, since).await?)
+    }
+}
+
+/// A central PDP: feed from `/admin/changes`, snapshots from signed `/admin/bundles`.
+pub struct Central {
+    base_url: String,
+    api_key: Option<String>,
+    keys: Vec<Vec<u8>>,
+    http: reqwest::Client,
+}
+
+impl Central {
+    fn get(&self, path: &str) -> reqwest::RequestBuilder {
+        let req = self.http.get(format!("{}{path}", self.base_url));
+        match &self.api_key {
+            Some(key) => req.header(API_KEY_HEADER, key),
+            None => req,
+        }
+    }
+}
+
+#[async_trait]
+impl Upstream for Central {
+    async fn snapshot(&self, tenant: Uuid) -> anyhow::Result<Option<MemoryTenant>> {
+        let resp = self.get(&format!("/admin/bundles/{tenant}")).send().await?;
+        if resp.status() == StatusCode::NOT_FOUND {
+            // Also 404 when the central PDP has no signing key: only trust "tenant unknown".
+            let body: Value = resp.json().await.unwrap_or_default();
+            if body["reason"] == "tenant unknown" {
+                return Ok(None);
+            }
+            bail!("bundle: 404 {body}");
+        }
+        let bundle: Bundle = resp
+            .error_for_status()?
+            .json()
+            .await
+            .context("not a bundle")?;
+        let (manifest, payload) = bundle::verify(&bundle, &self.keys)?;
+        if manifest.tenant_id != tenant {
+            bail!("bundle is for tenant {}", manifest.tenant_id);
+        }
+        Ok(Some(payload.into_tenant(manifest.policy_set_version)?))
+    }
+
+    async fn changes(&self, tenant: Uuid, since: Option<i64>) -> anyhow::Result<ChangeFeed> {
+        let mut req = self.get(&format!("/admin/changes/{tenant}"));
+        if let Some(since) = since {
+            req = req.query(&[("since", since)]);
+        }
+        Ok(req.send().await?.error_for_status()?.json().await?)
+    }
+}
+
+/// `SIDECAR_STALE_MODE`: what `/check` does once the snapshot is too old.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StaleMode {
+    FailClosed,
+    ServeStale,
+}
+
+#[derive(Default)]
+struct SyncState {
+    cursor: i64,
+    synced_at: Option<Instant>,
+    snapshot_at: Option<Instant>,
+    failing: bool,
+}
+
+pub struct SidecarStore {
+    tenants: Vec<Uuid>,
+    upstream: Box<dyn Upstream>,
+    inner: MemoryStore,
+    sync: Mutex<HashMap<Uuid, SyncState>>,
+    poll: Duration,
+    resync: Duration,
+    max_staleness: Duration,
+    stale_mode: StaleMode,
+}
+
+fn secs_from_env(name: &str, default: u64) -> Duration {
+    Duration::from_secs(
+        env::var(name)
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(default),
+    )
+}
+
+impl SidecarStore {
+    /// `SIDECAR_*` settings (see the module docs); `db` is the upstream unless
+    /// `SIDECAR_CENTRAL_URL` is set. Syncs every tenant once before returning;
+    /// tenants that fail are retried on the next poll.
+    pub async fn from_env(db: Option<PgPool>) -> anyhow::Result<Arc<Self>> {
+        let tenants = env::var("SIDECAR_TENANTS")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(|s| Uuid::parse_str(s).with_context(|| format!("SIDECAR_TENANTS: {s:?}")))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        if tenants.is_empty() {
+            bail!("POLICY_STORE=sidecar needs SIDECAR_TENANTS");
+        }
+        let upstream: Box<dyn Upstream> = match env::var("SIDECAR_CENTRAL_URL") {
+            Ok(url) => {
+                let keys =
+                    bundle::parse_public_keys(&env::var("BUNDLE_PUBLIC_KEYS").unwrap_or_default())?;
+                if keys.is_empty() {
+                    bail!("SIDECAR_CENTRAL_URL needs BUNDLE_PUBLIC_KEYS");
+                }
+                info!("sidecar: syncing from central PDP {url}");
+                Box::new(Central {
+                    base_url: url.trim_end_matches('/').to_string(),
+                    api_key: env::var("SIDECAR_API_KEY").ok(),
+                    keys,
+                    http: reqwest::Client::builder().timeout(FETCH_TIMEOUT).build()?,
+                })
+            }
+            Err(_) => {
+                let db = db.ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "POLICY_STORE=sidecar needs DATABASE_URL or SIDECAR_CENTRAL_URL"
+                    )
+                })?;
+                info!("sidecar: syncing from the database");
+                Box::new(PostgresStore::new(db))
+            }
+        };
+        let stale_mode = match env::var("SIDECAR_STALE_MODE").as_deref() {
+            Ok("fail-closed") | Err(_) => StaleMode::FailClosed,
+            Ok("serve-stale") => StaleMode::ServeStale,
+            Ok(other) => {
+                bail!("unknown SIDECAR_STALE_MODE {other:?} (fail-closed|serve-stale)")
+            }
+        };
+
+        let store = Arc::new(Self::new(
+            tenants,
+            upstream,
+            secs_from_env("SIDECAR_POLL_SECS", DEFAULT_POLL_SECS),
+            secs_from_env("SIDECAR_RESYNC_SECS", DEFAULT_RESYNC_SECS),
+            secs_from_env("SIDECAR_MAX_STALENESS_SECS", DEFAULT_MAX_STALENESS_SECS),
+            stale_mode,
+        ));
+        store.sync_all().await;
+        let synced = store
+            .tenants
+            .iter()
+            .filter(|t| store.staleness(**t).is_some())
+            .count();
+        info!(
+            "sidecar store: {synced} of {} tenants synced",
+            store.tenants.len()
+        );
+        Ok(store)
+    }
+
+    pub fn new(
+        tenants: Vec<Uuid>,
+        upstream: Box<dyn Upstream>,
+        poll: Duration,
+        resync: Duration,
+        max_staleness: Duration,
+        stale_mode: StaleMode,
+    ) -> Self {
+        Self {
+            tenants,
+            upstream,
+            inner: MemoryStore::new(),
+            sync: Mutex::new(HashMap::new()),
+            poll,
+            resync,
+            max_staleness,
+            stale_mode,
+        }
+    }
+
+    /// Syncs every tenant once. Returns the tenants whose data changed.
+    pub async fn sync_all(&self) -> Vec<Uuid> {
+        let mut changed = Vec::new();
+        for &tenant in &self.tenants {
+            let result = self.sync_tenant(tenant).await;
+            let mut sync = self.sync.lock().unwrap_or_else(PoisonError::into_inner);
+            let state = sync.entry(tenant).or_default();
+            match result {
+                Ok(true) => changed.push(tenant),
+                Ok(false) => {}
+                Err(e) => {
+                    metrics::counter!("pdp_sidecar_syncs_total", "result" => "error").increment(1);
+                    // Once per outage; staleness shows how long it lasts.
+                    if !state.failing {
+                        warn!("sidecar: tenant {tenant}: sync failed, keeping the last snapshot: {e:#}");
+                    }
+                    state.failing = true;
+                    continue;
+                }
+            }
+            metrics::counter!("pdp_sidecar_syncs_total", "result" => "ok").increment(1);
+            if state.failing {
+                info!("sidecar: tenant {tenant}: sync recovered");
+            }
+            state.failing = false;
+        }
+        for &tenant in &self.tenants {
+            let secs = self
+                .staleness(tenant)
+                .map_or(f64::INFINITY, |d| d.as_secs_f64());
+            metrics::gauge!("pdp_sidecar_staleness_seconds", "tenant" => tenant.to_string())
+                .set(secs);
+        }
+        changed
+    }
+
+    /// Applies the tenant's new feed entries, or takes a snapshot when they touch
+    /// more than entities or a resync is due. `true` when anything changed.
+    async fn sync_tenant(&self, tenant: Uuid) -> anyhow::Result<bool> {
+        let (cursor, snapshot_due) = {
+            let sync = self.sync.lock().unwrap_or_else(PoisonError::into_inner);
+            match sync.get(&tenant) {
+                Some(s) => (
+                    s.cursor,
+                    s.snapshot_at.is_none_or(|at| at.elapsed() >= self.resync),
+                ),
+                None => (0, true),
+            }
+        };
+        if !snapshot_due {
+            let feed = self.upstream.changes(tenant, Some(cursor)).await?;
+            if feed.changes.iter().all(|c| c.kind == ChangeKind::Entity) {
+                for c in &feed.changes {
+                    match (&c.entity, &c.uid) {
+                        (Some(e), _) => self.inner.put_entity(tenant, e.clone()),
+                        (None, Some(uid)) => self.inner.remove_entity(tenant, uid),
+                        (None, None) => {}
+                    }
+                }
+                self.mark_synced(tenant, feed.cursor, false);
+                return Ok(!feed.changes.is_empty());
+            }
+        }
+
+        // Cursor first: whatever changes during the snapshot is applied again next poll.
+        let cursor = self.upstream.changes(tenant, None).await?.cursor;
+        match self.upstream.snapshot(tenant).await? {
+            Some(data) => self.inner.put_tenant(tenant, data),
+            None => self.inner.remove_tenant(tenant),
+        }
+        metrics::counter!("pdp_sidecar_snapshots_total").increment(1);
+        self.mark_synced(tenant, cursor, true);
+        Ok(true)
+    }
+
+    fn mark_synced(&self, tenant: Uuid, cursor: i64, snapshot: bool) {
+        let mut sync = self.sync.lock().unwrap_or_else(PoisonError::into_inner);
+        let state = sync.entry(tenant).or_default();
+        let now = Instant::now();
+        state.cursor = cursor;
+        state.synced_at = Some(now);
+        if snapshot {
+            state.snapshot_at = Some(now);
+        }
+    }
+
+    /// Polls the upstream and sends every tenant whose data changed.
+    pub fn spawn_watch(self: &Arc<Self>, changed: UnboundedSender<Uuid>) {
+        let store = self.clone();
+        tokio::spawn(async move {
+            let mut tick = tokio::time::interval(store.poll);
+            tick.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            tick.tick().await;
+            loop {
+                tick.tick().await;
+                for tenant in store.sync_all().await {
+                    if changed.send(tenant).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+    }
+
+    /// Time since the tenant last synced; `None` before its first sync.
+    pub fn staleness(&self, tenant: Uuid) -> Option<Duration> {
+        self.sync
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&tenant)
+            .and_then(|s| s.synced_at)
+            .map(|at| at.elapsed())
+    }
+
+    /// The reason `/check` must deny for this tenant, if any. Tenants the sidecar
+    /// does not serve pass through and are unknown to the store.
+    pub fn check_fresh(&self, tenant: Uuid) -> Result<(), String> {
+        if !self.tenants.contains(&tenant) {
+            return Ok(());
+        }
+        match self.staleness(tenant) {
+            None => Err("snapshot not synced".into()),
+            Some(age) if age > self.max_staleness => match self.stale_mode {
+                StaleMode::FailClosed => Err(format!("snapshot stale ({}s)", age.as_secs())),
+                StaleMode::ServeStale => {
+                    metrics::counter!("pdp_sidecar_stale_served_total").increment(1);
+                    Ok(())
+                }
+            },
+            Some(_) => Ok(()),
+        }
+    }
+
+    /// For `/ready`: whether to take traffic, and the worst staleness across tenants.
+    pub fn readiness(&self) -> (bool, String) {
+        let mut worst = Duration::ZERO;
+        for &tenant in &self.tenants {
+            match self.staleness(tenant) {
+                Some(age) => worst = worst.max(age),
+                None => return (false, format!("not synced: tenant {tenant}")),
+            }
+        }
+        let summary = format!(
+            "staleness {:.1}s, max {}s",
+            worst.as_secs_f64(),
+            self.max_staleness.as_secs()
+        );
+        if worst <= self.max_staleness {
+            (true, format!("ok ({summary})"))
+        } else if self.stale_mode == StaleMode::ServeStale {
+            (true, format!("stale, serving ({summary})"))
+        } else {
+            (false, format!("stale ({summary})"))
+        }
+    }
+}
+
+#[async_trait]
+impl TenantStore for SidecarStore {
+    async fn tenant(&self, tenant: Uuid) -> Result<Option<TenantMeta>, StoreError> {
+        self.inner.tenant(tenant).await
+    }
+
+    async fn active_tenants(&self) -> Result<Vec<Uuid>, StoreError> {
+        self.inner.active_tenants().await
+    }
+}
+
+#[async_trait]
+impl PolicyStore for SidecarStore {
+    async fn active_policy_set(&self, tenant: Uuid) -> Result<(i32, PolicySet), StoreError> {
+        self.inner.active_policy_set(tenant).await
+    }
+}
+
+#[async_trait]
+impl EntityStore for SidecarStore {
+    async fn entities(&self, tenant: Uuid, uids: &[&str]) -> Result<Vec<EntityRecord>, StoreError> {
+        self.inner.entities(tenant, uids).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::Change;
+    use serde_json::json;
+    use std::str::FromStr;
+
+    const TENANT: Uuid = Uuid::from_u128(9);
+
+    #[derive(Default)]
+    struct Fake {
+        policy_version: i32,
+        entities: HashMap<String, EntityRecord>,
+        feed: Vec<Change>,
+        down: bool,
+        snapshots: usize,
+    }
+
+    impl Fake {
+        fn write(&mut self, kind: ChangeKind, entity: Option<EntityRecord>, uid: Option<&str>) {
+            if let Some(e) = &entity {
+                self.entities.insert(e.uid.clone(), e.clone());
+            } else if let Some(uid) = uid {
+                self.entities.remove(uid);
+            }
+            self.feed.push(Change {
+                seq: self.feed.len() as i64 + 1,
+                kind,
+                uid: uid.map(str::to_string),
+                entity,
+            });
+        }
+    }
+
+    struct FakeUpstream(Arc<Mutex<Fake>>);
+
+    #[async_trait]
+    impl Upstream for FakeUpstream {
+        async fn snapshot(&self, _tenant: Uuid) -> anyhow::Result<Option<MemoryTenant>> {
+            let mut fake = self.0.lock().unwrap();
+            if fake.down {
+                bail!("upstream down");
+            }
+            fake.snapshots += 1;
+            let pset = PolicySet::from_str("permit(principal, action, resource);").unwrap();
+            Ok(Some(MemoryTenant::new(
+                Some((fake.policy_version, pset)),
+                fake.entities.values().cloned(),
+            )))
+        }
+
+        async fn changes(&self, _tenant: Uuid, since: Option<i64>) -> anyhow::Result<ChangeFeed> {
+            let fake = self.0.lock().unwrap();
+            if fake.down {
+                bail!("upstream down");
+            }
+            Ok(ChangeFeed {
+                cursor: fake.feed.len() as i64,
+                changes: since.map_or_else(Vec::new, |since| fake.feed[since as usize..].to_vec()),
+            })
+        }
+    }
+
+    fn sidecar(fake: &Arc<Mutex<Fake>>, max_staleness: Duration, mode: StaleMode) -> SidecarStore {
+        SidecarStore::new(
+            vec![TENANT],
+            Box::new(FakeUpstream(fake.clone())),
+            Duration::from_secs(1),
+            Duration::from_secs(3600),
+            max_staleness,
+            mode,
+        )
+    }
+
+    fn user(dept: &str) -> EntityRecord {
+        EntityRecord {
+            attrs: json!({ "department": dept }),
+            ..EntityRecord::bare("User::\"1\"")
+        }
+    }
+
+    #[tokio::test]
+    async fn patches_entities_and_resnapshots_on_policy_changes() {
+        let fake = Arc::new(Mutex::new(Fake {
+            policy_version: 1,
+            ..Default::default()
+        }));
+        let store = sidecar(&fake, Duration::from_secs(30), StaleMode::FailClosed);
+        assert_eq!(store.check_fresh(TENANT), Err("snapshot not synced".into()));
+        assert_eq!(store.sync_all().await, vec![TENANT]);
+        assert!(store.check_fresh(TENANT).is_ok());
+
+        // Entity writes are applied from the feed, without a new snapshot.
+        fake.lock()
+            .unwrap()
+            .write(ChangeKind::Entity, Some(user("sales")), Some("User::\"1\""));
+        assert_eq!(store.sync_all().await, vec![TENANT]);
+        let found = store.entities(TENANT, &["User::\"1\""]).await.unwrap();
+        assert_eq!(found, vec![user("sales")]);
+        fake.lock()
+            .unwrap()
+            .write(ChangeKind::Entity, None, Some("User::\"1\""));
+        store.sync_all().await;
+        assert!(store
+            .entities(TENANT, &["User::\"1\""])
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(fake.lock().unwrap().snapshots, 1);
+        assert!(store.sync_all().await.is_empty());
+
+        // A policy set change takes a new snapshot.
+        {
+            let mut fake = fake.lock().unwrap();
+            fake.policy_version = 2;
+            fake.write(ChangeKind::PolicySet, None, None);
+        }
+        assert_eq!(store.sync_all().await, vec![TENANT]);
+        assert_eq!(store.active_policy_set(TENANT).await.unwrap().0, 2);
+        assert_eq!(fake.lock().unwrap().snapshots, 2);
+    }
+
+    #[tokio::test]
+    async fn stale_snapshot_fails_closed_or_serves() {
+        let fake = Arc::new(Mutex::new(Fake::default()));
+        let closed = sidecar(&fake, Duration::ZERO, StaleMode::FailClosed);
+        let serving = sidecar(&fake, Duration::ZERO, StaleMode::ServeStale);
+        closed.sync_all().await;
+        serving.sync_all().await;
+        fake.lock().unwrap().down = true;
+        assert!(closed.sync_all().await.is_empty());
+        serving.sync_all().await;
+        tokio::time::sleep(Duration::from_millis(5)).await;
+
+        assert!(closed
+            .check_fresh(TENANT)
+            .unwrap_err()
+            .starts_with("snapshot stale"));
+        assert!(!closed.readiness().0);
+        assert!(serving.check_fresh(TENANT).is_ok());
+        assert!(serving.readiness().0);
+        // The last snapshot is still there to serve.
+        assert!(serving.active_policy_set(TENANT).await.is_ok());
+        // Tenants the sidecar does not serve are left to the store ("tenant unknown").
+        assert!(closed.check_fresh(Uuid::from_u128(1)).is_ok());
+    }
+}
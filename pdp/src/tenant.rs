@@ -0,0 +1,144 @@
+//! Tenant lifecycle and per-tenant settings.
+//!
+//! Read from `tenants.status` / `tenants.settings` through the tenant store and
+//! cached in memory next to the policy sets; unknown tenant ids are remembered for
+//! a few seconds. Only `active` tenants get decisions; the invalidation channel can
+//! carry a new `status` so suspensions apply without waiting for a reload.
+
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TenantStatus {
+    Active,
+    Suspended,
+    Deleted,
+}
+
+impl TenantStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TenantStatus::Active => "active",
+            TenantStatus::Suspended => "suspended",
+            TenantStatus::Deleted => "deleted",
+        }
+    }
+}
+
+impl FromStr for TenantStatus {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "active" => Ok(TenantStatus::Active),
+            // admin-api calls it "disabled"
+            "suspended" | "disabled" => Ok(TenantStatus::Suspended),
+            "deleted" => Ok(TenantStatus::Deleted),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DefaultDecision {
+    Allow,
+    Deny,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum FailMode {
+    Open,
+    #[default]
+    Closed,
+}
+
+/// `tenants.settings` (JSONB). Unknown keys are ignored.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TenantSettings {
+    /// Decision when the tenant has no active policy set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default_decision: Option<DefaultDecision>,
+    /// What to answer when policies or attributes cannot be loaded.
+    #[serde(default)]
+    pub fail_mode: FailMode,
+}
+
+#[derive(Debug, Clone)]
+pub struct TenantMeta {
+    pub status: TenantStatus,
+    pub settings: TenantSettings,
+}
+
+/// Status and settings as written outside the database: `tenant.json` in file
+/// mode and the `tenant` section of a bundle. A missing status means active.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TenantDoc {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<String>,
+    #[serde(default)]
+    pub settings: TenantSettings,
+}
+
+impl TenantDoc {
+    pub fn into_meta(self) -> anyhow::Result<TenantMeta> {
+        let status = match self.status {
+            Some(s) => s
+                .parse()
+                .map_err(|_| anyhow::anyhow!("unknown status {s:?}"))?,
+            None => TenantStatus::Active,
+        };
+        Ok(TenantMeta {
+            status,
+            settings: self.settings,
+        })
+    }
+}
+
+impl From<&TenantMeta> for TenantDoc {
+    fn from(meta: &TenantMeta) -> Self {
+        Self {
+            status: Some(meta.status.as_str().to_string()),
+            settings: meta.settings.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_statuses() {
+        assert_eq!("active".parse(), Ok(TenantStatus::Active));
+        assert_eq!("suspended".parse(), Ok(TenantStatus::Suspended));
+        assert_eq!("disabled".parse(), Ok(TenantStatus::Suspended));
+        assert_eq!("deleted".parse(), Ok(TenantStatus::Deleted));
+        assert_eq!("Active".parse::<TenantStatus>(), Err(()));
+    }
+
+    #[test]
+    fn tenant_docs_default_to_active_and_fail_closed() {
+        let meta = TenantDoc::default().into_meta().unwrap();
+        assert_eq!(meta.status, TenantStatus::Active);
+        assert_eq!(meta.settings.fail_mode, FailMode::Closed);
+        assert_eq!(meta.settings.default_decision, None);
+
+        let doc: TenantDoc = serde_json::from_value(serde_json::json!({
+            "status": "disabled",
+            "settings": { "default_decision": "allow", "fail_mode": "open", "other": 1 },
+        }))
+        .unwrap();
+        let meta = doc.into_meta().unwrap();
+        assert_eq!(meta.status, TenantStatus::Suspended);
+        assert_eq!(meta.settings.default_decision, Some(DefaultDecision::Allow));
+        assert_eq!(meta.settings.fail_mode, FailMode::Open);
+
+        let unknown = TenantDoc {
+            status: Some("archived".into()),
+            ..Default::default()
+        };
+        assert!(unknown.into_meta().is_err());
+    }
+}
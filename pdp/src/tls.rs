@@ -0,0 +1,452 @@
+//! TLS / mutual TLS for the PDP listeners.
+//!
+//! Enabled with `TLS_CERT_PATH` + `TLS_KEY_PATH`. `TLS_CLIENT_CA_PATH` turns on
+//! client certificate verification (`TLS_CLIENT_AUTH=required|optional`). The
+//! files are polled every `TLS_RELOAD_SECS` and the server config is swapped in
+//! place when they change, so certificates rotate without a restart. The verified
+//! client certificate subject is attached to each request as a [`ClientCert`].
+//! Handshakes not finished within `TLS_HANDSHAKE_TIMEOUT_SECS` (default 10) are
+//! dropped, so idle connections cannot pile up until the PDP runs out of file
+//! descriptors.
+
+use axum::{extract::Request, Router};
+use hyper::body::Incoming;
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::{conn::auto, graceful::GracefulShutdown},
+};
+use rustls::{crypto::ring, server::WebPkiClientVerifier, RootCertStore, ServerConfig};
+use std::{
+    env, fs,
+    future::Future,
+    io::BufReader,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+use tower::ServiceExt;
+use tracing::{debug, info, warn};
+
+const DEFAULT_RELOAD_SECS: u64 = 30;
+const DEFAULT_HANDSHAKE_TIMEOUT_SECS: u64 = 10;
+// Pause after an accept error such as EMFILE, as `axum::serve` does
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Subject of the verified client certificate of the current connection.
+#[derive(Clone, Debug)]
+pub struct ClientCert {
+    pub subject: String,
+}
+
+#[derive(Clone, Debug)]
+struct TlsFiles {
+    cert: String,
+    key: String,
+    client_ca: Option<String>,
+    client_auth_required: bool,
+}
+
+impl TlsFiles {
+    fn paths(&self) -> impl Iterator<Item = &str> {
+        [Some(&self.cert), Some(&self.key), self.client_ca.as_ref()]
+            .into_iter()
+            .flatten()
+            .map(String::as_str)
+    }
+
+    fn mtimes(&self) -> Vec<Option<SystemTime>> {
+        self.paths()
+            .map(|p| fs::metadata(p).and_then(|m| m.modified()).ok())
+            .collect()
+    }
+
+    fn build(&self) -> anyhow::Result<ServerConfig> {
+        let certs = rustls_pemfile::certs(&mut BufReader::new(fs::File::open(&self.cert)?))
+            .collect::<Result<Vec<_>, _>>()?;
+        let key = rustls_pemfile::private_key(&mut BufReader::new(fs::File::open(&self.key)?))?
+            .ok_or_else(|| anyhow::anyhow!("no private key in {}", self.key))?;
+
+        let provider = Arc::new(ring::default_provider());
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?;
+        let builder = match &self.client_ca {
+            Some(ca) => {
+                let mut roots = RootCertStore::empty();
+                for cert in rustls_pemfile::certs(&mut BufReader::new(fs::File::open(ca)?)) {
+                    roots.add(cert?)?;
+                }
+                let verifier =
+                    WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+                let verifier = if self.client_auth_required {
+                    verifier.build()?
+                } else {
+                    verifier.allow_unauthenticated().build()?
+                };
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+
+        let mut config = builder.with_single_cert(certs, key)?;
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        Ok(config)
+    }
+}
+
+/// A `TlsAcceptor` rebuilt whenever the certificate files change on disk.
+pub struct ReloadingTls {
+    files: TlsFiles,
+    acceptor: RwLock<TlsAcceptor>,
+    handshake_timeout: Duration,
+}
+
+impl ReloadingTls {
+    /// Returns `None` unless `TLS_CERT_PATH` and `TLS_KEY_PATH` are set.
+    pub fn from_env() -> anyhow::Result<Option<Arc<Self>>> {
+        let (cert, key) = match (env::var("TLS_CERT_PATH"), env::var("TLS_KEY_PATH")) {
+            (Ok(c), Ok(k)) => (c, k),
+            (Err(_), Err(_)) => return Ok(None),
+            _ => anyhow::bail!("TLS_CERT_PATH and TLS_KEY_PATH must be set together"),
+        };
+        let client_ca = env::var("TLS_CLIENT_CA_PATH").ok();
+        let client_auth_required = env::var("TLS_CLIENT_AUTH")
+            .map(|v| v != "optional")
+            .unwrap_or(true);
+        let files = TlsFiles {
+            cert,
+            key,
+            client_ca,
+            client_auth_required,
+        };
+
+        let mut tls = Self::new(files)?;
+        tls.handshake_timeout = Duration::from_secs(secs(
+            "TLS_HANDSHAKE_TIMEOUT_SECS",
+            DEFAULT_HANDSHAKE_TIMEOUT_SECS,
+        ));
+        let tls = Arc::new(tls);
+        info!(
+            "TLS enabled (client certs: {})",
+            match (&tls.files.client_ca, tls.files.client_auth_required) {
+                (None, _) => "off",
+                (Some(_), true) => "required",
+                (Some(_), false) => "optional",
+            }
+        );
+
+        let reload = secs("TLS_RELOAD_SECS", DEFAULT_RELOAD_SECS);
+        spawn_reload(tls.clone(), Duration::from_secs(reload));
+        Ok(Some(tls))
+    }
+
+    fn new(files: TlsFiles) -> anyhow::Result<Self> {
+        let acceptor = TlsAcceptor::from(Arc::new(files.build()?));
+        Ok(Self {
+            files,
+            acceptor: RwLock::new(acceptor),
+            handshake_timeout: Duration::from_secs(DEFAULT_HANDSHAKE_TIMEOUT_SECS),
+        })
+    }
+
+    /// Rebuilds the acceptor from the files; the previous one stays on error.
+    fn reload(&self) -> anyhow::Result<()> {
+        let config = self.files.build()?;
+        *self.acceptor.write().unwrap_or_else(|e| e.into_inner()) =
+            TlsAcceptor::from(Arc::new(config));
+        Ok(())
+    }
+
+    fn acceptor(&self) -> TlsAcceptor {
+        self.acceptor
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+}
+
+fn secs(name: &str, default: u64) -> u64 {
+    env::var(name)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(default)
+}
+
+fn spawn_reload(tls: Arc<ReloadingTls>, every: Duration) {
+    tokio::spawn(async move {
+        let mut last = tls.files.mtimes();
+        let mut tick = tokio::time::interval(every);
+        tick.tick().await;
+        loop {
+            tick.tick().await;
+            let now = tls.files.mtimes();
+            if now == last {
+                continue;
+            }
+            match tls.reload() {
+                Ok(()) => {
+                    metrics::counter!("pdp_tls_reloads_total", "result" => "ok").increment(1);
+                    info!("TLS certificates reloaded");
+                    last = now;
+                }
+                // Files may be mid-rotation; keep the old config and retry next tick.
+                Err(e) => {
+                    metrics::counter!("pdp_tls_reloads_total", "result" => "error").increment(1);
+                    warn!("TLS reload failed, keeping previous certificates: {e}");
+                }
+            }
+        }
+    });
+}
+
+/// Serves `app` on `listener`, over TLS when `tls` is set. Once `shutdown`
+/// completes, stops accepting and returns when open connections have finished
+/// their requests.
+pub async fn serve(
+    listener: TcpListener,
+    app: Router,
+    tls: Option<Arc<ReloadingTls>>,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> anyhow::Result<()> {
+    let Some(tls) = tls else {
+        axum::serve(listener, app)
+            .with_graceful_shutdown(shutdown)
+            .await?;
+        return Ok(());
+    };
+
+    let graceful = GracefulShutdown::new();
+    tokio::pin!(shutdown);
+    loop {
+        let (tcp, peer) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(v) => v,
+                Err(e) => {
+                    warn!("accept error: {e}");
+                    tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+                    continue;
+                }
+            },
+            _ = &mut shutdown => break,
+        };
+        let acceptor = tls.acceptor();
+        let handshake_timeout = tls.handshake_timeout;
+        let app = app.clone();
+        let watcher = graceful.watcher();
+        tokio::spawn(async move {
+            let stream = match tokio::time::timeout(handshake_timeout, acceptor.accept(tcp)).await {
+                Ok(Ok(s)) => s,
+                Ok(Err(e)) => {
+                    metrics::counter!("pdp_tls_handshake_failures_total", "reason" => "error")
+                        .increment(1);
+                    debug!("TLS handshake with {peer} failed: {e}");
+                    return;
+                }
+                Err(_) => {
+                    metrics::counter!("pdp_tls_handshake_failures_total", "reason" => "timeout")
+                        .increment(1);
+                    debug!("TLS handshake with {peer} timed out");
+                    return;
+                }
+            };
+            let client_cert = stream
+                .get_ref()
+                .1
+                .peer_certificates()
+                .and_then(|certs| certs.first())
+                .and_then(|der| {
+                    x509_parser::parse_x509_certificate(der)
+                        .ok()
+                        .map(|(_, cert)| ClientCert {
+                            subject: cert.subject().to_string(),
+                        })
+                });
+
+            let svc = hyper::service::service_fn(move |mut req: Request<Incoming>| {
+                if let Some(cert) = &client_cert {
+                    req.extensions_mut().insert(cert.clone());
+                }
+                app.clone().oneshot(req)
+            });
+            let builder = auto::Builder::new(TokioExecutor::new());
+            let conn = builder.serve_connection_with_upgrades(TokioIo::new(stream), svc);
+            if let Err(e) = watcher.watch(conn).await {
+                debug!("connection from {peer} closed: {e}");
+            }
+        });
+    }
+    drop(listener);
+    graceful.shutdown().await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{routing::get, Extension};
+    use rcgen::{BasicConstraints, CertificateParams, DnType, IsCa, KeyPair};
+    use rustls::{
+        pki_types::{PrivatePkcs8KeyDer, ServerName},
+        ClientConfig,
+    };
+    use std::{net::SocketAddr, path::PathBuf};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::TlsConnector;
+
+    struct Pki {
+        dir: PathBuf,
+        ca: rcgen::Certificate,
+        ca_key: KeyPair,
+    }
+
+    impl Pki {
+        /// A CA and a `localhost` server certificate, written as PEM files.
+        fn new() -> Self {
+            let dir = env::temp_dir().join(format!("pdp-tls-{}", uuid::Uuid::new_v4()));
+            fs::create_dir_all(&dir).unwrap();
+            let ca_key = KeyPair::generate().unwrap();
+            let mut params = CertificateParams::new(vec![]).unwrap();
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            params
+                .distinguished_name
+                .push(DnType::CommonName, "test ca");
+            let ca = params.self_signed(&ca_key).unwrap();
+            fs::write(dir.join("ca.pem"), ca.pem()).unwrap();
+
+            let pki = Self { dir, ca, ca_key };
+            let (cert, key) = pki.issue("localhost");
+            fs::write(pki.dir.join("cert.pem"), cert.pem()).unwrap();
+            fs::write(pki.dir.join("key.pem"), key.serialize_pem()).unwrap();
+            pki
+        }
+
+        fn issue(&self, name: &str) -> (rcgen::Certificate, KeyPair) {
+            let key = KeyPair::generate().unwrap();
+            let mut params = CertificateParams::new(vec![name.to_string()]).unwrap();
+            params.distinguished_name.push(DnType::CommonName, name);
+            let cert = params.signed_by(&key, &self.ca, &self.ca_key).unwrap();
+            (cert, key)
+        }
+
+        fn files(&self) -> TlsFiles {
+            let path = |f: &str| self.dir.join(f).to_string_lossy().into_owned();
+            TlsFiles {
+                cert: path("cert.pem"),
+                key: path("key.pem"),
+                client_ca: Some(path("ca.pem")),
+                client_auth_required: true,
+            }
+        }
+
+        /// `GET /` over TLS, presenting a client certificate for `client` if set.
+        async fn get(&self, addr: SocketAddr, client: Option<&str>) -> std::io::Result<String> {
+            let mut roots = RootCertStore::empty();
+            roots.add(self.ca.der().clone()).unwrap();
+            let builder = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_root_certificates(roots);
+            let config = match client {
+                Some(name) => {
+                    let (cert, key) = self.issue(name);
+                    let key = PrivatePkcs8KeyDer::from(key.serialize_der()).into();
+                    builder
+                        .with_client_auth_cert(vec![cert.der().clone()], key)
+                        .unwrap()
+                }
+                None => builder.with_no_client_auth(),
+            };
+            let tcp = tokio::net::TcpStream::connect(addr).await?;
+            let mut stream = TlsConnector::from(Arc::new(config))
+                .connect(ServerName::try_from("localhost").unwrap(), tcp)
+                .await?;
+            stream
+                .write_all(b"GET / HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n\r\n")
+                .await?;
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await?;
+            Ok(response)
+        }
+    }
+
+    impl Drop for Pki {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    /// Serves a route echoing the client certificate subject.
+    async fn start(tls: Arc<ReloadingTls>) -> SocketAddr {
+        let app = Router::new().route(
+            "/",
+            get(|cert: Option<Extension<ClientCert>>| async move {
+                cert.map(|Extension(c)| c.subject).unwrap_or_default()
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, app, Some(tls), std::future::pending()));
+        addr
+    }
+
+    #[tokio::test]
+    async fn attaches_the_client_certificate_subject() {
+        let pki = Pki::new();
+        let addr = start(Arc::new(ReloadingTls::new(pki.files()).unwrap())).await;
+
+        let response = pki.get(addr, Some("billing-svc")).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+        assert!(response.ends_with("CN=billing-svc"), "{response}");
+    }
+
+    #[tokio::test]
+    async fn required_client_auth_rejects_anonymous_clients() {
+        let pki = Pki::new();
+        let addr = start(Arc::new(ReloadingTls::new(pki.files()).unwrap())).await;
+        assert!(pki.get(addr, None).await.is_err());
+
+        // Optional client auth lets them in, without a subject
+        let optional = TlsFiles {
+            client_auth_required: false,
+            ..pki.files()
+        };
+        let addr = start(Arc::new(ReloadingTls::new(optional).unwrap())).await;
+        let response = pki.get(addr, None).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+        assert!(response.ends_with("\r\n\r\n"), "{response}");
+    }
+
+    #[tokio::test]
+    async fn stalled_handshakes_are_dropped() {
+        let pki = Pki::new();
+        let mut tls = ReloadingTls::new(pki.files()).unwrap();
+        tls.handshake_timeout = Duration::from_millis(100);
+        let addr = start(Arc::new(tls)).await;
+
+        // Connects and never sends a ClientHello: the server closes the socket
+        let mut tcp = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let mut buf = [0; 1];
+        let read = tokio::time::timeout(Duration::from_secs(5), tcp.read(&mut buf)).await;
+        assert_eq!(read.expect("connection still open").unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn broken_reload_keeps_the_previous_certificates() {
+        let pki = Pki::new();
+        let tls = Arc::new(ReloadingTls::new(pki.files()).unwrap());
+        let addr = start(tls.clone()).await;
+
+        // Mid-rotation: the new certificate is written before its key
+        fs::write(pki.dir.join("cert.pem"), "not a certificate").unwrap();
+        assert!(tls.reload().is_err());
+        let response = pki.get(addr, Some("billing-svc")).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+
+        let (cert, key) = pki.issue("localhost");
+        fs::write(pki.dir.join("cert.pem"), cert.pem()).unwrap();
+        fs::write(pki.dir.join("key.pem"), key.serialize_pem()).unwrap();
+        tls.reload().unwrap();
+        let response = pki.get(addr, Some("billing-svc")).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+    }
+}